@@ -0,0 +1,236 @@
+//! Instructions per second of the interpreter on synthetic loops and, if
+//! `RUM_SANDMARK` names a copy of it, on the sandmark.
+//!
+//! `cargo bench --bench interpreter -- [--optimize] [--save FILE] [--baseline FILE] [FILTER]`
+//! runs the benchmarks whose names contain FILTER. `--optimize` enables
+//! superinstructions, `--save` writes the results to FILE and `--baseline`
+//! compares them with results saved before.
+
+use rum::asm::Assembler;
+use rum::io::IoDevice;
+use rum::machine::VirtualMachine;
+use rum::rumload;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::process;
+use std::time::{Duration, Instant};
+
+/// Times each loop body is repeated per iteration, so the loop itself costs little.
+const UNROLL: usize = 16;
+/// Iterations of each synthetic loop.
+const ITERATIONS: u32 = 250_000;
+/// Each benchmark is run at least this many times, and until this much time has passed.
+const MIN_RUNS: usize = 3;
+const MIN_TIME: Duration = Duration::from_secs(1);
+
+/// Registers the loops keep for themselves: zero, the counter, minus one and two scratch.
+const ZERO: usize = 0;
+const COUNTER: usize = 1;
+const MINUS_ONE: usize = 2;
+const SCRATCH: [usize; 2] = [3, 4];
+
+///Input of zeros that never ends, and output that goes nowhere
+struct Sink;
+impl IoDevice for Sink {
+    fn read_byte(&mut self) -> Option<u8> {
+        Some(0)
+    }
+    fn write_byte(&mut self, _byte: u8) {}
+}
+
+///A loop running `body` ITERATIONS * UNROLL times after `setup`
+/// Both may use r5 to r7.
+fn looped(setup: impl Fn(&mut Assembler), body: impl Fn(&mut Assembler)) -> Vec<u32> {
+    let mut asm = Assembler::new();
+    asm.constant(COUNTER, ITERATIONS, SCRATCH[0]);
+    asm.nand(MINUS_ONE, ZERO, ZERO);
+    setup(&mut asm);
+    let top = asm.label();
+    let done = asm.label();
+    asm.bind(top);
+    for _ in 0..UNROLL {
+        body(&mut asm);
+    }
+    asm.add(COUNTER, COUNTER, MINUS_ONE);
+    asm.branch(COUNTER, top, done, ZERO, SCRATCH);
+    asm.bind(done);
+    asm.halt();
+    asm.finish()
+}
+
+///A loop that reloads $m[0] from a copy of itself, so each Load Program replaces the program
+fn reload() -> Vec<u32> {
+    // The copy needs the program's length, which is known once it is assembled
+    let program = |len: u32| {
+        let setup = |asm: &mut Assembler| {
+            let top = asm.label();
+            let done = asm.label();
+            asm.loadv(5, len);
+            asm.map(6, 5);
+            asm.bind(top);
+            asm.add(5, 5, MINUS_ONE);
+            asm.load(7, ZERO, 5);
+            asm.store(6, 5, 7);
+            asm.branch(5, top, done, ZERO, SCRATCH);
+            asm.bind(done);
+        };
+        looped(setup, |asm| {
+            let next = asm.label();
+            asm.loadv_label(7, next);
+            asm.loadp(6, 7);
+            asm.bind(next);
+        })
+    };
+    program(program(0).len() as u32)
+}
+
+fn synthetic() -> Vec<(&'static str, Vec<u32>)> {
+    let none = |_: &mut Assembler| {};
+    let operands = |asm: &mut Assembler| {
+        asm.loadv(5, 3);
+        asm.loadv(6, 1_000_003);
+    };
+    let segment = |asm: &mut Assembler| {
+        asm.loadv(5, 16);
+        asm.map(6, 5);
+    };
+    vec![
+        ("cmov", looped(operands, |asm| asm.cmov(7, 6, 5))),
+        ("load", looped(none, |asm| asm.load(7, ZERO, ZERO))),
+        ("store", looped(segment, |asm| asm.store(6, ZERO, 5))),
+        ("add", looped(operands, |asm| asm.add(7, 6, 5))),
+        ("mul", looped(operands, |asm| asm.mul(7, 6, 5))),
+        ("div", looped(operands, |asm| asm.div(7, 6, 5))),
+        ("nand", looped(operands, |asm| asm.nand(7, 6, 5))),
+        ("output", looped(operands, |asm| asm.output(5))),
+        ("input", looped(none, |asm| asm.input(7))),
+        ("loadv", looped(none, |asm| asm.loadv(7, 42))),
+        (
+            "map-unmap",
+            looped(operands, |asm| {
+                asm.map(7, 5);
+                asm.unmap(7);
+            }),
+        ),
+        (
+            "loadp-jump",
+            looped(none, |asm| {
+                let next = asm.label();
+                asm.jump(next, ZERO, 7);
+                asm.bind(next);
+            }),
+        ),
+        ("loadp-reload", reload()),
+    ]
+}
+
+fn machine(program: &[u32], optimize: bool) -> VirtualMachine {
+    let mut vm = VirtualMachine::new();
+    vm.set_io(Box::new(Sink));
+    vm.initialize_machine(program.to_vec());
+    if optimize {
+        vm.enable_superinstructions();
+    }
+    vm
+}
+
+/// Instructions the program executes, halt included.
+fn count_steps(program: &[u32]) -> u64 {
+    let mut vm = machine(program, false);
+    let mut steps = 1;
+    while vm.step().expect("benchmark faulted") {
+        steps += 1;
+    }
+    steps
+}
+
+/// Best time of several runs of the program.
+fn time(program: &[u32], optimize: bool) -> Duration {
+    let started = Instant::now();
+    let mut best = Duration::MAX;
+    let mut runs = 0;
+    while runs < MIN_RUNS || started.elapsed() < MIN_TIME {
+        let mut vm = machine(program, optimize);
+        let start = Instant::now();
+        vm.run_program().expect("benchmark faulted");
+        best = best.min(start.elapsed());
+        runs += 1;
+    }
+    best
+}
+
+/// Results saved by `--save`, as millions of instructions per second by name.
+fn read_report(path: &str) -> HashMap<String, f64> {
+    let text = fs::read_to_string(path).unwrap_or_else(|error| {
+        eprintln!("bench: {}: {}", path, error);
+        process::exit(1);
+    });
+    text.lines()
+        .filter_map(|line| {
+            let (name, mips) = line.split_once(' ')?;
+            Some((name.to_string(), mips.parse().ok()?))
+        })
+        .collect()
+}
+
+fn main() {
+    let mut optimize = false;
+    let mut save = None;
+    let mut baseline = None;
+    let mut filter = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--optimize" => optimize = true,
+            "--save" => save = args.next(),
+            "--baseline" => baseline = args.next().map(|path| read_report(&path)),
+            // Passed by `cargo bench`
+            "--bench" => {}
+            _ => filter = Some(arg),
+        }
+    }
+    let mut benchmarks = synthetic();
+    match env::var("RUM_SANDMARK") {
+        Ok(path) => match fs::read(&path) {
+            Ok(bytes) => benchmarks.push(("sandmark", rumload::from_bytes(&bytes))),
+            Err(error) => eprintln!("bench: {}: {}", path, error),
+        },
+        Err(_) => eprintln!("bench: set RUM_SANDMARK to a sandmark.umz to include it"),
+    }
+    println!(
+        "{:<12} {:>12} {:>12} {:>10} {:>8}",
+        "benchmark", "instructions", "best", "MIPS", "change"
+    );
+    let mut report = String::new();
+    for (name, program) in benchmarks {
+        if filter
+            .as_ref()
+            .is_some_and(|filter| !name.contains(filter.as_str()))
+        {
+            continue;
+        }
+        let steps = count_steps(&program);
+        let best = time(&program, optimize);
+        let mips = steps as f64 / best.as_secs_f64() / 1e6;
+        let change = match baseline.as_ref().and_then(|b| b.get(name)) {
+            Some(before) => format!("{:+.1}%", (mips / before - 1.0) * 100.0),
+            None => String::new(),
+        };
+        println!(
+            "{:<12} {:>12} {:>10.3}ms {:>10.1} {:>8}",
+            name,
+            steps,
+            best.as_secs_f64() * 1e3,
+            mips,
+            change
+        );
+        report += &format!("{} {:.3}\n", name, mips);
+    }
+    if let Some(path) = save {
+        if let Err(error) = fs::write(&path, report) {
+            eprintln!("bench: {}: {}", path, error);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use crate::disasm::{decode, Instruction};
+
+///Abstract register value
+/// # Variants:
+/// * `Const`: the register always holds this value.
+/// * `Choice`: the register holds one of two values, as left by a
+///   Conditional Move between two constants.
+/// * `Unknown`: the value depends on memory, input or control flow.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Value {
+    Const(u32),
+    Choice(u32, u32),
+    Unknown,
+}
+impl Value {
+    ///Values this register may hold, if there are few enough to list
+    pub fn values(&self) -> Option<Vec<u32>> {
+        match *self {
+            Value::Const(v) => Some(vec![v]),
+            Value::Choice(t, f) => Some(vec![t, f]),
+            Value::Unknown => None,
+        }
+    }
+    /// Combines the values reaching an address along two paths.
+    fn join(self, other: Value) -> Value {
+        match (self, other) {
+            (x, y) if x == y => x,
+            (Value::Const(x), Value::Const(y)) => Value::Choice(x, y),
+            (Value::Choice(x, y), Value::Const(z)) | (Value::Const(z), Value::Choice(x, y))
+                if z == x || z == y =>
+            {
+                Value::Choice(x, y)
+            }
+            (Value::Choice(x, y), Value::Choice(z, w)) if x == w && y == z => self,
+            _ => Value::Unknown,
+        }
+    }
+    /// Folds a binary operation over constant operands.
+    fn fold(self, other: Value, op: impl Fn(u32, u32) -> Option<u32>) -> Value {
+        match (self, other) {
+            (Value::Const(x), Value::Const(y)) => op(x, y).map_or(Value::Unknown, Value::Const),
+            _ => Value::Unknown,
+        }
+    }
+}
+
+/// Register values known before an instruction runs.
+pub type Registers = [Value; 8];
+
+///Where control goes after an instruction
+/// # Variants:
+/// * `Next`: the following address.
+/// * `Halt`: nowhere, the machine stops.
+/// * `Jump`: Load Program from $m[0] to one of the listed addresses.
+/// * `Indirect`: Load Program to a target that is not known.
+/// * `Dynamic`: Load Program from another segment, leaving this code.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Exit {
+    Next,
+    Halt,
+    Jump(Vec<u32>),
+    Indirect,
+    Dynamic,
+}
+
+///Runs one instruction on abstract registers
+/// Returns where control goes next.
+pub fn transfer(instruction: Instruction, registers: &mut Registers) -> Exit {
+    match instruction {
+        Instruction::CMov { a, b, c } => match registers[c] {
+            Value::Const(0) => {}
+            Value::Const(_) => registers[a] = registers[b],
+            _ => registers[a] = registers[a].join(registers[b]),
+        },
+        Instruction::Load { a, .. } => registers[a] = Value::Unknown,
+        Instruction::Add { a, b, c } => {
+            registers[a] = registers[b].fold(registers[c], |x, y| Some(x.wrapping_add(y)))
+        }
+        Instruction::Mul { a, b, c } => {
+            registers[a] = registers[b].fold(registers[c], |x, y| Some(x.wrapping_mul(y)))
+        }
+        Instruction::Div { a, b, c } => {
+            registers[a] = registers[b].fold(registers[c], |x, y| x.checked_div(y))
+        }
+        Instruction::Nand { a, b, c } => {
+            registers[a] = registers[b].fold(registers[c], |x, y| Some(!(x & y)))
+        }
+        Instruction::Halt => return Exit::Halt,
+        Instruction::MapSegment { b, .. } => registers[b] = Value::Unknown,
+        Instruction::Input { c } => registers[c] = Value::Unknown,
+        Instruction::LoadProgram { b, c } => {
+            return match (registers[b], registers[c].values()) {
+                (Value::Const(0), Some(targets)) => Exit::Jump(targets),
+                (Value::Const(0), None) => Exit::Indirect,
+                (Value::Const(_), _) => Exit::Dynamic,
+                _ => Exit::Indirect,
+            }
+        }
+        Instruction::LoadValue { a, value } => registers[a] = Value::Const(value),
+        Instruction::Store { .. }
+        | Instruction::UnmapSegment { .. }
+        | Instruction::Output { .. }
+        | Instruction::Invalid { .. } => {}
+    }
+    Exit::Next
+}
+
+///Constant propagation over $m[0]
+/// # Parameters:
+/// * `registers`: Abstract registers before each address, `None` if no
+///   path from address 0 reaches it through known jumps.
+/// * `exits`: Where control goes after each reached address.
+/// * `indirect`: Whether some reached jump has an unknown target, in which
+///   case any address may run.
+pub struct Analysis {
+    pub registers: Vec<Option<Registers>>,
+    pub exits: Vec<Option<Exit>>,
+    pub indirect: bool,
+}
+impl Analysis {
+    ///Analyzes a program, starting from address 0 with every register zero
+    pub fn new(program: &[u32]) -> Self {
+        let mut analysis = Analysis {
+            registers: vec![None; program.len()],
+            exits: vec![None; program.len()],
+            indirect: false,
+        };
+        let mut work = vec![];
+        analysis.reach(0, [Value::Const(0); 8], &mut work);
+        while let Some(address) = work.pop() {
+            let mut registers = analysis.registers[address].unwrap();
+            let exit = transfer(decode(program[address]), &mut registers);
+            match &exit {
+                Exit::Next => analysis.reach(address + 1, registers, &mut work),
+                Exit::Jump(targets) => {
+                    for &target in targets {
+                        analysis.reach(target as usize, registers, &mut work);
+                    }
+                }
+                Exit::Indirect => analysis.indirect = true,
+                Exit::Halt | Exit::Dynamic => {}
+            }
+            analysis.exits[address] = Some(exit);
+        }
+        analysis
+    }
+    /// Merges `registers` into the state of `address`, queueing it if it changed.
+    fn reach(&mut self, address: usize, registers: Registers, work: &mut Vec<usize>) {
+        let slot = match self.registers.get_mut(address) {
+            Some(slot) => slot,
+            None => return,
+        };
+        let merged = match slot {
+            Some(old) => {
+                let mut merged = *old;
+                for (m, r) in merged.iter_mut().zip(registers) {
+                    *m = m.join(r);
+                }
+                merged
+            }
+            None => registers,
+        };
+        if *slot != Some(merged) {
+            *slot = Some(merged);
+            work.push(address);
+        }
+    }
+    ///Whether the instruction at `address` may ever run
+    pub fn is_reachable(&self, address: usize) -> bool {
+        self.indirect || self.registers.get(address).is_some_and(Option::is_some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+
+    #[test]
+    fn follows_constant_jumps() {
+        let mut asm = Assembler::new();
+        asm.loadv(1, 3);
+        asm.loadp(0, 1);
+        asm.halt(); // skipped
+        asm.add(2, 1, 1);
+        asm.halt();
+        let program = asm.finish();
+        let analysis = Analysis::new(&program);
+        assert!(!analysis.indirect);
+        assert!(analysis.is_reachable(3));
+        assert!(!analysis.is_reachable(2));
+        assert_eq!(analysis.registers[4].unwrap()[2], Value::Const(6));
+        assert_eq!(analysis.exits[4], Some(Exit::Halt));
+    }
+
+    #[test]
+    fn conditional_moves_branch_both_ways() {
+        let mut asm = Assembler::new();
+        asm.input(3);
+        asm.loadv(1, 5);
+        asm.loadv(2, 6);
+        asm.cmov(2, 1, 3);
+        asm.loadp(0, 2);
+        asm.halt();
+        asm.halt();
+        let program = asm.finish();
+        let analysis = Analysis::new(&program);
+        assert_eq!(analysis.exits[4], Some(Exit::Jump(vec![6, 5])));
+        assert!(analysis.is_reachable(5) && analysis.is_reachable(6));
+    }
+
+    #[test]
+    fn unknown_targets_are_indirect() {
+        let mut asm = Assembler::new();
+        asm.input(1);
+        asm.loadp(0, 1);
+        asm.halt();
+        let program = asm.finish();
+        let analysis = Analysis::new(&program);
+        assert!(analysis.indirect);
+        assert!(analysis.is_reachable(2));
+    }
+}
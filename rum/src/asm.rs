@@ -0,0 +1,268 @@
+use crate::disasm::{encode, Instruction};
+use crate::object::{Object, Relocation, Symbol};
+
+/// Largest value Load Value can hold.
+pub const MAX_LOAD_VALUE: u32 = (1 << 25) - 1;
+
+///An address in the program being assembled, possibly not yet known
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Label(usize);
+
+///Builds a program for $m[0] one instruction at a time
+/// Labels may be used before they are bound. Their uses are patched by
+/// `finish`, which needs every used label to be bound by then.
+/// # Parameters:
+/// * `words`: The program so far.
+/// * `labels`: Address each label is bound to, if it is yet.
+/// * `fixups`: Load Value words whose value is the address of a label.
+/// * `symbols`: Global symbols defined so far, for objects.
+/// * `externs`: Load Value words whose value is the address of a symbol.
+#[derive(Debug, Default)]
+pub struct Assembler {
+    words: Vec<u32>,
+    labels: Vec<Option<u32>>,
+    fixups: Vec<(usize, Label)>,
+    symbols: Vec<Symbol>,
+    externs: Vec<(usize, String)>,
+}
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    ///Address of the next word
+    pub fn here(&self) -> u32 {
+        self.words.len() as u32
+    }
+    ///Creates a label that is not bound yet
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+    ///Binds `label` to the next word
+    pub fn bind(&mut self, label: Label) {
+        assert!(self.labels[label.0].is_none(), "label bound twice");
+        self.labels[label.0] = Some(self.here());
+    }
+    ///Address `label` is bound to, if it is yet
+    pub fn address(&self, label: Label) -> Option<u32> {
+        self.labels[label.0]
+    }
+    ///Defines a global symbol at the next word, for other objects to use
+    pub fn define(&mut self, name: &str) {
+        self.symbols.push(Symbol {
+            name: name.to_string(),
+            offset: self.here(),
+            global: true,
+        });
+    }
+    ///Loads the address of symbol `name` into `a`, once linked
+    pub fn loadv_symbol(&mut self, a: usize, name: &str) {
+        self.externs.push((self.words.len(), name.to_string()));
+        self.loadv(a, 0);
+    }
+    ///Appends a raw word, such as data
+    pub fn word(&mut self, word: u32) {
+        self.words.push(word);
+    }
+    ///Appends an instruction
+    pub fn emit(&mut self, instruction: Instruction) {
+        self.word(encode(instruction));
+    }
+
+    pub fn cmov(&mut self, a: usize, b: usize, c: usize) {
+        self.emit(Instruction::CMov { a, b, c });
+    }
+    pub fn load(&mut self, a: usize, b: usize, c: usize) {
+        self.emit(Instruction::Load { a, b, c });
+    }
+    pub fn store(&mut self, a: usize, b: usize, c: usize) {
+        self.emit(Instruction::Store { a, b, c });
+    }
+    pub fn add(&mut self, a: usize, b: usize, c: usize) {
+        self.emit(Instruction::Add { a, b, c });
+    }
+    pub fn mul(&mut self, a: usize, b: usize, c: usize) {
+        self.emit(Instruction::Mul { a, b, c });
+    }
+    pub fn div(&mut self, a: usize, b: usize, c: usize) {
+        self.emit(Instruction::Div { a, b, c });
+    }
+    pub fn nand(&mut self, a: usize, b: usize, c: usize) {
+        self.emit(Instruction::Nand { a, b, c });
+    }
+    pub fn halt(&mut self) {
+        self.emit(Instruction::Halt);
+    }
+    pub fn map(&mut self, b: usize, c: usize) {
+        self.emit(Instruction::MapSegment { b, c });
+    }
+    pub fn unmap(&mut self, c: usize) {
+        self.emit(Instruction::UnmapSegment { c });
+    }
+    pub fn output(&mut self, c: usize) {
+        self.emit(Instruction::Output { c });
+    }
+    pub fn input(&mut self, c: usize) {
+        self.emit(Instruction::Input { c });
+    }
+    pub fn loadp(&mut self, b: usize, c: usize) {
+        self.emit(Instruction::LoadProgram { b, c });
+    }
+    pub fn loadv(&mut self, a: usize, value: u32) {
+        assert!(value <= MAX_LOAD_VALUE, "{} does not fit Load Value", value);
+        self.emit(Instruction::LoadValue { a, value });
+    }
+    ///Loads the address of `label` into `a`
+    pub fn loadv_label(&mut self, a: usize, label: Label) {
+        self.fixups.push((self.words.len(), label));
+        self.loadv(a, 0);
+    }
+
+    ///Loads any 32 bit value into `a`
+    /// Takes one to five instructions. Values too wide for Load Value go
+    /// through `scratch`, which must differ from `a`.
+    pub fn constant(&mut self, a: usize, value: u32, scratch: usize) {
+        if value <= MAX_LOAD_VALUE {
+            self.loadv(a, value);
+        } else if !value <= MAX_LOAD_VALUE {
+            self.loadv(a, !value);
+            self.nand(a, a, a);
+        } else {
+            assert_ne!(a, scratch);
+            self.loadv(a, value >> 16);
+            self.loadv(scratch, 1 << 16);
+            self.mul(a, a, scratch);
+            self.loadv(scratch, value & 0xffff);
+            self.add(a, a, scratch);
+        }
+    }
+    ///Jumps to `label` within $m[0]
+    /// `zero` must hold 0, and `scratch` is overwritten.
+    pub fn jump(&mut self, label: Label, zero: usize, scratch: usize) {
+        self.loadv_label(scratch, label);
+        self.loadp(zero, scratch);
+    }
+    ///Jumps to `taken` if `condition` is not zero, else to `not_taken`
+    /// `zero` must hold 0. Both scratch registers are overwritten, and must
+    /// differ from `condition`.
+    pub fn branch(
+        &mut self,
+        condition: usize,
+        taken: Label,
+        not_taken: Label,
+        zero: usize,
+        scratch: [usize; 2],
+    ) {
+        self.loadv_label(scratch[0], not_taken);
+        self.loadv_label(scratch[1], taken);
+        self.cmov(scratch[0], scratch[1], condition);
+        self.loadp(zero, scratch[0]);
+    }
+
+    ///Returns the program with every label use patched
+    /// Panics if a used label was never bound, or a symbol was used.
+    pub fn finish(mut self) -> Vec<u32> {
+        assert!(self.externs.is_empty(), "symbols need linking");
+        for &(at, label) in &self.fixups {
+            let address = self.labels[label.0].expect("label used but never bound");
+            assert!(
+                address <= MAX_LOAD_VALUE,
+                "program too large for Load Value"
+            );
+            self.words[at] |= address;
+        }
+        self.words
+    }
+
+    ///Returns the program as a relocatable object
+    /// Labels become local symbols named `.L<n>`.
+    /// Panics if a used label was never bound.
+    pub fn object(self) -> Object {
+        let name = |label: Label| format!(".L{}", label.0);
+        let mut symbols = self.symbols;
+        let mut relocations = vec![];
+        for &(at, label) in &self.fixups {
+            let offset = self.labels[label.0].expect("label used but never bound");
+            if !symbols.iter().any(|s| s.name == name(label)) {
+                symbols.push(Symbol {
+                    name: name(label),
+                    offset,
+                    global: false,
+                });
+            }
+            relocations.push(Relocation {
+                offset: at as u32,
+                symbol: name(label),
+                addend: 0,
+            });
+        }
+        for (at, symbol) in self.externs {
+            relocations.push(Relocation {
+                offset: at as u32,
+                symbol,
+                addend: 0,
+            });
+        }
+        relocations.sort_by_key(|r| r.offset);
+        Object {
+            code: self.words,
+            symbols,
+            relocations,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::Captured;
+    use crate::machine::VirtualMachine;
+
+    fn run(program: Vec<u32>) -> Vec<u8> {
+        let io = Captured::new(&[]);
+        let output = io.output();
+        let mut vm = VirtualMachine::new();
+        vm.set_io(Box::new(io));
+        vm.initialize_machine(program);
+        vm.run_program().unwrap();
+        let output = output.lock().unwrap().clone();
+        output
+    }
+
+    #[test]
+    fn patches_forward_labels() {
+        let mut asm = Assembler::new();
+        let skip = asm.label();
+        asm.jump(skip, 0, 1);
+        asm.loadv(2, 'x' as u32);
+        asm.output(2);
+        asm.bind(skip);
+        asm.loadv(2, 'y' as u32);
+        asm.output(2);
+        asm.halt();
+        assert_eq!(run(asm.finish()), b"y");
+    }
+
+    #[test]
+    fn loads_any_constant() {
+        for value in [0, 72, MAX_LOAD_VALUE + 1, u32::MAX, 0x8765_4321] {
+            let mut asm = Assembler::new();
+            asm.constant(1, value, 2);
+            for shift in [24, 16, 8, 0] {
+                // Keep one byte: divide it down, then subtract the bytes above it.
+                asm.constant(3, 1 << shift, 2);
+                asm.div(4, 1, 3);
+                asm.constant(3, 256, 2);
+                asm.div(5, 4, 3);
+                asm.mul(5, 5, 3);
+                asm.nand(5, 5, 5);
+                asm.add(4, 4, 5);
+                asm.loadv(5, 1);
+                asm.add(4, 4, 5);
+                asm.output(4);
+            }
+            asm.halt();
+            assert_eq!(run(asm.finish()), value.to_be_bytes());
+        }
+    }
+}
@@ -0,0 +1,433 @@
+use crate::io::Captured;
+use crate::machine::VirtualMachine;
+use crate::rumload;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Steps run between checks of the time limit.
+const STEPS_PER_CHECK: u64 = 1 << 12;
+
+///Limits on one case, `None` for none
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub timeout: Option<Duration>,
+}
+
+///A program to run, with its input and the output it should give
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Case {
+    pub name: String,
+    pub program: PathBuf,
+    pub input: Option<PathBuf>,
+    pub expected: PathBuf,
+    pub limits: Limits,
+}
+
+///How a case ended
+/// # Variants:
+/// * `Mismatch`: the program halted, but its output differs as described.
+/// * `Fault`: the machine failed.
+/// * `Error`: a file of the case could not be read.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Status {
+    Passed,
+    Mismatch(String),
+    Fault(String),
+    StepLimit,
+    Timeout,
+    Error(String),
+}
+impl Status {
+    fn label(&self) -> &'static str {
+        match self {
+            Status::Passed => "pass",
+            Status::Mismatch(_) => "FAIL",
+            Status::Fault(_) => "FAULT",
+            Status::StepLimit => "STEPS",
+            Status::Timeout => "TIMEOUT",
+            Status::Error(_) => "ERROR",
+        }
+    }
+    fn detail(&self) -> String {
+        match self {
+            Status::Passed => String::new(),
+            Status::Mismatch(diff) => diff.clone(),
+            Status::Fault(error) | Status::Error(error) => error.clone(),
+            Status::StepLimit => "step limit reached".to_string(),
+            Status::Timeout => "time limit reached".to_string(),
+        }
+    }
+}
+
+///Result of running one case
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub name: String,
+    pub status: Status,
+    pub steps: u64,
+    pub elapsed: Duration,
+    pub output: Vec<u8>,
+}
+
+///Reads a manifest, one case per line
+/// Each line is `name program input expected`, then optional
+/// `steps=N` and `timeout=SECONDS` overriding `defaults`. Paths are
+/// relative to `base`, and an input of `-` means no input. Blank lines
+/// and lines starting with `#` are skipped.
+pub fn read_manifest(text: &str, base: &Path, defaults: Limits) -> Result<Vec<Case>, String> {
+    let mut cases = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() || fields[0].starts_with('#') {
+            continue;
+        }
+        let bad = |what: &str| format!("manifest line {}: {}", number + 1, what);
+        if fields.len() < 4 {
+            return Err(bad("expected name, program, input and expected output"));
+        }
+        let mut limits = defaults;
+        for option in &fields[4..] {
+            match option.split_once('=') {
+                Some(("steps", n)) => {
+                    limits.max_steps = Some(n.parse().map_err(|_| bad("bad step limit"))?)
+                }
+                Some(("timeout", s)) => {
+                    let timeout = s.parse().ok().map(Duration::try_from_secs_f64);
+                    limits.timeout = Some(
+                        timeout
+                            .and_then(Result::ok)
+                            .ok_or_else(|| bad("bad timeout"))?,
+                    );
+                }
+                _ => return Err(bad(&format!("unknown option `{}`", option))),
+            }
+        }
+        cases.push(Case {
+            name: fields[0].to_string(),
+            program: base.join(fields[1]),
+            input: (fields[2] != "-").then(|| base.join(fields[2])),
+            expected: base.join(fields[3]),
+            limits,
+        });
+    }
+    Ok(cases)
+}
+
+///Runs one case in a machine of its own
+pub fn run_case(case: &Case) -> Outcome {
+    let start = Instant::now();
+    let mut outcome = Outcome {
+        name: case.name.clone(),
+        status: Status::Passed,
+        steps: 0,
+        elapsed: Duration::ZERO,
+        output: vec![],
+    };
+    let read = |path: &Path| fs::read(path).map_err(|e| format!("{}: {}", path.display(), e));
+    let files = read(&case.program).and_then(|program| {
+        let input = match &case.input {
+            Some(path) => read(path)?,
+            None => vec![],
+        };
+        Ok((program, input, read(&case.expected)?))
+    });
+    let (program, input, expected) = match files {
+        Ok(files) => files,
+        Err(error) => {
+            outcome.status = Status::Error(error);
+            return outcome;
+        }
+    };
+    let io = Captured::new(&input);
+    let output = io.output();
+    let mut vm = VirtualMachine::new();
+    vm.set_io(Box::new(io));
+    vm.initialize_machine(rumload::from_bytes(&program));
+    outcome.status = loop {
+        if case.limits.max_steps == Some(outcome.steps) {
+            break Status::StepLimit;
+        }
+        if outcome.steps.is_multiple_of(STEPS_PER_CHECK)
+            && case.limits.timeout.is_some_and(|t| start.elapsed() >= t)
+        {
+            break Status::Timeout;
+        }
+        match vm.step() {
+            Ok(true) => outcome.steps += 1,
+            Ok(false) => {
+                outcome.steps += 1;
+                break match diff(&expected, &output.lock().unwrap()) {
+                    None => Status::Passed,
+                    Some(diff) => Status::Mismatch(diff),
+                };
+            }
+            Err(error) => break Status::Fault(error.to_string()),
+        }
+    };
+    outcome.output = output.lock().unwrap().clone();
+    outcome.elapsed = start.elapsed();
+    outcome
+}
+
+///Runs every case on a pool of `jobs` threads
+/// Outcomes come back in the order of `cases`.
+pub fn run_all(cases: Vec<Case>, jobs: usize) -> Vec<Outcome> {
+    let total = cases.len();
+    let queue = Arc::new(Mutex::new(cases.into_iter().enumerate()));
+    let (sender, receiver) = mpsc::channel();
+    let workers: Vec<_> = (0..jobs.max(1))
+        .map(|_| {
+            let queue = Arc::clone(&queue);
+            let sender = sender.clone();
+            thread::spawn(move || loop {
+                let next = queue.lock().unwrap().next();
+                match next {
+                    Some((index, case)) => sender.send((index, run_case(&case))).unwrap(),
+                    None => return,
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+    let mut outcomes: Vec<Option<Outcome>> = vec![None; total];
+    for (index, outcome) in receiver {
+        outcomes[index] = Some(outcome);
+    }
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    outcomes.into_iter().map(Option::unwrap).collect()
+}
+
+///Describes the first line where `actual` differs from `expected`, if any
+pub fn diff(expected: &[u8], actual: &[u8]) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    let expected_lines: Vec<&[u8]> = expected.split(|&b| b == b'\n').collect();
+    let actual_lines: Vec<&[u8]> = actual.split(|&b| b == b'\n').collect();
+    let show = |line: Option<&&[u8]>| match line {
+        Some(line) => format!("{:?}", String::from_utf8_lossy(line)),
+        None => "end of output".to_string(),
+    };
+    let line = (0..)
+        .find(|&i| expected_lines.get(i) != actual_lines.get(i))
+        .unwrap();
+    Some(format!(
+        "line {}: expected {}, got {}",
+        line + 1,
+        show(expected_lines.get(line)),
+        show(actual_lines.get(line))
+    ))
+}
+
+///Prints a table of the outcomes and what went wrong with each failure
+pub fn summary(outcomes: &[Outcome], out: &mut dyn Write) -> io::Result<()> {
+    let width = outcomes
+        .iter()
+        .map(|o| o.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    writeln!(
+        out,
+        "{:<width$}  {:<7}  {:>12}  {:>9}",
+        "case", "status", "steps", "time"
+    )?;
+    for outcome in outcomes {
+        writeln!(
+            out,
+            "{:<width$}  {:<7}  {:>12}  {:>8.3}s",
+            outcome.name,
+            outcome.status.label(),
+            outcome.steps,
+            outcome.elapsed.as_secs_f64()
+        )?;
+    }
+    for outcome in outcomes.iter().filter(|o| o.status != Status::Passed) {
+        writeln!(out, "{}: {}", outcome.name, outcome.status.detail())?;
+    }
+    let passed = outcomes
+        .iter()
+        .filter(|o| o.status == Status::Passed)
+        .count();
+    writeln!(out, "passed {} of {}", passed, outcomes.len())
+}
+
+///Writes the outcomes as a JUnit XML test suite
+/// Output mismatches are failures, anything else that did not pass is an error.
+pub fn junit(outcomes: &[Outcome], suite: &str, out: &mut dyn Write) -> io::Result<()> {
+    let count = |kind: fn(&Status) -> bool| outcomes.iter().filter(|o| kind(&o.status)).count();
+    let failures = count(|s| matches!(s, Status::Mismatch(_)));
+    let errors = count(|s| !matches!(s, Status::Passed | Status::Mismatch(_)));
+    let time: f64 = outcomes.iter().map(|o| o.elapsed.as_secs_f64()).sum();
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(
+        out,
+        "<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+        escape(suite),
+        outcomes.len(),
+        failures,
+        errors,
+        time
+    )?;
+    for outcome in outcomes {
+        let mut element = format!(
+            "  <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            escape(&outcome.name),
+            escape(suite),
+            outcome.elapsed.as_secs_f64()
+        );
+        let kind = match outcome.status {
+            Status::Passed => None,
+            Status::Mismatch(_) => Some("failure"),
+            _ => Some("error"),
+        };
+        match kind {
+            None => element.push_str("/>"),
+            Some(kind) => write!(
+                element,
+                ">\n    <{} message=\"{}\">{}</{}>\n  </testcase>",
+                kind,
+                outcome.status.label(),
+                escape(&outcome.status.detail()),
+                kind
+            )
+            .unwrap(),
+        }
+        writeln!(out, "{}", element)?;
+    }
+    writeln!(out, "</testsuite>")
+}
+
+/// Escapes text for XML attributes and content.
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c if c < ' ' && c != '\n' && c != '\t' => write!(escaped, "&#{};", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::disasm::{encode, Instruction};
+
+    /// Echoes its input, then halts.
+    fn echo() -> Vec<u32> {
+        let mut asm = Assembler::new();
+        let (top, print, end) = (asm.label(), asm.label(), asm.label());
+        asm.bind(top);
+        asm.input(1);
+        asm.nand(2, 1, 1);
+        asm.branch(2, print, end, 0, [3, 4]);
+        asm.bind(print);
+        asm.output(1);
+        asm.jump(top, 0, 3);
+        asm.bind(end);
+        asm.halt();
+        asm.finish()
+    }
+
+    fn write_cases(dir: &Path) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("echo.um"), rumload::to_bytes(&echo())).unwrap();
+        let spin = encode(Instruction::LoadProgram { b: 0, c: 0 });
+        fs::write(dir.join("loop.um"), rumload::to_bytes(&[spin])).unwrap();
+        let fault = encode(Instruction::Div { a: 0, b: 0, c: 0 });
+        fs::write(dir.join("fault.um"), rumload::to_bytes(&[fault])).unwrap();
+        fs::write(dir.join("hi.txt"), "hi\n").unwrap();
+        fs::write(dir.join("bye.txt"), "bye\n").unwrap();
+    }
+
+    #[test]
+    fn reads_manifest_options() {
+        let text =
+            "# name program input expected\n\nhello hello.um - hello.out steps=10 timeout=0.5\n";
+        let cases = read_manifest(text, Path::new("/cases"), Limits::default()).unwrap();
+        assert_eq!(
+            cases,
+            vec![Case {
+                name: "hello".to_string(),
+                program: PathBuf::from("/cases/hello.um"),
+                input: None,
+                expected: PathBuf::from("/cases/hello.out"),
+                limits: Limits {
+                    max_steps: Some(10),
+                    timeout: Some(Duration::from_millis(500)),
+                },
+            }]
+        );
+        assert_eq!(
+            read_manifest("x a b", Path::new("."), Limits::default()),
+            Err("manifest line 1: expected name, program, input and expected output".to_string())
+        );
+        for timeout in ["-1", "nan", "1e300"] {
+            assert_eq!(
+                read_manifest(
+                    &format!("x a - b timeout={}", timeout),
+                    Path::new("."),
+                    Limits::default()
+                ),
+                Err("manifest line 1: bad timeout".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn runs_cases_in_parallel() {
+        let dir = std::env::temp_dir().join(format!("rum-batch-{}", std::process::id()));
+        write_cases(&dir);
+        let manifest = "
+            pass    echo.um   hi.txt  hi.txt
+            differ  echo.um   hi.txt  bye.txt
+            forever loop.um   -       hi.txt   steps=1000
+            fault   fault.um  -       hi.txt
+            missing nope.um   -       hi.txt
+        ";
+        let defaults = Limits {
+            max_steps: None,
+            timeout: Some(Duration::from_secs(10)),
+        };
+        let outcomes = run_all(read_manifest(manifest, &dir, defaults).unwrap(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+        let statuses: Vec<&str> = outcomes.iter().map(|o| o.status.label()).collect();
+        assert_eq!(statuses, vec!["pass", "FAIL", "STEPS", "FAULT", "ERROR"]);
+        assert_eq!(outcomes[0].output, b"hi\n");
+        assert_eq!(
+            outcomes[1].status,
+            Status::Mismatch("line 1: expected \"bye\", got \"hi\"".to_string())
+        );
+        assert_eq!(outcomes[2].steps, 1000);
+
+        let mut table = vec![];
+        summary(&outcomes, &mut table).unwrap();
+        assert!(String::from_utf8(table)
+            .unwrap()
+            .ends_with("passed 1 of 5\n"));
+        let mut xml = vec![];
+        junit(&outcomes, "suite", &mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(xml.contains("tests=\"5\" failures=\"1\" errors=\"3\""));
+        assert!(xml.contains(
+            "<failure message=\"FAIL\">line 1: expected &quot;bye&quot;, got &quot;hi&quot;</failure>"
+        ));
+    }
+}
@@ -0,0 +1,18 @@
+use rum::rumload;
+use rum::translate::translate;
+use std::env;
+use std::fs;
+use std::process;
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("usage: rum2rs program.um [output.rs]");
+        process::exit(2);
+    }
+    let program = rumload::load(Some(&args[1]));
+    let source = translate(&program, &args[1]);
+    match args.get(2) {
+        Some(output) => fs::write(output, source).unwrap(),
+        None => print!("{}", source),
+    }
+}
@@ -0,0 +1,31 @@
+use rum::compiler::compile_with_symbols;
+use rum::rumload;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 && args.len() != 3 {
+        eprintln!("usage: rumc source [output.um]");
+        process::exit(2);
+    }
+    let source = fs::read_to_string(&args[1]).unwrap_or_else(|error| {
+        eprintln!("rumc: {}: {}", args[1], error);
+        process::exit(1);
+    });
+    let (program, symbols) = compile_with_symbols(&source, &args[1]).unwrap_or_else(|error| {
+        eprintln!("rumc: {}: {}", args[1], error);
+        process::exit(1);
+    });
+    let output = match args.get(2) {
+        Some(output) => output.into(),
+        None => Path::new(&args[1]).with_extension("um"),
+    };
+    let written = fs::write(&output, rumload::to_bytes(&program))
+        .and_then(|_| rumload::save_symbols(&output, &symbols));
+    if let Err(error) = written {
+        eprintln!("rumc: {}: {}", output.display(), error);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,21 @@
+use rum::cfg::Graph;
+use rum::rumload;
+use std::env;
+use std::process;
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let (json, filename) = match &args[1..] {
+        [flag, filename] if flag == "--json" => (true, filename),
+        [filename] => (false, filename),
+        _ => {
+            eprintln!("usage: rumcfg [--json] program.um");
+            process::exit(2);
+        }
+    };
+    let graph = Graph::new(&rumload::load(Some(filename)));
+    if json {
+        print!("{}", graph.to_json());
+    } else {
+        print!("{}", graph.to_dot());
+    }
+}
@@ -0,0 +1,28 @@
+use rum::coverage::Coverage;
+use rum::rumload;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::process;
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 && args.len() != 3 {
+        eprintln!("usage: rumcov coverage.cov [program.um]");
+        process::exit(2);
+    }
+    let coverage = File::open(&args[1])
+        .and_then(|file| Coverage::read(&mut BufReader::new(file)))
+        .unwrap_or_else(|error| {
+            eprintln!("rumcov: {}: {}", args[1], error);
+            process::exit(1);
+        });
+    // Symbols of the program, from its .umsym file, if it has one.
+    let symbols = args.get(2).and_then(|program| {
+        rumload::load_symbols(program).unwrap_or_else(|error| {
+            eprintln!("rumcov: ignoring symbols of {}: {}", program, error);
+            None
+        })
+    });
+    // A closed pipe (e.g. piping into head) is not an error worth reporting.
+    let _ = coverage.report(&mut io::stdout().lock(), symbols.as_ref());
+}
@@ -0,0 +1,46 @@
+use rum::object::{link, symbols, Object};
+use rum::rumload;
+use std::env;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::process;
+fn usage() -> ! {
+    eprintln!("usage: rumld [-o output.um] object.umo...");
+    process::exit(2);
+}
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut output = "a.um".to_string();
+    let mut inputs = vec![];
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-o" => output = rest.next().unwrap_or_else(|| usage()).clone(),
+            _ => inputs.push(arg),
+        }
+    }
+    if inputs.is_empty() {
+        usage();
+    }
+    let objects: Vec<Object> = inputs
+        .iter()
+        .map(|input| {
+            File::open(input)
+                .and_then(|file| Object::read(&mut BufReader::new(file)))
+                .unwrap_or_else(|error| {
+                    eprintln!("rumld: {}: {}", input, error);
+                    process::exit(1);
+                })
+        })
+        .collect();
+    let program = link(&objects).unwrap_or_else(|error| {
+        eprintln!("rumld: {}", error);
+        process::exit(1);
+    });
+    let written = fs::write(&output, rumload::to_bytes(&program))
+        .and_then(|_| rumload::save_symbols(output.as_ref(), &symbols(&objects)));
+    if let Err(error) = written {
+        eprintln!("rumld: {}: {}", output, error);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,18 @@
+use rum::lint::{lint, report};
+use rum::rumload;
+use std::env;
+use std::io;
+use std::process;
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 {
+        eprintln!("usage: rumlint program.um");
+        process::exit(2);
+    }
+    let program = rumload::load(Some(&args[1]));
+    let findings = lint(&program);
+    let _ = report(&program, &findings, &mut io::stdout().lock());
+    if !findings.is_empty() {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,285 @@
+use crate::analysis::{Analysis, Exit};
+use crate::disasm::decode;
+use crate::json;
+use std::fmt::Write;
+
+///Basic block of $m[0]
+/// Covers addresses `start..end`. Only control reaching the block through
+/// `start` runs it, and only its last instruction can jump.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Block {
+    pub start: usize,
+    pub end: usize,
+}
+
+///Where an edge of the graph leads
+/// # Variants:
+/// * `Block`: a block of $m[0], by index.
+/// * `Unknown`: a Load Program whose target could not be resolved.
+/// * `Dynamic`: a Load Program from another segment.
+/// * `OutOfRange`: a jump past the end of $m[0].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Target {
+    Block(usize),
+    Unknown,
+    Dynamic,
+    OutOfRange(u32),
+}
+
+///Control flow edge
+/// `kind` is `next` for falling through, `jump` for a constant Load Program
+/// and `branch` for one of the targets of a Conditional Move before it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Edge {
+    pub from: usize,
+    pub to: Target,
+    pub kind: &'static str,
+}
+
+///Control flow graph of the code reachable in $m[0]
+/// Jump targets are resolved by constant propagation from address 0.
+pub struct Graph {
+    pub program: Vec<u32>,
+    pub blocks: Vec<Block>,
+    pub edges: Vec<Edge>,
+}
+impl Graph {
+    ///Builds the graph of a program
+    pub fn new(program: &[u32]) -> Self {
+        let analysis = Analysis::new(program);
+        let reached = |address: usize| analysis.exits.get(address).is_some_and(Option::is_some);
+        let mut leader = vec![false; program.len() + 1];
+        leader[0] = true;
+        for exit in analysis.exits.iter().flatten() {
+            if let Exit::Jump(targets) = exit {
+                for &target in targets {
+                    if (target as usize) < program.len() {
+                        leader[target as usize] = true;
+                    }
+                }
+            }
+        }
+        for (address, exit) in analysis.exits.iter().enumerate() {
+            if !matches!(exit, None | Some(Exit::Next)) {
+                leader[address + 1] = true;
+            }
+        }
+
+        let mut blocks: Vec<Block> = vec![];
+        for address in (0..program.len()).filter(|&a| reached(a)) {
+            match blocks.last_mut() {
+                Some(block) if block.end == address && !leader[address] => block.end += 1,
+                _ => blocks.push(Block {
+                    start: address,
+                    end: address + 1,
+                }),
+            }
+        }
+
+        let block_at =
+            |address: u32| match blocks.binary_search_by_key(&(address as usize), |b| b.start) {
+                Ok(index) => Target::Block(index),
+                Err(_) => Target::OutOfRange(address),
+            };
+        let mut edges = vec![];
+        for (from, block) in blocks.iter().enumerate() {
+            let edge = |to, kind| Edge { from, to, kind };
+            match analysis.exits[block.end - 1].as_ref().unwrap() {
+                Exit::Next => edges.push(edge(block_at(block.end as u32), "next")),
+                Exit::Halt => {}
+                Exit::Jump(targets) => {
+                    let kind = if targets.len() > 1 { "branch" } else { "jump" };
+                    for &target in targets {
+                        edges.push(edge(block_at(target), kind));
+                    }
+                }
+                Exit::Indirect => edges.push(edge(Target::Unknown, "jump")),
+                Exit::Dynamic => edges.push(edge(Target::Dynamic, "jump")),
+            }
+        }
+        Graph {
+            program: program.to_vec(),
+            blocks,
+            edges,
+        }
+    }
+
+    ///Writes the graph in Graphviz DOT format
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        out.push_str("digraph um {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for (index, block) in self.blocks.iter().enumerate() {
+            let mut label = String::new();
+            for address in block.start..block.end {
+                write!(label, "{}: {}\\l", address, decode(self.program[address])).unwrap();
+            }
+            writeln!(out, "    b{} [label=\"{}\"];", index, label).unwrap();
+        }
+        let mut special = vec![];
+        for edge in &self.edges {
+            let (to, label) = match edge.to {
+                Target::Block(index) => (format!("b{}", index), None),
+                Target::Unknown => ("unknown".to_string(), Some("?")),
+                Target::Dynamic => ("dynamic".to_string(), Some("other segment")),
+                Target::OutOfRange(address) => (format!("out{}", address), None),
+            };
+            if let Target::OutOfRange(address) = edge.to {
+                special.push(format!(
+                    "    out{} [shape=octagon, label=\"{} (past end)\"];",
+                    address, address
+                ));
+            } else if let Some(label) = label {
+                special.push(format!(
+                    "    {} [shape=ellipse, style=dashed, label=\"{}\"];",
+                    to, label
+                ));
+            }
+            let style = match edge.kind {
+                "next" => "",
+                "branch" => " [color=blue]",
+                _ => " [style=bold]",
+            };
+            writeln!(out, "    b{} -> {}{};", edge.from, to, style).unwrap();
+        }
+        special.sort();
+        special.dedup();
+        for line in special {
+            writeln!(out, "{}", line).unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    ///Writes the graph as JSON
+    /// Blocks list their instructions, and edges name blocks by index, with
+    /// `"unknown"`, `"dynamic"` or the address for other targets.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\n  \"blocks\": [");
+        for (index, block) in self.blocks.iter().enumerate() {
+            let instructions: Vec<String> = (block.start..block.end)
+                .map(|address| {
+                    let word = self.program[address];
+                    format!(
+                        "{{\"address\": {}, \"word\": {}, \"text\": {}}}",
+                        address,
+                        word,
+                        json::string(&decode(word).to_string())
+                    )
+                })
+                .collect();
+            write!(
+                out,
+                "{}\n    {{\"id\": {}, \"start\": {}, \"end\": {}, \"instructions\": [{}]}}",
+                if index == 0 { "" } else { "," },
+                index,
+                block.start,
+                block.end,
+                instructions.join(", ")
+            )
+            .unwrap();
+        }
+        out.push_str("\n  ],\n  \"edges\": [");
+        for (index, edge) in self.edges.iter().enumerate() {
+            let to = match edge.to {
+                Target::Block(block) => block.to_string(),
+                Target::Unknown => json::string("unknown"),
+                Target::Dynamic => json::string("dynamic"),
+                Target::OutOfRange(address) => format!("{{\"address\": {}}}", address),
+            };
+            write!(
+                out,
+                "{}\n    {{\"from\": {}, \"to\": {}, \"kind\": {}}}",
+                if index == 0 { "" } else { "," },
+                edge.from,
+                to,
+                json::string(edge.kind)
+            )
+            .unwrap();
+        }
+        out.push_str("\n  ]\n}\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::disasm::{encode, Instruction};
+
+    fn sample() -> Vec<u32> {
+        let mut asm = Assembler::new();
+        asm.input(3);
+        asm.loadv(1, 6);
+        asm.loadv(2, 7);
+        asm.cmov(2, 1, 3);
+        asm.loadp(0, 2);
+        asm.halt(); // 5: never reached
+        asm.loadv(4, 9);
+        asm.output(4);
+        asm.halt();
+        asm.finish()
+    }
+
+    #[test]
+    fn splits_blocks_at_targets() {
+        let graph = Graph::new(&sample());
+        assert_eq!(
+            graph.blocks,
+            vec![
+                Block { start: 0, end: 5 },
+                Block { start: 6, end: 7 },
+                Block { start: 7, end: 9 },
+            ]
+        );
+        assert_eq!(
+            graph.edges,
+            vec![
+                Edge {
+                    from: 0,
+                    to: Target::Block(2),
+                    kind: "branch"
+                },
+                Edge {
+                    from: 0,
+                    to: Target::Block(1),
+                    kind: "branch"
+                },
+                Edge {
+                    from: 1,
+                    to: Target::Block(2),
+                    kind: "next"
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn marks_unknown_and_out_of_range_targets() {
+        let mut asm = Assembler::new();
+        asm.input(1);
+        asm.loadp(0, 1);
+        let graph = Graph::new(&asm.finish());
+        assert_eq!(graph.edges[0].to, Target::Unknown);
+        let mut asm = Assembler::new();
+        asm.loadv(1, 50);
+        asm.loadp(0, 1);
+        let graph = Graph::new(&asm.finish());
+        assert_eq!(graph.edges[0].to, Target::OutOfRange(50));
+    }
+
+    #[test]
+    fn exports_dot_and_json() {
+        let graph = Graph::new(&sample());
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph um {"));
+        assert!(dot.contains("    b1 [label=\"6: loadv r4, 9\\l\"];"));
+        assert!(dot.contains("    b0 -> b2 [color=blue];"));
+        let json = graph.to_json();
+        assert!(json.contains(&format!(
+            "{{\"id\": 1, \"start\": 6, \"end\": 7, \"instructions\": [{{\"address\": 6, \"word\": {}, \"text\": \"loadv r4, 9\"}}]}}",
+            encode(Instruction::LoadValue { a: 4, value: 9 })
+        )));
+        assert!(json.contains("{\"from\": 1, \"to\": 2, \"kind\": \"next\"}"));
+    }
+}
@@ -0,0 +1,498 @@
+use super::parser::{Expr, Function, Program, Stmt, StmtKind};
+use super::CompileError;
+use crate::asm::{Assembler, Label};
+use crate::symbols::{self, Symbols};
+use std::collections::HashMap;
+
+/// Always holds 0, for Load Program and moves.
+const ZERO: usize = 0;
+/// Identifier of the stack segment.
+const STACK: usize = 1;
+/// Index of the first free word of the stack.
+const SP: usize = 2;
+/// Index of the first argument of the running function.
+const FP: usize = 3;
+/// Result of the expression just evaluated.
+const ACC: usize = 4;
+/// Right operand of a binary operator.
+const ARG: usize = 5;
+/// Address of a variable, and scratch.
+const ADDR: usize = 6;
+/// Scratch for constants.
+const TMP: usize = 7;
+
+/// Words in the stack segment, which also holds the globals.
+pub const STACK_WORDS: u32 = 1 << 20;
+
+/// Functions provided by the compiler, with their number of arguments.
+const BUILTINS: [(&str, usize); 4] = [("putc", 1), ("getc", 0), ("array", 1), ("free", 1)];
+
+/// Where a variable lives, as an index into the stack segment.
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    Global(u32),
+    Local(u32),
+}
+
+///Generates a UM program from a parsed one
+/// The stack is a segment mapped at startup. Globals take its first words,
+/// then each call pushes the caller's frame pointer, the return address
+/// and the arguments, and the callee reserves its locals above them.
+/// Symbols name the startup code `_start` and each function by its name,
+/// with statements attributed to lines of `file`.
+pub fn generate(program: &Program, file: &str) -> Result<(Vec<u32>, Symbols), CompileError> {
+    let mut codegen = Codegen {
+        asm: Assembler::new(),
+        functions: HashMap::new(),
+        globals: HashMap::new(),
+        scopes: vec![],
+        next_local: 0,
+        params: 0,
+        labels: vec![("_start".to_string(), 0)],
+        lines: vec![],
+    };
+    for global in &program.globals {
+        if let StmtKind::Var(name, _) = &global.kind {
+            let index = codegen.globals.len() as u32;
+            if codegen.globals.insert(name.clone(), index).is_some() {
+                return Err(CompileError::new(
+                    global.line,
+                    format!("`{}` is already declared", name),
+                ));
+            }
+        }
+    }
+    for function in &program.functions {
+        if BUILTINS.iter().any(|&(name, _)| name == function.name) {
+            return Err(CompileError::new(
+                function.line,
+                format!("`{}` is a builtin", function.name),
+            ));
+        }
+        let label = codegen.asm.label();
+        let previous = codegen
+            .functions
+            .insert(function.name.clone(), (label, function.params.len()));
+        if previous.is_some() {
+            return Err(CompileError::new(
+                function.line,
+                format!("`{}` is already defined", function.name),
+            ));
+        }
+    }
+    match codegen.functions.get("main") {
+        Some(&(_, 0)) => {}
+        Some(_) => {
+            let main = program.functions.iter().find(|f| f.name == "main").unwrap();
+            return Err(CompileError::new(main.line, "`main` takes no arguments"));
+        }
+        None => return Err(CompileError::new(1, "no `main` function")),
+    }
+
+    codegen.asm.loadv(ARG, STACK_WORDS);
+    codegen.asm.map(STACK, ARG);
+    codegen.asm.constant(SP, codegen.globals.len() as u32, TMP);
+    for global in &program.globals {
+        if let StmtKind::Var(name, Some(init)) = &global.kind {
+            codegen.lines.push((codegen.asm.here(), Some(global.line)));
+            codegen.expr(init, global.line)?;
+            codegen.address(Slot::Global(codegen.globals[name]));
+            codegen.asm.store(STACK, ADDR, ACC);
+        }
+    }
+    codegen.lines.push((codegen.asm.here(), None));
+    codegen.call("main", &[], 1)?;
+    codegen.asm.halt();
+    for function in &program.functions {
+        codegen.function(function)?;
+    }
+    let end = codegen.asm.here();
+    let symbols = codegen.symbols(file, end);
+    Ok((codegen.asm.finish(), symbols))
+}
+
+/// State of code generation.
+/// `scopes` maps the names visible in each enclosing block to stack
+/// offsets from `FP`, innermost last. `labels` and `lines` record where
+/// each function and statement starts, for the symbols, with `None` for
+/// code that comes from no line.
+struct Codegen {
+    asm: Assembler,
+    functions: HashMap<String, (Label, usize)>,
+    globals: HashMap<String, u32>,
+    scopes: Vec<HashMap<String, u32>>,
+    next_local: u32,
+    params: u32,
+    labels: Vec<(String, u32)>,
+    lines: Vec<(u32, Option<usize>)>,
+}
+impl Codegen {
+    fn function(&mut self, function: &Function) -> Result<(), CompileError> {
+        let (label, _) = self.functions[&function.name];
+        self.labels.push((function.name.clone(), self.asm.here()));
+        self.lines.push((self.asm.here(), Some(function.line)));
+        self.asm.bind(label);
+        let mut params = HashMap::new();
+        for (i, name) in function.params.iter().enumerate() {
+            if params.insert(name.clone(), i as u32).is_some() {
+                return Err(CompileError::new(
+                    function.line,
+                    format!("parameter `{}` is repeated", name),
+                ));
+            }
+        }
+        self.scopes = vec![params];
+        self.params = function.params.len() as u32;
+        self.next_local = 0;
+        self.asm.constant(TMP, count_locals(&function.body), ADDR);
+        self.asm.add(SP, SP, TMP);
+        self.block(&function.body)?;
+        self.asm.loadv(ACC, 0);
+        self.ret();
+        Ok(())
+    }
+
+    fn block(&mut self, body: &[Stmt]) -> Result<(), CompileError> {
+        self.scopes.push(HashMap::new());
+        for stmt in body {
+            self.statement(stmt)?;
+        }
+        self.scopes.pop();
+        Ok(())
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> Result<(), CompileError> {
+        let line = stmt.line;
+        self.lines.push((self.asm.here(), Some(line)));
+        match &stmt.kind {
+            StmtKind::Var(name, init) => {
+                if self.scopes.last().unwrap().contains_key(name) {
+                    return Err(CompileError::new(
+                        line,
+                        format!("`{}` is already declared", name),
+                    ));
+                }
+                match init {
+                    Some(init) => self.expr(init, line)?,
+                    None => self.asm.loadv(ACC, 0),
+                }
+                let offset = self.params + self.next_local;
+                self.next_local += 1;
+                self.scopes.last_mut().unwrap().insert(name.clone(), offset);
+                self.address(Slot::Local(offset));
+                self.asm.store(STACK, ADDR, ACC);
+            }
+            StmtKind::Assign(name, value) => {
+                self.expr(value, line)?;
+                let slot = self.lookup(name, line)?;
+                self.address(slot);
+                self.asm.store(STACK, ADDR, ACC);
+            }
+            StmtKind::Store(array, index, value) => {
+                self.expr(array, line)?;
+                self.push(ACC);
+                self.expr(index, line)?;
+                self.push(ACC);
+                self.expr(value, line)?;
+                self.pop(ARG);
+                self.pop(ADDR);
+                self.asm.store(ADDR, ARG, ACC);
+            }
+            StmtKind::If(condition, then, otherwise) => {
+                let (yes, no, end) = (self.asm.label(), self.asm.label(), self.asm.label());
+                self.expr(condition, line)?;
+                self.asm.branch(ACC, yes, no, ZERO, [ADDR, TMP]);
+                self.asm.bind(yes);
+                self.block(then)?;
+                self.asm.jump(end, ZERO, TMP);
+                self.asm.bind(no);
+                self.block(otherwise)?;
+                self.asm.bind(end);
+            }
+            StmtKind::While(condition, body) => {
+                let (top, inside, end) = (self.asm.label(), self.asm.label(), self.asm.label());
+                self.asm.bind(top);
+                self.expr(condition, line)?;
+                self.asm.branch(ACC, inside, end, ZERO, [ADDR, TMP]);
+                self.asm.bind(inside);
+                self.block(body)?;
+                self.asm.jump(top, ZERO, TMP);
+                self.asm.bind(end);
+            }
+            StmtKind::Return(value) => {
+                match value {
+                    Some(value) => self.expr(value, line)?,
+                    None => self.asm.loadv(ACC, 0),
+                }
+                self.ret();
+            }
+            StmtKind::Expr(expr) => self.expr(expr, line)?,
+        }
+        Ok(())
+    }
+
+    /// Evaluates `expr` into `ACC`.
+    fn expr(&mut self, expr: &Expr, line: usize) -> Result<(), CompileError> {
+        match expr {
+            Expr::Number(n) => self.asm.constant(ACC, *n, TMP),
+            Expr::Var(name) => {
+                let slot = self.lookup(name, line)?;
+                self.address(slot);
+                self.asm.load(ACC, STACK, ADDR);
+            }
+            Expr::Index(array, index) => {
+                self.operands(array, index, line)?;
+                self.asm.load(ACC, ACC, ARG);
+            }
+            Expr::Call(name, args) => self.call(name, args, line)?,
+            Expr::Unary(op, operand) => {
+                self.expr(operand, line)?;
+                match *op {
+                    "-" => {
+                        self.asm.nand(ACC, ACC, ACC);
+                        self.asm.loadv(TMP, 1);
+                        self.asm.add(ACC, ACC, TMP);
+                    }
+                    _ => self.not(),
+                }
+            }
+            Expr::Binary("&&", left, right) => {
+                let (rhs, no, end) = (self.asm.label(), self.asm.label(), self.asm.label());
+                self.expr(left, line)?;
+                self.asm.branch(ACC, rhs, no, ZERO, [ADDR, TMP]);
+                self.asm.bind(rhs);
+                self.expr(right, line)?;
+                self.truth();
+                self.asm.jump(end, ZERO, TMP);
+                self.asm.bind(no);
+                self.asm.loadv(ACC, 0);
+                self.asm.bind(end);
+            }
+            Expr::Binary("||", left, right) => {
+                let (yes, rhs, end) = (self.asm.label(), self.asm.label(), self.asm.label());
+                self.expr(left, line)?;
+                self.asm.branch(ACC, yes, rhs, ZERO, [ADDR, TMP]);
+                self.asm.bind(rhs);
+                self.expr(right, line)?;
+                self.truth();
+                self.asm.jump(end, ZERO, TMP);
+                self.asm.bind(yes);
+                self.asm.loadv(ACC, 1);
+                self.asm.bind(end);
+            }
+            Expr::Binary(op, left, right) => {
+                self.operands(left, right, line)?;
+                match *op {
+                    "+" => self.asm.add(ACC, ACC, ARG),
+                    "*" => self.asm.mul(ACC, ACC, ARG),
+                    "/" => self.asm.div(ACC, ACC, ARG),
+                    "-" => self.subtract(ARG),
+                    "%" => {
+                        self.asm.div(ADDR, ACC, ARG);
+                        self.asm.mul(ADDR, ADDR, ARG);
+                        self.subtract(ADDR);
+                    }
+                    "==" => {
+                        self.subtract(ARG);
+                        self.not();
+                    }
+                    "!=" => {
+                        self.subtract(ARG);
+                        self.truth();
+                    }
+                    "<" => self.less(),
+                    ">=" => {
+                        self.less();
+                        self.not();
+                    }
+                    ">" => {
+                        self.swap();
+                        self.less();
+                    }
+                    _ => {
+                        self.swap();
+                        self.less();
+                        self.not();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluates `left` into `ACC` and `right` into `ARG`.
+    fn operands(&mut self, left: &Expr, right: &Expr, line: usize) -> Result<(), CompileError> {
+        self.expr(left, line)?;
+        self.push(ACC);
+        self.expr(right, line)?;
+        self.asm.add(ARG, ACC, ZERO);
+        self.pop(ACC);
+        Ok(())
+    }
+
+    /// Calls a builtin or a function, leaving its result in `ACC`.
+    fn call(&mut self, name: &str, args: &[Expr], line: usize) -> Result<(), CompileError> {
+        let arity = BUILTINS
+            .iter()
+            .find(|&&(builtin, _)| builtin == name)
+            .map(|&(_, arity)| arity)
+            .or_else(|| self.functions.get(name).map(|&(_, arity)| arity))
+            .ok_or_else(|| CompileError::new(line, format!("unknown function `{}`", name)))?;
+        if args.len() != arity {
+            return Err(CompileError::new(
+                line,
+                format!("`{}` takes {} arguments, not {}", name, arity, args.len()),
+            ));
+        }
+        match name {
+            "putc" => {
+                self.expr(&args[0], line)?;
+                self.asm.output(ACC);
+            }
+            "getc" => self.asm.input(ACC),
+            "array" => {
+                self.expr(&args[0], line)?;
+                self.asm.add(ARG, ACC, ZERO);
+                self.asm.map(ACC, ARG);
+            }
+            "free" => {
+                self.expr(&args[0], line)?;
+                self.asm.unmap(ACC);
+                self.asm.loadv(ACC, 0);
+            }
+            _ => {
+                let (label, _) = self.functions[name];
+                let back = self.asm.label();
+                self.push(FP);
+                self.asm.loadv_label(ACC, back);
+                self.push(ACC);
+                for arg in args {
+                    self.expr(arg, line)?;
+                    self.push(ACC);
+                }
+                self.asm
+                    .constant(TMP, (args.len() as u32).wrapping_neg(), ADDR);
+                self.asm.add(FP, SP, TMP);
+                self.asm.jump(label, ZERO, TMP);
+                self.asm.bind(back);
+            }
+        }
+        Ok(())
+    }
+
+    /// Turns the recorded starts into ranges ending at `end`.
+    fn symbols(&self, file: &str, end: u32) -> Symbols {
+        let mut symbols = Symbols::default();
+        for (i, (name, start)) in self.labels.iter().enumerate() {
+            symbols.labels.push(symbols::Label {
+                start: *start,
+                end: self.labels.get(i + 1).map_or(end, |next| next.1),
+                name: name.clone(),
+            });
+        }
+        for (i, &(start, line)) in self.lines.iter().enumerate() {
+            let stop = self.lines.get(i + 1).map_or(end, |next| next.0);
+            let line = match line {
+                Some(line) => line,
+                None => continue,
+            };
+            match symbols.lines.last_mut() {
+                Some(last) if last.line == line as u32 && last.end == start => last.end = stop,
+                _ if start < stop => symbols.lines.push(symbols::Line {
+                    start,
+                    end: stop,
+                    file: file.to_string(),
+                    line: line as u32,
+                }),
+                _ => {}
+            }
+        }
+        symbols
+    }
+
+    /// Returns `ACC` to the caller, restoring its frame.
+    fn ret(&mut self) {
+        self.asm.nand(TMP, ZERO, ZERO);
+        self.asm.add(SP, FP, TMP);
+        self.asm.load(ARG, STACK, SP);
+        self.asm.add(SP, SP, TMP);
+        self.asm.load(FP, STACK, SP);
+        self.asm.loadp(ZERO, ARG);
+    }
+
+    fn lookup(&self, name: &str, line: usize) -> Result<Slot, CompileError> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied().map(Slot::Local))
+            .or_else(|| self.globals.get(name).copied().map(Slot::Global))
+            .ok_or_else(|| CompileError::new(line, format!("unknown variable `{}`", name)))
+    }
+    /// Puts the index of `slot` in the stack segment into `ADDR`.
+    fn address(&mut self, slot: Slot) {
+        match slot {
+            Slot::Global(index) => self.asm.constant(ADDR, index, TMP),
+            Slot::Local(offset) => {
+                self.asm.constant(ADDR, offset, TMP);
+                self.asm.add(ADDR, FP, ADDR);
+            }
+        }
+    }
+    fn push(&mut self, register: usize) {
+        self.asm.store(STACK, SP, register);
+        self.asm.loadv(TMP, 1);
+        self.asm.add(SP, SP, TMP);
+    }
+    fn pop(&mut self, register: usize) {
+        self.asm.nand(TMP, ZERO, ZERO);
+        self.asm.add(SP, SP, TMP);
+        self.asm.load(register, STACK, SP);
+    }
+    /// `ACC -= register`, overwriting `register`.
+    fn subtract(&mut self, register: usize) {
+        self.asm.nand(register, register, register);
+        self.asm.add(ACC, ACC, register);
+        self.asm.loadv(TMP, 1);
+        self.asm.add(ACC, ACC, TMP);
+    }
+    /// `ACC = ACC == 0`
+    fn not(&mut self) {
+        self.asm.loadv(TMP, 1);
+        self.asm.cmov(TMP, ZERO, ACC);
+        self.asm.add(ACC, TMP, ZERO);
+    }
+    /// `ACC = ACC != 0`
+    fn truth(&mut self) {
+        self.asm.loadv(TMP, 1);
+        self.asm.cmov(ACC, TMP, ACC);
+    }
+    fn swap(&mut self) {
+        self.asm.add(ADDR, ACC, ZERO);
+        self.asm.add(ACC, ARG, ZERO);
+        self.asm.add(ARG, ADDR, ZERO);
+    }
+    /// `ACC = ACC < ARG`, unsigned. `ACC / ARG` is zero exactly when
+    /// `ACC` is smaller, as long as `ARG` is not zero.
+    fn less(&mut self) {
+        self.asm.loadv(ADDR, 1);
+        self.asm.cmov(ADDR, ARG, ARG);
+        self.asm.div(ADDR, ACC, ADDR);
+        self.asm.loadv(ACC, 1);
+        self.asm.cmov(ACC, ZERO, ADDR);
+        self.asm.add(ADDR, ACC, ZERO);
+        self.asm.loadv(ACC, 0);
+        self.asm.cmov(ACC, ADDR, ARG);
+    }
+}
+
+/// Counts the variables a function body declares, each taking its own slot.
+fn count_locals(body: &[Stmt]) -> u32 {
+    body.iter()
+        .map(|stmt| match &stmt.kind {
+            StmtKind::Var(..) => 1,
+            StmtKind::If(_, then, otherwise) => count_locals(then) + count_locals(otherwise),
+            StmtKind::While(_, body) => count_locals(body),
+            _ => 0,
+        })
+        .sum()
+}
@@ -0,0 +1,112 @@
+use super::CompileError;
+
+/// Punctuation, longest first so `==` is not read as two `=`.
+const PUNCTUATION: [&str; 23] = [
+    "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "!", "=", "(", ")", "{",
+    "}", "[", "]", ",", ";",
+];
+
+///Token of the source language
+/// Keywords are read as identifiers and told apart by the parser.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Token {
+    Number(u32),
+    Ident(String),
+    Punct(&'static str),
+    Eof,
+}
+
+///Splits source into tokens, each with its line number
+/// Skips whitespace and `//` comments. Character literals such as `'a'`
+/// and `'\n'` are read as numbers.
+pub fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, CompileError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '\n' {
+            line += 1;
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let value = text
+                .parse()
+                .map_err(|_| CompileError::new(line, format!("bad number `{}`", text)))?;
+            tokens.push((Token::Number(value), line));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push((Token::Ident(chars[start..i].iter().collect()), line));
+        } else if c == '\'' {
+            let (value, len) = match (chars.get(i + 1), chars.get(i + 2), chars.get(i + 3)) {
+                (Some('\\'), Some(&escape), Some('\'')) => {
+                    let value = match escape {
+                        'n' => '\n',
+                        't' => '\t',
+                        '0' => '\0',
+                        '\\' | '\'' => escape,
+                        _ => return Err(CompileError::new(line, "bad character escape")),
+                    };
+                    (value, 4)
+                }
+                (Some(&value), Some('\''), _) if value != '\\' => (value, 3),
+                _ => return Err(CompileError::new(line, "bad character literal")),
+            };
+            if value as u32 > 255 {
+                return Err(CompileError::new(line, "character does not fit a byte"));
+            }
+            tokens.push((Token::Number(value as u32), line));
+            i += len;
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let punct = PUNCTUATION
+                .iter()
+                .find(|p| rest.starts_with(*p))
+                .ok_or_else(|| CompileError::new(line, format!("unexpected `{}`", c)))?;
+            tokens.push((Token::Punct(punct), line));
+            i += punct.len();
+        }
+    }
+    tokens.push((Token::Eof, line));
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn reads_tokens_and_lines() {
+        let tokens = tokenize("x <= 10; // done\nputc('\\n');").unwrap();
+        let tokens: Vec<Token> = tokens.into_iter().map(|(t, _)| t).collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Ident("x".to_string()),
+                Token::Punct("<="),
+                Token::Number(10),
+                Token::Punct(";"),
+                Token::Ident("putc".to_string()),
+                Token::Punct("("),
+                Token::Number(10),
+                Token::Punct(")"),
+                Token::Punct(";"),
+                Token::Eof,
+            ]
+        );
+        assert_eq!(tokenize("\n\n@").unwrap_err().line, 3);
+    }
+}
@@ -0,0 +1,226 @@
+mod codegen;
+mod lexer;
+mod parser;
+
+use crate::symbols::Symbols;
+use std::error::Error;
+use std::fmt;
+
+pub use codegen::STACK_WORDS;
+
+///A mistake in the source, with the line it was found on
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CompileError {
+    pub line: usize,
+    pub message: String,
+}
+impl CompileError {
+    fn new(line: usize, message: impl Into<String>) -> Self {
+        CompileError {
+            line,
+            message: message.into(),
+        }
+    }
+}
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+impl Error for CompileError {}
+
+///Compiles a program in the tiny language into a program for $m[0]
+/// The language has unsigned 32 bit words as its only type:
+///  * `fn name(a, b) { ... }` defines a function, and the program runs `main()`.
+///  * `var x = e;` declares a variable, global when outside a function.
+///  * `if (e) { ... } else { ... }`, `while (e) { ... }` and `return e;`.
+///  * `+ - * / %`, the unsigned comparisons `== != < > <= >=`, `&& || !`
+///    and unary `-`. Numbers are decimal or character literals like `'a'`.
+///  * `array(n)` maps a segment of `n` zero words, `a[i]` reads and
+///    writes it and `free(a)` unmaps it.
+///  * `putc(c)` outputs a byte, `getc()` reads one, or 4294967295 at
+///    the end of input.
+pub fn compile(source: &str) -> Result<Vec<u32>, CompileError> {
+    compile_with_symbols(source, "").map(|(program, _)| program)
+}
+
+///Compiles a program along with its debug symbols
+/// Source lines are attributed to `file`.
+pub fn compile_with_symbols(source: &str, file: &str) -> Result<(Vec<u32>, Symbols), CompileError> {
+    codegen::generate(&parser::parse(source)?, file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::Captured;
+    use crate::machine::VirtualMachine;
+    use crate::rumload;
+    use std::fs;
+
+    /// Compiles `source` to a `.um` image, loads it back and runs it.
+    fn run(name: &str, source: &str, input: &[u8]) -> String {
+        let image = std::env::temp_dir().join(format!("rumc-{}-{}.um", name, std::process::id()));
+        fs::write(&image, rumload::to_bytes(&compile(source).unwrap())).unwrap();
+        let program = rumload::load(image.to_str());
+        fs::remove_file(&image).unwrap();
+        let io = Captured::new(input);
+        let output = io.output();
+        let mut vm = VirtualMachine::new();
+        vm.set_io(Box::new(io));
+        vm.initialize_machine(program);
+        vm.run_program().unwrap();
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        output
+    }
+
+    /// Prints a number in decimal, shared by the tests below.
+    const PRINT: &str = "
+        fn print(n) {
+            if (n >= 10) { print(n / 10); }
+            putc('0' + n % 10);
+        }
+    ";
+
+    #[test]
+    fn prints_characters() {
+        let source = "fn main() { putc('h'); putc('i'); putc('\\n'); }";
+        assert_eq!(run("chars", source, b""), "hi\n");
+    }
+
+    #[test]
+    fn evaluates_arithmetic() {
+        let source = format!(
+            "{} fn main() {{
+                print(1 + 2 * 3 - 4 / 2); putc(' ');
+                print(17 % 5); putc(' ');
+                print(-1); putc(' ');
+                print(100000 * 100000); putc(' ');
+                print((7 > 3) + (3 > 7) + (3 <= 3) + (2 == 2) + (2 != 2) + !0 + !5);
+            }}",
+            PRINT
+        );
+        assert_eq!(run("arith", &source, b""), "5 2 4294967295 1410065408 4");
+    }
+
+    #[test]
+    fn compares_unsigned() {
+        let source = format!(
+            "{} fn main() {{
+                print(0 < 1); print(1 < 0); print(0 < 0); print(-1 > 1);
+                print(4000000000 < 4000000001); print(5 >= 0); print(0 >= 5);
+            }}",
+            PRINT
+        );
+        assert_eq!(run("compare", &source, b""), "1001110");
+    }
+
+    #[test]
+    fn loops_and_branches() {
+        let source = format!(
+            "{} fn main() {{
+                var i = 1;
+                while (i <= 15) {{
+                    if (i % 15 == 0) {{ putc('F'); putc('B'); }}
+                    else if (i % 3 == 0) {{ putc('F'); }}
+                    else if (i % 5 == 0) {{ putc('B'); }}
+                    else {{ print(i); }}
+                    putc(' ');
+                    i = i + 1;
+                }}
+            }}",
+            PRINT
+        );
+        assert_eq!(
+            run("fizzbuzz", &source, b""),
+            "1 2 F 4 B F 7 8 F B 11 F 13 14 FB "
+        );
+    }
+
+    #[test]
+    fn calls_recursive_functions() {
+        let source = format!(
+            "{}
+            fn fib(n) {{
+                if (n < 2) {{ return n; }}
+                return fib(n - 1) + fib(n - 2);
+            }}
+            fn add3(a, b, c) {{ var sum = a + b; return sum + c; }}
+            fn main() {{ print(fib(15)); putc(' '); print(add3(1, 20, 300)); }}",
+            PRINT
+        );
+        assert_eq!(run("fib", &source, b""), "610 321");
+    }
+
+    #[test]
+    fn uses_arrays_and_globals() {
+        let source = format!(
+            "{}
+            var count = 0;
+            var limit = 30;
+            fn main() {{
+                var sieve = array(limit);
+                var i = 2;
+                while (i < limit) {{
+                    if (!sieve[i]) {{
+                        print(i); putc(' ');
+                        count = count + 1;
+                        var j = i * i;
+                        while (j < limit) {{ sieve[j] = 1; j = j + i; }}
+                    }}
+                    i = i + 1;
+                }}
+                free(sieve);
+                print(count);
+            }}",
+            PRINT
+        );
+        assert_eq!(run("sieve", &source, b""), "2 3 5 7 11 13 17 19 23 29 10");
+    }
+
+    #[test]
+    fn reads_input_until_end() {
+        let source = "
+            fn main() {
+                var c = getc();
+                while (c != -1 && c != '.') {
+                    if (c >= 'a' && c <= 'z') { c = c - 32; }
+                    putc(c);
+                    c = getc();
+                }
+            }";
+        assert_eq!(run("upper", source, b"Hello, um"), "HELLO, UM");
+        assert_eq!(run("upper-dot", source, b"ab.cd"), "AB");
+    }
+
+    #[test]
+    fn records_functions_and_lines() {
+        let source = "fn main() {\n  putc(f());\n}\nfn f() {\n  return 'x';\n}";
+        let (program, symbols) = compile_with_symbols(source, "x.rl").unwrap();
+        let names: Vec<&str> = symbols.labels.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, vec!["_start", "main", "f"]);
+        assert_eq!(symbols.labels[2].end, program.len() as u32);
+        let out = program.iter().position(|&w| w >> 28 == 10).unwrap() as u32;
+        assert_eq!(symbols.line_at(out), Some(("x.rl", 2)));
+        let f = symbols.labels[2].start;
+        assert!(symbols.describe(f + 1).starts_with("f+1 (x.rl:"));
+    }
+
+    #[test]
+    fn reports_mistakes() {
+        let error = |source| compile(source).unwrap_err().to_string();
+        assert_eq!(error("fn f() {}"), "line 1: no `main` function");
+        assert_eq!(
+            error("fn main() {\n  x = 1;\n}"),
+            "line 2: unknown variable `x`"
+        );
+        assert_eq!(
+            error("fn f(a) {}\nfn main() { f(); }"),
+            "line 2: `f` takes 1 arguments, not 0"
+        );
+        assert_eq!(
+            error("fn main() { var a; var a; }"),
+            "line 1: `a` is already declared"
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use super::lexer::{tokenize, Token};
+use super::CompileError;
+
+/// Binary operators by precedence, loosest first.
+const LEVELS: [&[&str]; 5] = [
+    &["||"],
+    &["&&"],
+    &["==", "!=", "<", ">", "<=", ">="],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+///Expression
+/// Every value is an unsigned 32 bit word. Arrays are identifiers of
+/// mapped segments.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Expr {
+    Number(u32),
+    Var(String),
+    Index(Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+///Statement, with the line it starts on
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub line: usize,
+}
+
+///Kinds of statement
+/// # Variants:
+/// * `Var`: declares a variable, zero unless initialized.
+/// * `Assign`: assigns a variable.
+/// * `Store`: assigns an array element, `array[index] = value`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum StmtKind {
+    Var(String, Option<Expr>),
+    Assign(String, Expr),
+    Store(Expr, Expr, Expr),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    While(Expr, Vec<Stmt>),
+    Return(Option<Expr>),
+    Expr(Expr),
+}
+
+///Function definition
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<Stmt>,
+    pub line: usize,
+}
+
+///Whole program: global variables and functions, in source order
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Program {
+    pub globals: Vec<Stmt>,
+    pub functions: Vec<Function>,
+}
+
+///Parses source into a program
+pub fn parse(source: &str) -> Result<Program, CompileError> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+    };
+    let mut program = Program::default();
+    while parser.peek() != &Token::Eof {
+        if parser.eat_keyword("fn") {
+            program.functions.push(parser.function()?);
+        } else if parser.peek() == &Token::Ident("var".to_string()) {
+            program.globals.push(parser.statement()?);
+        } else {
+            return Err(parser.error("expected `fn` or `var`"));
+        }
+    }
+    Ok(program)
+}
+
+/// Recursive descent over the tokens.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position].0
+    }
+    fn line(&self) -> usize {
+        self.tokens[self.position].1
+    }
+    fn error(&self, expected: &str) -> CompileError {
+        let found = match self.peek() {
+            Token::Number(n) => n.to_string(),
+            Token::Ident(name) => name.clone(),
+            Token::Punct(p) => p.to_string(),
+            Token::Eof => "end of input".to_string(),
+        };
+        CompileError::new(self.line(), format!("{}, found `{}`", expected, found))
+    }
+    fn eat(&mut self, punct: &str) -> bool {
+        if matches!(self.peek(), Token::Punct(p) if *p == punct) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn expect(&mut self, punct: &str) -> Result<(), CompileError> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", punct)))
+        }
+    }
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Token::Ident(name) if name == keyword) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn ident(&mut self) -> Result<String, CompileError> {
+        match self.peek() {
+            Token::Ident(name) if !is_keyword(name) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(self.error("expected a name")),
+        }
+    }
+
+    fn function(&mut self) -> Result<Function, CompileError> {
+        let line = self.line();
+        let name = self.ident()?;
+        self.expect("(")?;
+        let mut params = vec![];
+        if !self.eat(")") {
+            loop {
+                params.push(self.ident()?);
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        let body = self.block()?;
+        Ok(Function {
+            name,
+            params,
+            body,
+            line,
+        })
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, CompileError> {
+        self.expect("{")?;
+        let mut body = vec![];
+        while !self.eat("}") {
+            body.push(self.statement()?);
+        }
+        Ok(body)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, CompileError> {
+        let line = self.line();
+        let kind = if self.eat_keyword("var") {
+            let name = self.ident()?;
+            let init = if self.eat("=") {
+                Some(self.expression()?)
+            } else {
+                None
+            };
+            self.expect(";")?;
+            StmtKind::Var(name, init)
+        } else if self.eat_keyword("if") {
+            let condition = self.condition()?;
+            let then = self.block()?;
+            let otherwise = if !self.eat_keyword("else") {
+                vec![]
+            } else if matches!(self.peek(), Token::Ident(name) if name == "if") {
+                vec![self.statement()?]
+            } else {
+                self.block()?
+            };
+            StmtKind::If(condition, then, otherwise)
+        } else if self.eat_keyword("while") {
+            let condition = self.condition()?;
+            StmtKind::While(condition, self.block()?)
+        } else if self.eat_keyword("return") {
+            let value = if self.eat(";") {
+                None
+            } else {
+                let value = self.expression()?;
+                self.expect(";")?;
+                Some(value)
+            };
+            StmtKind::Return(value)
+        } else {
+            let target = self.expression()?;
+            let kind = if self.eat("=") {
+                let value = self.expression()?;
+                match target {
+                    Expr::Var(name) => StmtKind::Assign(name, value),
+                    Expr::Index(array, index) => StmtKind::Store(*array, *index, value),
+                    _ => return Err(CompileError::new(line, "cannot assign to this")),
+                }
+            } else {
+                StmtKind::Expr(target)
+            };
+            self.expect(";")?;
+            kind
+        };
+        Ok(Stmt { kind, line })
+    }
+
+    fn condition(&mut self) -> Result<Expr, CompileError> {
+        self.expect("(")?;
+        let condition = self.expression()?;
+        self.expect(")")?;
+        Ok(condition)
+    }
+
+    fn expression(&mut self) -> Result<Expr, CompileError> {
+        self.binary(0)
+    }
+
+    /// Parses operators of precedence `level` and tighter, left to right.
+    fn binary(&mut self, level: usize) -> Result<Expr, CompileError> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(&op) = LEVELS[level]
+            .iter()
+            .find(|&&op| matches!(self.peek(), Token::Punct(p) if *p == op))
+        {
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, CompileError> {
+        for op in ["-", "!"] {
+            if self.eat(op) {
+                return Ok(Expr::Unary(op, Box::new(self.unary()?)));
+            }
+        }
+        let mut expr = self.primary()?;
+        while self.eat("[") {
+            let index = self.expression()?;
+            self.expect("]")?;
+            expr = Expr::Index(Box::new(expr), Box::new(index));
+        }
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Result<Expr, CompileError> {
+        if let Token::Number(n) = *self.peek() {
+            self.position += 1;
+            return Ok(Expr::Number(n));
+        }
+        if self.eat("(") {
+            let expr = self.expression()?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        let name = self
+            .ident()
+            .map_err(|_| self.error("expected an expression"))?;
+        if !self.eat("(") {
+            return Ok(Expr::Var(name));
+        }
+        let mut args = vec![];
+        if !self.eat(")") {
+            loop {
+                args.push(self.expression()?);
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        Ok(Expr::Call(name, args))
+    }
+}
+
+fn is_keyword(name: &str) -> bool {
+    matches!(name, "fn" | "var" | "if" | "else" | "while" | "return")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn respects_precedence() {
+        let program = parse("fn main() { x = 1 + 2 * 3 < 4 && !a[5]; }").unwrap();
+        let n = |v| Box::new(Expr::Number(v));
+        assert_eq!(
+            program.functions[0].body[0].kind,
+            StmtKind::Assign(
+                "x".to_string(),
+                Expr::Binary(
+                    "&&",
+                    Box::new(Expr::Binary(
+                        "<",
+                        Box::new(Expr::Binary(
+                            "+",
+                            n(1),
+                            Box::new(Expr::Binary("*", n(2), n(3)))
+                        )),
+                        n(4)
+                    )),
+                    Box::new(Expr::Unary(
+                        "!",
+                        Box::new(Expr::Index(Box::new(Expr::Var("a".to_string())), n(5)))
+                    ))
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn reports_line_of_error() {
+        let error = parse("fn main() {\n  var x = ;\n}").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.message, "expected an expression, found `;`");
+    }
+}
@@ -0,0 +1,235 @@
+use crate::disasm::decode;
+use crate::segment::Segment;
+use crate::symbols::Symbols;
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+
+///Instructions executed from one version of $m[0]
+/// # Parameters:
+/// * `program`: A copy of $m[0] as it was when the generation started,
+///   shared with the generation before if that ran the same words.
+/// * `hits`: How many times each address ran, as far as the highest address run.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Generation {
+    pub program: Arc<Segment>,
+    pub hits: Vec<u64>,
+}
+impl Generation {
+    ///Runs of addresses in the program that never ran, as inclusive ranges
+    pub fn never_executed(&self) -> Vec<(usize, usize)> {
+        let mut ranges: Vec<(usize, usize)> = vec![];
+        for address in 0..self.program.len() {
+            if self.hits.get(address).copied().unwrap_or(0) != 0 {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == address => *end = address,
+                _ => ranges.push((address, address)),
+            }
+        }
+        ranges
+    }
+}
+
+///Coverage of a guest program
+/// A new generation starts each time Load Program replaces $m[0].
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Coverage {
+    pub generations: Vec<Generation>,
+}
+impl Coverage {
+    ///Starts a new generation running `program`
+    /// The words are copied rather than shared with the machine, so
+    /// recording never holds on to storage the guest has released.
+    pub fn start(&mut self, program: &Segment) {
+        let program = match self.generations.last() {
+            Some(last) if *last.program == *program => Arc::clone(&last.program),
+            _ => Arc::new(program.clone()),
+        };
+        self.generations.push(Generation {
+            program,
+            hits: vec![],
+        });
+    }
+    ///Counts one execution of `address` in the current generation
+    #[inline]
+    pub fn hit(&mut self, address: usize) {
+        let hits = &mut self.generations.last_mut().unwrap().hits;
+        if address >= hits.len() {
+            hits.resize(address + 1, 0);
+        }
+        hits[address] += 1;
+    }
+
+    ///Writes the coverage in the `.cov` text format
+    /// A header line, then for each generation a `generation <n> <words>`
+    /// line followed by one `<address> <word> <hits>` line per word.
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "rum coverage 1")?;
+        for (n, generation) in self.generations.iter().enumerate() {
+            writeln!(out, "generation {} {}", n, generation.program.len())?;
+            for address in 0..generation.program.len() {
+                let hits = generation.hits.get(address).copied().unwrap_or(0);
+                writeln!(out, "{} {} {}", address, generation.program[address], hits)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Reads coverage written by `write`
+    pub fn read(input: &mut dyn BufRead) -> io::Result<Coverage> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad coverage line: {}", line),
+            )
+        };
+        let mut lines = input.lines();
+        match lines.next() {
+            Some(Ok(header)) if header == "rum coverage 1" => {}
+            _ => return Err(invalid("missing header")),
+        }
+        let mut coverage = Coverage::default();
+        let mut words = vec![];
+        for line in lines {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                ["generation", _, _] => {
+                    coverage.finish(&mut words);
+                    coverage.start(&Segment::Dense(vec![]));
+                }
+                [_, word, hits] if !coverage.generations.is_empty() => {
+                    let word = word.parse().map_err(|_| invalid(&line))?;
+                    let hits = hits.parse().map_err(|_| invalid(&line))?;
+                    words.push(word);
+                    coverage.generations.last_mut().unwrap().hits.push(hits);
+                }
+                _ => return Err(invalid(&line)),
+            }
+        }
+        coverage.finish(&mut words);
+        Ok(coverage)
+    }
+    /// Moves the words read so far into the current generation.
+    fn finish(&mut self, words: &mut Vec<u32>) {
+        if let Some(generation) = self.generations.last_mut() {
+            generation.program = Arc::new(Segment::Dense(std::mem::take(words)));
+        }
+    }
+
+    ///Prints an annotated disassembly with hit counts, and the ranges never executed
+    /// `symbols`, if given, describe the first generation, which is the
+    /// program as loaded.
+    pub fn report(&self, out: &mut dyn Write, symbols: Option<&Symbols>) -> io::Result<()> {
+        for (n, generation) in self.generations.iter().enumerate() {
+            let len = generation.program.len();
+            let executed = generation.hits.iter().filter(|&&h| h != 0).count();
+            writeln!(
+                out,
+                "generation {}: {} of {} words executed ({:.1}%)",
+                n,
+                executed,
+                len,
+                if len == 0 {
+                    0.0
+                } else {
+                    100.0 * executed as f64 / len as f64
+                }
+            )?;
+            for address in 0..len {
+                let word = generation.program[address];
+                match generation.hits.get(address).copied().unwrap_or(0) {
+                    0 => write!(out, "{:>12}", "-")?,
+                    hits => write!(out, "{:>12}", hits)?,
+                }
+                write!(out, "  {:>8}  {:08x}  {}", address, word, decode(word))?;
+                match symbols
+                    .filter(|_| n == 0)
+                    .and_then(|s| s.lookup(address as u32))
+                {
+                    Some(symbol) => writeln!(out, "  ; {}", symbol)?,
+                    None => writeln!(out)?,
+                }
+            }
+            let ranges: Vec<String> = generation
+                .never_executed()
+                .iter()
+                .map(|&(start, end)| {
+                    if start == end {
+                        start.to_string()
+                    } else {
+                        format!("{}-{}", start, end)
+                    }
+                })
+                .collect();
+            if !ranges.is_empty() {
+                writeln!(out, "never executed: {}", ranges.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::{encode, Instruction};
+    fn sample() -> Coverage {
+        let mut coverage = Coverage::default();
+        coverage.start(&Segment::Dense(vec![
+            encode(Instruction::LoadValue { a: 1, value: 72 }),
+            encode(Instruction::Output { c: 1 }),
+            0,
+            0,
+            encode(Instruction::Halt),
+        ]));
+        coverage.hit(0);
+        coverage.hit(1);
+        coverage.hit(1);
+        coverage.hit(4);
+        coverage
+    }
+
+    #[test]
+    fn finds_never_executed_ranges() {
+        assert_eq!(sample().generations[0].never_executed(), vec![(2, 3)]);
+    }
+
+    #[test]
+    fn round_trips_through_text() {
+        let coverage = sample();
+        let mut text = vec![];
+        coverage.write(&mut text).unwrap();
+        let read = Coverage::read(&mut &text[..]).unwrap();
+        assert_eq!(read.generations[0].program, coverage.generations[0].program);
+        assert_eq!(read.generations[0].hits, vec![1, 2, 0, 0, 1]);
+    }
+
+    #[test]
+    fn reports_hits_and_gaps() {
+        let mut text = vec![];
+        sample().report(&mut text, None).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("generation 0: 3 of 5 words executed (60.0%)"));
+        assert!(text.contains("           2         1  a0000001  out r1"));
+        assert!(text.contains("never executed: 2-3"));
+    }
+
+    #[test]
+    fn reports_symbols() {
+        let symbols = Symbols {
+            labels: vec![crate::symbols::Label {
+                start: 1,
+                end: 5,
+                name: "greet".to_string(),
+            }],
+            lines: vec![],
+        };
+        let mut text = vec![];
+        sample().report(&mut text, Some(&symbols)).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("  a0000001  out r1  ; greet\n"));
+        assert!(text.contains("  d2000048  loadv r1, 72\n"));
+    }
+}
@@ -0,0 +1,139 @@
+use crate::machine::{get, put, Opcode, OP, RA, RB, RC, RL, VL};
+use std::fmt;
+
+///Decoded instruction
+/// Register operands are register numbers, `value` is the 25 bit
+/// immediate of Load Value. Words with opcodes 14 and 15 decode
+/// as `Invalid`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Instruction {
+    CMov { a: usize, b: usize, c: usize },
+    Load { a: usize, b: usize, c: usize },
+    Store { a: usize, b: usize, c: usize },
+    Add { a: usize, b: usize, c: usize },
+    Mul { a: usize, b: usize, c: usize },
+    Div { a: usize, b: usize, c: usize },
+    Nand { a: usize, b: usize, c: usize },
+    Halt,
+    MapSegment { b: usize, c: usize },
+    UnmapSegment { c: usize },
+    Output { c: usize },
+    Input { c: usize },
+    LoadProgram { b: usize, c: usize },
+    LoadValue { a: usize, value: u32 },
+    Invalid { opcode: u32 },
+}
+
+///Decodes one instruction word
+pub fn decode(word: u32) -> Instruction {
+    let a = get(&RA, word) as usize;
+    let b = get(&RB, word) as usize;
+    let c = get(&RC, word) as usize;
+    match get(&OP, word) {
+        o if o == Opcode::CMov as u32 => Instruction::CMov { a, b, c },
+        o if o == Opcode::Load as u32 => Instruction::Load { a, b, c },
+        o if o == Opcode::Store as u32 => Instruction::Store { a, b, c },
+        o if o == Opcode::Add as u32 => Instruction::Add { a, b, c },
+        o if o == Opcode::Mul as u32 => Instruction::Mul { a, b, c },
+        o if o == Opcode::Div as u32 => Instruction::Div { a, b, c },
+        o if o == Opcode::Nand as u32 => Instruction::Nand { a, b, c },
+        o if o == Opcode::Halt as u32 => Instruction::Halt,
+        o if o == Opcode::MapSegment as u32 => Instruction::MapSegment { b, c },
+        o if o == Opcode::UnmapSegment as u32 => Instruction::UnmapSegment { c },
+        o if o == Opcode::Output as u32 => Instruction::Output { c },
+        o if o == Opcode::Input as u32 => Instruction::Input { c },
+        o if o == Opcode::LoadProgram as u32 => Instruction::LoadProgram { b, c },
+        o if o == Opcode::LoadValue as u32 => Instruction::LoadValue {
+            a: get(&RL, word) as usize,
+            value: get(&VL, word),
+        },
+        opcode => Instruction::Invalid { opcode },
+    }
+}
+
+///Encodes an instruction as a word, the inverse of `decode`
+pub fn encode(instruction: Instruction) -> u32 {
+    let three = |op: Opcode, a: usize, b: usize, c: usize| {
+        put(&OP, op as u32) | put(&RA, a as u32) | put(&RB, b as u32) | put(&RC, c as u32)
+    };
+    match instruction {
+        Instruction::CMov { a, b, c } => three(Opcode::CMov, a, b, c),
+        Instruction::Load { a, b, c } => three(Opcode::Load, a, b, c),
+        Instruction::Store { a, b, c } => three(Opcode::Store, a, b, c),
+        Instruction::Add { a, b, c } => three(Opcode::Add, a, b, c),
+        Instruction::Mul { a, b, c } => three(Opcode::Mul, a, b, c),
+        Instruction::Div { a, b, c } => three(Opcode::Div, a, b, c),
+        Instruction::Nand { a, b, c } => three(Opcode::Nand, a, b, c),
+        Instruction::Halt => three(Opcode::Halt, 0, 0, 0),
+        Instruction::MapSegment { b, c } => three(Opcode::MapSegment, 0, b, c),
+        Instruction::UnmapSegment { c } => three(Opcode::UnmapSegment, 0, 0, c),
+        Instruction::Output { c } => three(Opcode::Output, 0, 0, c),
+        Instruction::Input { c } => three(Opcode::Input, 0, 0, c),
+        Instruction::LoadProgram { b, c } => three(Opcode::LoadProgram, 0, b, c),
+        Instruction::LoadValue { a, value } => {
+            put(&OP, Opcode::LoadValue as u32) | put(&RL, a as u32) | put(&VL, value)
+        }
+        Instruction::Invalid { opcode } => put(&OP, opcode),
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::CMov { a, b, c } => write!(f, "cmov r{}, r{}, r{}", a, b, c),
+            Instruction::Load { a, b, c } => write!(f, "load r{}, r{}, r{}", a, b, c),
+            Instruction::Store { a, b, c } => write!(f, "store r{}, r{}, r{}", a, b, c),
+            Instruction::Add { a, b, c } => write!(f, "add r{}, r{}, r{}", a, b, c),
+            Instruction::Mul { a, b, c } => write!(f, "mul r{}, r{}, r{}", a, b, c),
+            Instruction::Div { a, b, c } => write!(f, "div r{}, r{}, r{}", a, b, c),
+            Instruction::Nand { a, b, c } => write!(f, "nand r{}, r{}, r{}", a, b, c),
+            Instruction::Halt => write!(f, "halt"),
+            Instruction::MapSegment { b, c } => write!(f, "map r{}, r{}", b, c),
+            Instruction::UnmapSegment { c } => write!(f, "unmap r{}", c),
+            Instruction::Output { c } => write!(f, "out r{}", c),
+            Instruction::Input { c } => write!(f, "in r{}", c),
+            Instruction::LoadProgram { b, c } => write!(f, "loadp r{}, r{}", b, c),
+            Instruction::LoadValue { a, value } => write!(f, "loadv r{}, {}", a, value),
+            Instruction::Invalid { opcode } => write!(f, "invalid opcode {}", opcode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn decodes_every_format() {
+        assert_eq!(
+            decode((3 << 28) | (1 << 6) | (2 << 3) | 3),
+            Instruction::Add { a: 1, b: 2, c: 3 }
+        );
+        assert_eq!(
+            decode((13 << 28) | (5 << 25) | 72),
+            Instruction::LoadValue { a: 5, value: 72 }
+        );
+        assert_eq!(decode(2684354561), Instruction::Output { c: 1 });
+        assert_eq!(decode(14 << 28), Instruction::Invalid { opcode: 14 });
+    }
+
+    #[test]
+    fn disassembles() {
+        assert_eq!(decode(3523215432).to_string(), "loadv r1, 72");
+        assert_eq!(decode((12 << 28) | 5).to_string(), "loadp r0, r5");
+        assert_eq!(decode(7 << 28).to_string(), "halt");
+    }
+
+    #[test]
+    fn encodes_what_it_decodes() {
+        for word in [
+            3523215432,
+            2684354561,
+            (12 << 28) | 5,
+            (6 << 28) | (7 << 6) | (3 << 3) | 1,
+            7 << 28,
+            15 << 28,
+        ] {
+            assert_eq!(encode(decode(word)), word);
+        }
+    }
+}
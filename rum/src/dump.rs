@@ -0,0 +1,319 @@
+use crate::disasm::decode;
+use crate::json;
+use crate::machine::{MachineError, VirtualMachine};
+use std::io::{self, Read, Write};
+
+/// Instructions listed in a dump when the caller has no preference.
+pub const DEFAULT_TRAIL: usize = 64;
+/// First bytes of the binary form.
+const MAGIC: &[u8; 4] = b"UMDP";
+const VERSION: u32 = 2;
+
+/// Stored words of a segment, as runs of offset and words.
+pub type Runs = Vec<(u32, Vec<u32>)>;
+
+///State of a machine that faulted
+/// # Parameters:
+/// * `error`: Description of the fault.
+/// * `pc`: Address of the instruction that failed.
+/// * `word`: That instruction, unless `pc` is past the end of $m[0].
+/// * `registers`: The eight registers.
+/// * `segments`: Identifier and size in words of each mapped segment, by identifier.
+/// * `trail`: Address and word of the last instructions executed, oldest first.
+/// * `contents`: Identifier of each mapped segment with its stored words as
+///   runs of offset and words, if asked for. Words outside the runs of a
+///   lazily mapped segment were never written and read as zero.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Dump {
+    pub error: String,
+    pub pc: u32,
+    pub word: Option<u32>,
+    pub registers: Vec<u32>,
+    pub segments: Vec<(u32, usize)>,
+    pub trail: Vec<(u32, u32)>,
+    pub contents: Vec<(u32, Runs)>,
+}
+impl Dump {
+    ///Captures the machine as it was left by `error`
+    /// The trail is what `vm.trail()` kept. Segment contents are included
+    /// only if `contents` is set, since they can be large.
+    pub fn capture(vm: &VirtualMachine, error: &MachineError, contents: bool) -> Dump {
+        let pc = error.pc();
+        let mut segments: Vec<(u32, usize)> = vm
+            .memory
+            .iter()
+            .map(|(&id, segment)| (id, segment.len()))
+            .collect();
+        segments.sort_unstable();
+        let contents = match contents {
+            true => segments
+                .iter()
+                .map(|&(id, _)| {
+                    let runs = vm.memory[&id]
+                        .runs()
+                        .into_iter()
+                        .map(|(offset, words)| (offset as u32, words.to_vec()))
+                        .collect();
+                    (id, runs)
+                })
+                .collect(),
+            false => vec![],
+        };
+        Dump {
+            error: error.to_string(),
+            pc,
+            word: vm
+                .memory
+                .get(&0)
+                .and_then(|program| program.get(pc as usize)),
+            registers: vm.registers.to_vec(),
+            segments,
+            trail: vm.trail(),
+            contents,
+        }
+    }
+
+    ///Writes the dump as a JSON object
+    /// Instruction words come with their disassembly.
+    pub fn write_json(&self, out: &mut dyn Write) -> io::Result<()> {
+        let instruction = |pc: u32, word: u32| {
+            format!(
+                "{{\"pc\": {}, \"word\": {}, \"disassembly\": {}}}",
+                pc,
+                word,
+                json::string(&decode(word).to_string())
+            )
+        };
+        let list = |items: Vec<String>, indent: &str| match items.is_empty() {
+            true => "[]".to_string(),
+            false => format!(
+                "[\n{}{}\n{}]",
+                indent,
+                items.join(&format!(",\n{}", indent)),
+                &indent[2..]
+            ),
+        };
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"error\": {},", json::string(&self.error))?;
+        match self.word {
+            Some(word) => writeln!(out, "  \"fault\": {},", instruction(self.pc, word))?,
+            None => writeln!(out, "  \"fault\": {{\"pc\": {}}},", self.pc)?,
+        }
+        let registers: Vec<String> = self.registers.iter().map(u32::to_string).collect();
+        writeln!(out, "  \"registers\": [{}],", registers.join(", "))?;
+        let segments = self
+            .segments
+            .iter()
+            .map(|(id, words)| format!("{{\"id\": {}, \"words\": {}}}", id, words))
+            .collect();
+        writeln!(out, "  \"segments\": {},", list(segments, "    "))?;
+        let trail = self
+            .trail
+            .iter()
+            .map(|&(pc, word)| instruction(pc, word))
+            .collect();
+        write!(out, "  \"trail\": {}", list(trail, "    "))?;
+        if !self.contents.is_empty() {
+            let contents = self
+                .contents
+                .iter()
+                .map(|(id, runs)| {
+                    let runs: Vec<String> = runs
+                        .iter()
+                        .map(|(offset, words)| {
+                            let words: Vec<String> = words.iter().map(u32::to_string).collect();
+                            format!(
+                                "{{\"offset\": {}, \"words\": [{}]}}",
+                                offset,
+                                words.join(", ")
+                            )
+                        })
+                        .collect();
+                    format!("{{\"id\": {}, \"runs\": [{}]}}", id, runs.join(", "))
+                })
+                .collect();
+            write!(out, ",\n  \"contents\": {}", list(contents, "    "))?;
+        }
+        writeln!(out, "\n}}")
+    }
+
+    ///Writes the dump in a compact binary form
+    /// Big-endian 32 bit words: `UMDP`, the version, the pc, 1 and the
+    /// faulting word or 0 and 0, the eight registers, the length of the
+    /// error in bytes and its UTF-8 padded with zeros to a whole word,
+    /// then counted lists of segments as id and size, of the trail as pc
+    /// and word, and of contents as id and a counted list of runs, each
+    /// as offset, size and words.
+    pub fn write_binary(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut words = vec![VERSION, self.pc];
+        match self.word {
+            Some(word) => words.extend([1, word]),
+            None => words.extend([0, 0]),
+        }
+        words.extend(&self.registers);
+        words.push(self.error.len() as u32);
+        let mut error = self.error.as_bytes().to_vec();
+        error.resize(self.error.len().div_ceil(4) * 4, 0);
+        words.extend(
+            error
+                .chunks(4)
+                .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]])),
+        );
+        words.push(self.segments.len() as u32);
+        for &(id, len) in &self.segments {
+            words.extend([id, len as u32]);
+        }
+        words.push(self.trail.len() as u32);
+        for &(pc, word) in &self.trail {
+            words.extend([pc, word]);
+        }
+        words.push(self.contents.len() as u32);
+        for (id, runs) in &self.contents {
+            words.extend([*id, runs.len() as u32]);
+            for (offset, run) in runs {
+                words.extend([*offset, run.len() as u32]);
+                words.extend(run);
+            }
+        }
+        out.write_all(MAGIC)?;
+        for word in words {
+            out.write_all(&word.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    ///Reads a dump written by `write_binary`
+    pub fn read_binary(input: &mut dyn Read) -> io::Result<Dump> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes)?;
+        if !bytes.starts_with(MAGIC) || bytes.len() % 4 != 0 {
+            return Err(invalid("not a machine dump"));
+        }
+        let mut words = bytes[4..]
+            .chunks(4)
+            .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]));
+        let mut next = || {
+            words
+                .next()
+                .ok_or_else(|| invalid("truncated machine dump"))
+        };
+        if next()? != VERSION {
+            return Err(invalid("unknown machine dump version"));
+        }
+        let mut dump = Dump {
+            pc: next()?,
+            ..Dump::default()
+        };
+        let (present, word) = (next()?, next()?);
+        dump.word = (present == 1).then_some(word);
+        for _ in 0..8 {
+            dump.registers.push(next()?);
+        }
+        let length = next()? as usize;
+        let mut error = vec![];
+        for _ in 0..length.div_ceil(4) {
+            error.extend(next()?.to_be_bytes());
+        }
+        error.truncate(length);
+        dump.error = String::from_utf8(error).map_err(|_| invalid("error is not UTF-8"))?;
+        for _ in 0..next()? {
+            dump.segments.push((next()?, next()? as usize));
+        }
+        for _ in 0..next()? {
+            dump.trail.push((next()?, next()?));
+        }
+        for _ in 0..next()? {
+            let id = next()?;
+            let mut runs = vec![];
+            for _ in 0..next()? {
+                let (offset, len) = (next()?, next()?);
+                let run = (0..len).map(|_| next()).collect::<io::Result<_>>()?;
+                runs.push((offset, run));
+            }
+            dump.contents.push((id, runs));
+        }
+        Ok(dump)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::disasm::{encode, Instruction};
+    use crate::segment::PAGE_WORDS;
+
+    const FAULT: Instruction = Instruction::Load { a: 4, b: 2, c: 3 };
+
+    /// Maps a 3 word segment, then reads past its end.
+    fn faulted(contents: bool) -> Dump {
+        let mut asm = Assembler::new();
+        asm.loadv(1, 3);
+        asm.map(2, 1);
+        asm.loadv(3, 5);
+        asm.emit(FAULT);
+        asm.halt();
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(asm.finish());
+        vm.enable_trail(2);
+        let error = vm.run_program().unwrap_err();
+        Dump::capture(&vm, &error, contents)
+    }
+
+    #[test]
+    fn captures_fault() {
+        let dump = faulted(false);
+        assert_eq!(dump.pc, 3);
+        assert_eq!(dump.word, Some(encode(FAULT)));
+        assert_eq!(dump.registers, vec![0, 3, 1, 5, 0, 0, 0, 0]);
+        assert_eq!(dump.segments, vec![(0, 5), (1, 3)]);
+        let loadv = encode(Instruction::LoadValue { a: 3, value: 5 });
+        assert_eq!(dump.trail, vec![(2, loadv), (3, encode(FAULT))]);
+        assert!(dump.contents.is_empty());
+        let mut json = vec![];
+        dump.write_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("{\n  \"error\": \"instruction at pc 3 used word 5"));
+        assert!(json.contains(
+            "\"fault\": {\"pc\": 3, \"word\": 268435731, \"disassembly\": \"load r4, r2, r3\"}"
+        ));
+        assert!(json.contains("\"segments\": [\n    {\"id\": 0, \"words\": 5},\n"));
+        assert!(!json.contains("contents"));
+    }
+
+    #[test]
+    fn round_trips_binary() {
+        let dump = faulted(true);
+        assert_eq!(dump.contents[1], (1, vec![(0, vec![0, 0, 0])]));
+        let mut bytes = vec![];
+        dump.write_binary(&mut bytes).unwrap();
+        assert_eq!(Dump::read_binary(&mut &bytes[..]).unwrap(), dump);
+        assert!(Dump::read_binary(&mut &bytes[..bytes.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn captures_only_stored_words_of_lazy_segments() {
+        let mut asm = Assembler::new();
+        asm.nand(1, 0, 0);
+        asm.map(2, 1);
+        asm.loadv(3, 7);
+        asm.store(2, 3, 3);
+        asm.div(4, 3, 0);
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(asm.finish());
+        let error = vm.run_program().unwrap_err();
+        let dump = Dump::capture(&vm, &error, true);
+        assert_eq!(dump.segments[1], (1, u32::MAX as usize));
+        let (offset, words) = &dump.contents[1].1[0];
+        assert_eq!((dump.contents[1].1.len(), *offset), (1, 0));
+        assert_eq!((words.len(), words[7]), (PAGE_WORDS, 7));
+        let mut json = vec![];
+        dump.write_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains("{\"id\": 1, \"runs\": [{\"offset\": 0, \"words\": [0, 0,"));
+        let mut bytes = vec![];
+        dump.write_binary(&mut bytes).unwrap();
+        assert_eq!(Dump::read_binary(&mut &bytes[..]).unwrap(), dump);
+    }
+}
@@ -0,0 +1,514 @@
+use crate::io::IoDevice;
+use crate::machine::{MachineError, VirtualMachine, REGISTERS};
+use crate::predecoded::Predecoded;
+use crate::reference::Reference;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+///Implementation of the Universal Machine a program can be run on
+/// `step` runs one instruction as `VirtualMachine::step` does: it returns
+/// false if the instruction was Halt, and on a fault leaves the machine as
+/// it was before the instruction. `program_version` counts writes to $m[0]
+/// and replacements of it, so it is 0 while $m[0] is the program the
+/// engine was created with.
+pub trait Engine: Send {
+    fn step(&mut self) -> Result<bool, MachineError>;
+    fn registers(&self) -> [u32; REGISTERS];
+    fn pc(&self) -> u32;
+    fn program_version(&self) -> u64;
+    fn set_io(&mut self, io: Box<dyn IoDevice>);
+
+    ///Runs the program until it halts or faults
+    fn run(&mut self) -> Result<(), MachineError> {
+        while self.step()? {}
+        Ok(())
+    }
+}
+impl Engine for VirtualMachine {
+    fn step(&mut self) -> Result<bool, MachineError> {
+        VirtualMachine::step(self)
+    }
+
+    fn registers(&self) -> [u32; REGISTERS] {
+        self.registers
+    }
+
+    fn pc(&self) -> u32 {
+        self.program_counter as u32
+    }
+
+    fn program_version(&self) -> u64 {
+        VirtualMachine::program_version(self)
+    }
+
+    fn set_io(&mut self, io: Box<dyn IoDevice>) {
+        VirtualMachine::set_io(self, io);
+    }
+
+    fn run(&mut self) -> Result<(), MachineError> {
+        self.run_program()
+    }
+}
+
+///Engines to choose from
+/// # Variants:
+/// * `Interp`: `VirtualMachine`, the interpreter the rest of the crate is built on.
+/// * `Predecoded`: `Predecoded`, which decodes the program ahead of time.
+/// * `Reference`: `Reference`, a plain reading of the specification.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Kind {
+    Interp,
+    Predecoded,
+    Reference,
+}
+impl Kind {
+    pub const ALL: [Kind; 3] = [Kind::Interp, Kind::Predecoded, Kind::Reference];
+
+    ///Name of the engine on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Interp => "interp",
+            Kind::Predecoded => "predecoded",
+            Kind::Reference => "reference",
+        }
+    }
+
+    ///Engine called `name`, if there is one
+    pub fn parse(name: &str) -> Option<Kind> {
+        Kind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    ///Creates an engine of this kind about to run `program` on standard input and output
+    /// The interpreter counts only the pages of large segments that have
+    /// been written against `memory_limit`, and the others every word mapped.
+    pub fn create(self, program: Vec<u32>, memory_limit: Option<usize>) -> Box<dyn Engine> {
+        match self {
+            Kind::Interp => {
+                let mut vm = VirtualMachine::new();
+                vm.set_memory_limit(memory_limit);
+                vm.initialize_machine(program);
+                Box::new(vm)
+            }
+            Kind::Predecoded => {
+                let mut engine = Predecoded::new(program);
+                engine.set_memory_limit(memory_limit);
+                Box::new(engine)
+            }
+            Kind::Reference => {
+                let mut engine = Reference::new(program);
+                engine.set_memory_limit(memory_limit);
+                Box::new(engine)
+            }
+        }
+    }
+}
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+///What two engines run in lockstep disagreed on
+/// Each variant holds what the first engine did, then what the second did.
+/// # Variants:
+/// * `Result`: one halted, faulted or ran on when the other did not.
+/// * `ProgramCounter`: they went on to different instructions.
+/// * `Registers`: they left different values in the registers.
+/// * `Output`: they output different bytes.
+/// * `Input`: they have read a different number of bytes.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Difference {
+    Result(Result<bool, MachineError>, Result<bool, MachineError>),
+    ProgramCounter(u32, u32),
+    Registers([u32; REGISTERS], [u32; REGISTERS]),
+    Output(Vec<u8>, Vec<u8>),
+    Input(usize, usize),
+}
+
+///Why a cross-check failed
+/// # Variants:
+/// * `Mismatch`: the engines disagreed after running the instruction at
+///   `pc`, which was instruction number `step` counting from 0.
+/// * `Machine`: both engines failed in the same way.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CrossCheckError {
+    Mismatch {
+        step: u64,
+        pc: u32,
+        difference: Difference,
+    },
+    Machine(MachineError),
+}
+impl fmt::Display for CrossCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let outcome = |result: &Result<bool, MachineError>| match result {
+            Ok(true) => "ran on".to_string(),
+            Ok(false) => "halted".to_string(),
+            Err(error) => format!("failed ({})", error),
+        };
+        match self {
+            CrossCheckError::Mismatch {
+                step,
+                pc,
+                difference,
+            } => {
+                write!(
+                    f,
+                    "engines disagree after step {} at pc {}: the first ",
+                    step, pc
+                )?;
+                match difference {
+                    Difference::Result(first, second) => {
+                        write!(f, "{}, the second {}", outcome(first), outcome(second))
+                    }
+                    Difference::ProgramCounter(first, second) => {
+                        write!(f, "went on to pc {}, the second to pc {}", first, second)
+                    }
+                    Difference::Registers(first, second) => {
+                        write!(f, "left registers {:?}, the second {:?}", first, second)
+                    }
+                    Difference::Output(first, second) => {
+                        write!(f, "output {:?}, the second {:?}", first, second)
+                    }
+                    Difference::Input(first, second) => {
+                        write!(f, "has read {} bytes, the second {}", first, second)
+                    }
+                }
+            }
+            CrossCheckError::Machine(error) => error.fmt(f),
+        }
+    }
+}
+impl Error for CrossCheckError {}
+
+/// I/O of two engines in lockstep. `input` is every byte the first engine
+/// read, which the second reads in turn, `read` counts the bytes each has
+/// read and `output` holds what each output during the current step.
+#[derive(Debug, Default)]
+struct Channel {
+    input: Vec<Option<u8>>,
+    read: [usize; 2],
+    output: [Vec<u8>; 2],
+}
+
+/// Device of engine `side` in a cross-check. The first engine's passes
+/// through to `inner`.
+struct Side {
+    channel: Arc<Mutex<Channel>>,
+    side: usize,
+    inner: Option<Box<dyn IoDevice>>,
+}
+impl IoDevice for Side {
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut channel = self.channel.lock().unwrap();
+        let position = channel.read[self.side];
+        channel.read[self.side] += 1;
+        match &mut self.inner {
+            Some(inner) => {
+                let byte = inner.read_byte();
+                channel.input.push(byte);
+                byte
+            }
+            None => channel.input.get(position).copied().flatten(),
+        }
+    }
+    fn write_byte(&mut self, byte: u8) {
+        if let Some(inner) = &mut self.inner {
+            inner.write_byte(byte);
+        }
+        self.channel.lock().unwrap().output[self.side].push(byte);
+    }
+}
+
+///Runs two engines in lockstep, stopping at the first instruction after which they disagree
+/// The first engine reads input from `io` and its output goes there, and
+/// the second is given the same input. After every instruction the
+/// engines must agree on how it went, the output, the bytes read, the
+/// registers and the program counter.
+pub fn cross_check(
+    first: &mut dyn Engine,
+    second: &mut dyn Engine,
+    io: Box<dyn IoDevice>,
+) -> Result<(), CrossCheckError> {
+    let channel = Arc::new(Mutex::new(Channel::default()));
+    first.set_io(Box::new(Side {
+        channel: Arc::clone(&channel),
+        side: 0,
+        inner: Some(io),
+    }));
+    second.set_io(Box::new(Side {
+        channel: Arc::clone(&channel),
+        side: 1,
+        inner: None,
+    }));
+    let mut step = 0;
+    loop {
+        let pc = first.pc();
+        let results = (first.step(), second.step());
+        let mut channel = channel.lock().unwrap();
+        let outputs = (
+            std::mem::take(&mut channel.output[0]),
+            std::mem::take(&mut channel.output[1]),
+        );
+        let registers = (first.registers(), second.registers());
+        let difference = if results.0 != results.1 {
+            Difference::Result(results.0.clone(), results.1.clone())
+        } else if outputs.0 != outputs.1 {
+            Difference::Output(outputs.0, outputs.1)
+        } else if channel.read[0] != channel.read[1] {
+            Difference::Input(channel.read[0], channel.read[1])
+        } else if registers.0 != registers.1 {
+            Difference::Registers(registers.0, registers.1)
+        } else if first.pc() != second.pc() {
+            Difference::ProgramCounter(first.pc(), second.pc())
+        } else {
+            match results.0 {
+                Ok(true) => {
+                    step += 1;
+                    continue;
+                }
+                Ok(false) => return Ok(()),
+                Err(error) => return Err(CrossCheckError::Machine(error)),
+            }
+        };
+        return Err(CrossCheckError::Mismatch {
+            step,
+            pc,
+            difference,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::compiler::compile;
+    use crate::disasm::{encode, Instruction};
+    use crate::io::Captured;
+    use crate::machine::DEFAULT_MEMORY_LIMIT;
+
+    /// Reads a line, then prints it back reversed with its length.
+    const REVERSE: &str = "
+        fn main() {
+            var line = array(64);
+            var n = 0;
+            var c = getc();
+            while (c != '\\n' && c != -1) { line[n] = c; n = n + 1; c = getc(); }
+            putc('0' + n);
+            while (n > 0) { n = n - 1; putc(line[n]); }
+        }
+    ";
+
+    /// Engine that reports a wrong register once past `pc`.
+    struct Skewed {
+        inner: Box<dyn Engine>,
+        pc: u32,
+    }
+    impl Engine for Skewed {
+        fn step(&mut self) -> Result<bool, MachineError> {
+            self.inner.step()
+        }
+        fn registers(&self) -> [u32; REGISTERS] {
+            let mut registers = self.inner.registers();
+            if self.inner.pc() > self.pc {
+                registers[7] ^= 1;
+            }
+            registers
+        }
+        fn pc(&self) -> u32 {
+            self.inner.pc()
+        }
+        fn program_version(&self) -> u64 {
+            self.inner.program_version()
+        }
+        fn set_io(&mut self, io: Box<dyn IoDevice>) {
+            self.inner.set_io(io);
+        }
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for kind in Kind::ALL {
+            assert_eq!(Kind::parse(kind.name()), Some(kind));
+        }
+        assert_eq!(Kind::parse("jit"), None);
+    }
+
+    #[test]
+    fn engines_agree() {
+        let program = compile(REVERSE).unwrap();
+        for first in Kind::ALL {
+            for second in Kind::ALL {
+                let io = Captured::new(b"lockstep\n");
+                let output = io.output();
+                let result = cross_check(
+                    first.create(program.clone(), None).as_mut(),
+                    second.create(program.clone(), None).as_mut(),
+                    Box::new(io),
+                );
+                assert_eq!(result, Ok(()), "{} against {}", first, second);
+                assert_eq!(&output.lock().unwrap()[..], b"8petskcol");
+            }
+        }
+    }
+
+    #[test]
+    fn engines_run_alone() {
+        let program = compile(REVERSE).unwrap();
+        for kind in Kind::ALL {
+            let mut engine = kind.create(program.clone(), None);
+            let io = Captured::new(b"abc");
+            let output = io.output();
+            engine.set_io(Box::new(io));
+            assert_eq!(engine.run(), Ok(()));
+            assert_eq!(&output.lock().unwrap()[..], b"3cba");
+        }
+    }
+
+    #[test]
+    fn engines_count_program_versions() {
+        // Overwrites its first word, then loads a one word program of Halt
+        let mut asm = Assembler::new();
+        asm.store(0, 0, 0);
+        asm.loadv(1, 1);
+        asm.map(2, 1);
+        asm.constant(3, encode(Instruction::Halt), 4);
+        asm.store(2, 0, 3);
+        asm.loadp(2, 0);
+        let program = asm.finish();
+        for kind in Kind::ALL {
+            let mut engine = kind.create(program.clone(), None);
+            assert_eq!(engine.program_version(), 0, "{}", kind);
+            assert_eq!(engine.run(), Ok(()));
+            assert_eq!(engine.program_version(), 2, "{}", kind);
+        }
+    }
+
+    #[test]
+    fn agrees_on_faults() {
+        let program = compile("fn main() { var a = array(2); putc(a[2]); }").unwrap();
+        let result = cross_check(
+            Kind::Reference.create(program.clone(), None).as_mut(),
+            Kind::Predecoded.create(program.clone(), None).as_mut(),
+            Box::new(Captured::new(&[])),
+        );
+        assert!(matches!(
+            result,
+            Err(CrossCheckError::Machine(MachineError::OutOfBounds {
+                index: 2,
+                ..
+            }))
+        ));
+        // Mapping more words than the limit faults instead of aborting
+        let huge = compile("fn main() { var a = array(0 - 1); }").unwrap();
+        let result = cross_check(
+            Kind::Reference
+                .create(huge.clone(), Some(DEFAULT_MEMORY_LIMIT))
+                .as_mut(),
+            Kind::Predecoded
+                .create(huge, Some(DEFAULT_MEMORY_LIMIT))
+                .as_mut(),
+            Box::new(Captured::new(&[])),
+        );
+        assert!(matches!(
+            result,
+            Err(CrossCheckError::Machine(
+                MachineError::AllocationLimit { .. }
+            ))
+        ));
+        // An engine with a limit disagrees with one without
+        let result = cross_check(
+            Kind::Interp.create(program.clone(), Some(2)).as_mut(),
+            Kind::Reference.create(program, None).as_mut(),
+            Box::new(Captured::new(&[])),
+        );
+        assert!(matches!(
+            result,
+            Err(CrossCheckError::Mismatch {
+                difference: Difference::Result(Err(MachineError::AllocationLimit { .. }), Ok(true)),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn stops_at_first_mismatch() {
+        let program = compile(REVERSE).unwrap();
+        let mut skewed = Skewed {
+            inner: Kind::Predecoded.create(program.clone(), None),
+            pc: 10,
+        };
+        let result = cross_check(
+            Kind::Interp.create(program.clone(), None).as_mut(),
+            &mut skewed,
+            Box::new(Captured::new(b"x\n")),
+        );
+        let error = result.unwrap_err();
+        let (step, pc) = match &error {
+            CrossCheckError::Mismatch {
+                step,
+                pc,
+                difference: Difference::Registers(first, second),
+            } => {
+                assert_eq!(first[7] ^ second[7], 1);
+                (*step, *pc)
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+        // The first instruction after which the skewed engine is past pc 10
+        let mut engine = Kind::Interp.create(program, None);
+        for _ in 0..step {
+            engine.step().unwrap();
+        }
+        assert_eq!(engine.pc(), pc);
+        engine.step().unwrap();
+        assert!(engine.pc() > 10);
+        assert!(error.to_string().starts_with(&format!(
+            "engines disagree after step {} at pc {}: the first left registers",
+            step, pc
+        )));
+    }
+
+    #[test]
+    fn notices_missing_output() {
+        /// Engine whose output goes nowhere.
+        struct Mute(Box<dyn Engine>);
+        impl Engine for Mute {
+            fn step(&mut self) -> Result<bool, MachineError> {
+                self.0.step()
+            }
+            fn registers(&self) -> [u32; REGISTERS] {
+                self.0.registers()
+            }
+            fn pc(&self) -> u32 {
+                self.0.pc()
+            }
+            fn program_version(&self) -> u64 {
+                self.0.program_version()
+            }
+            fn set_io(&mut self, _io: Box<dyn IoDevice>) {
+                self.0.set_io(Box::new(Captured::new(&[])));
+            }
+        }
+        let mut asm = Assembler::new();
+        asm.loadv(1, u32::from(b'A'));
+        asm.output(1);
+        asm.halt();
+        let program = asm.finish();
+        let result = cross_check(
+            Kind::Reference.create(program.clone(), None).as_mut(),
+            &mut Mute(Kind::Interp.create(program, None)),
+            Box::new(Captured::new(&[])),
+        );
+        assert_eq!(
+            result,
+            Err(CrossCheckError::Mismatch {
+                step: 1,
+                pc: 1,
+                difference: Difference::Output(vec![65], vec![]),
+            })
+        );
+    }
+}
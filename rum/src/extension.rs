@@ -0,0 +1,218 @@
+use crate::machine::{reg, RA, RB, RC, REGISTERS};
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Opcode of the host call instruction installed by `standard`.
+pub const HOST_CALL: u32 = 14;
+/// Host call service that reads the number of instructions executed.
+pub const SERVICE_CYCLES: u32 = 0;
+/// Host call service that prints the registers.
+pub const SERVICE_DEBUG_PRINT: u32 = 1;
+
+///What an extension instruction can see of the machine
+/// # Parameters:
+/// * `registers`: The eight registers, which the instruction may change.
+/// * `pc`: Address of the instruction.
+/// * `executed`: Instructions executed since the program was loaded, not
+///   counting this one.
+pub struct Context<'a> {
+    pub registers: &'a mut [u32; REGISTERS],
+    pub pc: u32,
+    pub executed: u64,
+}
+
+///Handler for an extension opcode
+/// Returning an error stops the machine. A handler that fails should
+/// leave the registers as they were.
+pub trait Extension: Send {
+    fn execute(&mut self, context: &mut Context, instruction: u32) -> Result<(), String>;
+}
+impl<F: FnMut(&mut Context, u32) -> Result<(), String> + Send> Extension for F {
+    fn execute(&mut self, context: &mut Context, instruction: u32) -> Result<(), String> {
+        self(context, instruction)
+    }
+}
+
+///Handlers installed for opcodes 14 and 15
+/// Opcodes without a handler do nothing, as in the standard UM.
+#[derive(Default)]
+pub struct Extensions {
+    handlers: [Option<Box<dyn Extension>>; 2],
+}
+impl Extensions {
+    ///Installs `handler` for `opcode`, returning the one it replaces
+    /// Panics unless `opcode` is 14 or 15.
+    pub fn install(
+        &mut self,
+        opcode: u32,
+        handler: Box<dyn Extension>,
+    ) -> Option<Box<dyn Extension>> {
+        self.slot(opcode).replace(handler)
+    }
+    ///Removes the handler for `opcode`, returning it
+    pub fn remove(&mut self, opcode: u32) -> Option<Box<dyn Extension>> {
+        self.slot(opcode).take()
+    }
+    /// Handler for `opcode`, if one is installed.
+    pub(crate) fn get(&mut self, opcode: u32) -> Option<&mut Box<dyn Extension>> {
+        self.slot(opcode).as_mut()
+    }
+    fn slot(&mut self, opcode: u32) -> &mut Option<Box<dyn Extension>> {
+        match opcode {
+            14 | 15 => &mut self.handlers[opcode as usize - 14],
+            _ => panic!("opcode {} is not an extension opcode", opcode),
+        }
+    }
+}
+
+///Host call instruction, running the service numbered by $r[A]
+/// Services read their arguments from and return results in the other
+/// registers.
+#[derive(Default)]
+pub struct HostCall {
+    services: HashMap<u32, Box<dyn Extension>>,
+}
+impl HostCall {
+    ///Creates a host call with no services
+    pub fn new() -> Self {
+        Self::default()
+    }
+    ///Adds `service` as service number `number`
+    pub fn service(mut self, number: u32, service: Box<dyn Extension>) -> Self {
+        self.services.insert(number, service);
+        self
+    }
+}
+impl Extension for HostCall {
+    fn execute(&mut self, context: &mut Context, instruction: u32) -> Result<(), String> {
+        let number = context.registers[reg(&RA, instruction)];
+        match self.services.get_mut(&number) {
+            Some(service) => service.execute(context, instruction),
+            None => Err(format!("unknown host call service {}", number)),
+        }
+    }
+}
+
+///Service setting $r[B] and $r[C] to the low and high words of the instruction count
+pub struct Cycles;
+impl Extension for Cycles {
+    fn execute(&mut self, context: &mut Context, instruction: u32) -> Result<(), String> {
+        context.registers[reg(&RB, instruction)] = context.executed as u32;
+        context.registers[reg(&RC, instruction)] = (context.executed >> 32) as u32;
+        Ok(())
+    }
+}
+
+///Service printing the address and registers as a line of text
+pub struct DebugPrint {
+    out: Box<dyn Write + Send>,
+}
+impl DebugPrint {
+    ///Creates a service printing to `out`
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        DebugPrint { out }
+    }
+}
+impl Extension for DebugPrint {
+    fn execute(&mut self, context: &mut Context, _instruction: u32) -> Result<(), String> {
+        let mut line = format!("pc {}:", context.pc);
+        for (n, value) in context.registers.iter().enumerate() {
+            line += &format!(" r{}={}", n, value);
+        }
+        writeln!(self.out, "{}", line).map_err(|error| error.to_string())
+    }
+}
+
+///Host call with the cycle counter and debug print services, printing to stderr
+pub fn standard() -> HostCall {
+    HostCall::new()
+        .service(SERVICE_CYCLES, Box::new(Cycles))
+        .service(
+            SERVICE_DEBUG_PRINT,
+            Box::new(DebugPrint::new(Box::new(std::io::stderr()))),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::machine::{put, MachineError, VirtualMachine, OP};
+    use std::sync::{Arc, Mutex};
+
+    fn machine(program: Vec<u32>, host: HostCall) -> VirtualMachine {
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(program);
+        vm.extensions().install(HOST_CALL, Box::new(host));
+        vm
+    }
+
+    /// Buffer that stays readable after being handed to `DebugPrint`.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn runs_standard_services() {
+        let printed = Shared::default();
+        let host = standard().service(
+            SERVICE_DEBUG_PRINT,
+            Box::new(DebugPrint::new(Box::new(printed.clone()))),
+        );
+        let mut asm = Assembler::new();
+        asm.loadv(0, SERVICE_CYCLES);
+        asm.loadv(1, 7);
+        asm.word(put(&OP, HOST_CALL) | put(&RB, 1) | put(&RC, 2));
+        asm.loadv(0, SERVICE_DEBUG_PRINT);
+        asm.word(put(&OP, HOST_CALL));
+        asm.halt();
+        let mut vm = machine(asm.finish(), host);
+        vm.run_program().unwrap();
+        assert_eq!(vm.registers[1..3], [2, 0]);
+        assert_eq!(
+            String::from_utf8(printed.0.lock().unwrap().clone()).unwrap(),
+            "pc 4: r0=1 r1=2 r2=0 r3=0 r4=0 r5=0 r6=0 r7=0\n"
+        );
+    }
+
+    #[test]
+    fn installs_custom_handlers() {
+        let mut asm = Assembler::new();
+        asm.loadv(3, 5);
+        asm.word(put(&OP, 15) | put(&RA, 3) | put(&RB, 3) | put(&RC, 3));
+        asm.word(put(&OP, 14));
+        asm.halt();
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(asm.finish());
+        let square = |context: &mut Context, instruction: u32| {
+            let a = reg(&RA, instruction);
+            context.registers[a] *= context.registers[a];
+            Ok(())
+        };
+        vm.extensions().install(15, Box::new(square));
+        vm.run_program().unwrap();
+        assert_eq!(vm.registers[3], 25);
+    }
+
+    #[test]
+    fn failing_handler_stops_the_machine() {
+        let mut asm = Assembler::new();
+        asm.loadv(0, 9);
+        asm.word(put(&OP, HOST_CALL));
+        let mut vm = machine(asm.finish(), standard());
+        assert_eq!(
+            vm.run_program(),
+            Err(MachineError::Extension {
+                pc: 1,
+                message: "unknown host call service 9".to_string()
+            })
+        );
+    }
+}
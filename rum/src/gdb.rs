@@ -0,0 +1,545 @@
+use crate::machine::{MachineError, VirtualMachine};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+/// Byte GDB sends to interrupt a running target.
+const INTERRUPT: u8 = 0x03;
+/// Steps run between checks for an interrupt while continuing.
+const STEPS_PER_POLL: u64 = 1 << 16;
+/// Largest packet GDB may send, and the longest reply asked for, in bytes.
+const PACKET_SIZE: usize = 0x4000;
+/// Registers as GDB sees them: r0 to r7, then the program counter.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rum.um">
+    <reg name="r0" bitsize="32" type="uint32"/>
+    <reg name="r1" bitsize="32" type="uint32"/>
+    <reg name="r2" bitsize="32" type="uint32"/>
+    <reg name="r3" bitsize="32" type="uint32"/>
+    <reg name="r4" bitsize="32" type="uint32"/>
+    <reg name="r5" bitsize="32" type="uint32"/>
+    <reg name="r6" bitsize="32" type="uint32"/>
+    <reg name="r7" bitsize="32" type="uint32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+///Why the machine stopped running
+/// # Variants:
+/// * `Trap`: a single step finished.
+/// * `Breakpoint`: a continue reached a breakpoint.
+/// * `Halted`: the guest ran Halt.
+/// * `Fault`: the machine failed, leaving the program counter on the instruction.
+/// * `Interrupted`: GDB interrupted a continue.
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Stop {
+    Trap,
+    Breakpoint,
+    Halted,
+    Fault(MachineError),
+    Interrupted,
+}
+impl Stop {
+    /// Stop reply packet, with the signal a Unix process would have got.
+    /// Breakpoints are reported with a `T` packet, since only it can
+    /// carry the `swbreak` reason that `qSupported` promises.
+    fn reply(&self) -> String {
+        match self {
+            Stop::Trap => "S05".to_string(),
+            Stop::Breakpoint => "T05swbreak:;".to_string(),
+            Stop::Halted => "W00".to_string(),
+            Stop::Fault(MachineError::DivisionByZero { .. }) => "S08".to_string(),
+            Stop::Fault(_) => "S0b".to_string(),
+            Stop::Interrupted => "S02".to_string(),
+        }
+    }
+}
+
+/// Connection to one GDB, driving the machine it debugs.
+struct Stub<'a> {
+    vm: &'a mut VirtualMachine,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    breakpoints: BTreeSet<u32>,
+    ack: bool,
+    stop: Stop,
+}
+
+///Serves GDB's remote serial protocol on `stream` until it kills or detaches
+/// Registers are r0 to r7 and the program counter, sent big-endian like
+/// the words of a `.um` file. Memory is addressed in bytes, with words
+/// big-endian: address `segment << 32 | offset` is byte `offset` of that
+/// segment, so $m[0] starts at address 0. The program counter is the
+/// byte address of its word, as are breakpoints, which can only be set
+/// in $m[0]. A halt is reported as the process
+/// exiting and a fault as a signal, SIGFPE for division by zero and
+/// SIGSEGV otherwise.
+pub fn serve(vm: &mut VirtualMachine, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut stub = Stub {
+        vm,
+        writer: stream.try_clone()?,
+        reader: BufReader::new(stream),
+        breakpoints: BTreeSet::new(),
+        ack: true,
+        stop: Stop::Trap,
+    };
+    while let Some(packet) = stub.read_packet()? {
+        let reply = match stub.handle(&packet)? {
+            Some(reply) => reply,
+            None => return Ok(()),
+        };
+        stub.send(&reply)?;
+        match packet.as_str() {
+            "QStartNoAckMode" => stub.ack = false,
+            "D" => return Ok(()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl Stub<'_> {
+    /// Answers one packet, or returns `None` when GDB kills the target.
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.stop.reply(),
+            Some(b'g') => self
+                .registers()
+                .iter()
+                .map(|r| format!("{:08x}", r))
+                .collect(),
+            Some(b'G') => match hex_words(&packet[1..]) {
+                Some(values) if values.len() == 9 && values[8].is_multiple_of(4) => {
+                    self.vm.registers.copy_from_slice(&values[..8]);
+                    self.vm.program_counter = (values[8] / 4) as i32;
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            Some(b'p') => {
+                match number(&packet[1..]).and_then(|n| self.registers().get(n as usize).copied()) {
+                    Some(value) => format!("{:08x}", value),
+                    None => "E01".to_string(),
+                }
+            }
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'Z') | Some(b'z') => self.breakpoint(packet),
+            Some(b's') => {
+                self.stop = self.step();
+                self.stop.reply()
+            }
+            Some(b'c') => {
+                self.stop = self.resume()?;
+                self.stop.reply()
+            }
+            Some(b'k') => return Ok(None),
+            Some(b'D') | Some(b'H') => "OK".to_string(),
+            _ => self.query(packet),
+        };
+        Ok(Some(reply))
+    }
+
+    /// Answers general queries, or with an empty packet if unsupported.
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+",
+                PACKET_SIZE
+            );
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match range
+                .split_once(',')
+                .and_then(|(o, l)| Some((number(o)?, number(l)?)))
+            {
+                Some((offset, length)) => {
+                    let start = offset.min(TARGET_XML.len() as u64) as usize;
+                    let length = length.min(PACKET_SIZE as u64) as usize;
+                    let end = start.saturating_add(length).min(TARGET_XML.len());
+                    let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+                    format!("{}{}", marker, &TARGET_XML[start..end])
+                }
+                None => "E01".to_string(),
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => "OK",
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        }
+        .to_string()
+    }
+
+    fn registers(&self) -> Vec<u32> {
+        let mut registers = self.vm.registers.to_vec();
+        registers.push((self.vm.program_counter as u32).wrapping_mul(4));
+        registers
+    }
+
+    fn write_register(&mut self, arguments: &str) -> String {
+        let parsed = arguments
+            .split_once('=')
+            .and_then(|(n, v)| Some((number(n)?, *hex_words(v)?.first()?)));
+        match parsed {
+            Some((8, value)) if value.is_multiple_of(4) => {
+                self.vm.program_counter = (value / 4) as i32
+            }
+            Some((n, value)) if n < 8 => self.vm.registers[n as usize] = value,
+            _ => return "E01".to_string(),
+        }
+        "OK".to_string()
+    }
+
+    fn read_memory(&self, arguments: &str) -> String {
+        // Each byte takes two characters of the reply
+        let (address, end) = match address_and_length(arguments) {
+            Some((address, length)) => {
+                match address.checked_add(length.min(PACKET_SIZE as u64 / 2)) {
+                    Some(end) => (address, end),
+                    None => return "E01".to_string(),
+                }
+            }
+            None => return "E01".to_string(),
+        };
+        let length = end - address;
+        // A read running off the end of a segment returns what there is.
+        let bytes: String = (address..end)
+            .map_while(|address| self.byte(address))
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        match bytes.is_empty() && length > 0 {
+            true => "E01".to_string(),
+            false => bytes,
+        }
+    }
+
+    fn write_memory(&mut self, arguments: &str) -> String {
+        let (range, data) = match arguments.split_once(':') {
+            Some(split) => split,
+            None => return "E01".to_string(),
+        };
+        let (address, length) = match address_and_length(range) {
+            Some((address, length)) if address.checked_add(length).is_some() => (address, length),
+            _ => return "E01".to_string(),
+        };
+        let bytes = match hex_bytes(data) {
+            Some(bytes) if bytes.len() as u64 == length => bytes,
+            _ => return "E01".to_string(),
+        };
+        for (address, byte) in (address..).zip(bytes) {
+            let (segment, offset) = ((address >> 32) as u32, address as u32);
+            let mut word = match self.byte(address) {
+                Some(_) => self.vm.memory[&segment]
+                    .get(offset as usize / 4)
+                    .unwrap()
+                    .to_be_bytes(),
+                None => return "E01".to_string(),
+            };
+            word[offset as usize % 4] = byte;
+            self.vm.poke(segment, offset / 4, u32::from_be_bytes(word));
+        }
+        "OK".to_string()
+    }
+
+    /// Byte at `address` in the segment addressing scheme, if mapped.
+    fn byte(&self, address: u64) -> Option<u8> {
+        let (segment, offset) = ((address >> 32) as u32, address as u32);
+        let word = self.vm.memory.get(&segment)?.get(offset as usize / 4)?;
+        Some(word.to_be_bytes()[offset as usize % 4])
+    }
+
+    fn breakpoint(&mut self, packet: &str) -> String {
+        let mut fields = packet[1..].split(',');
+        let address = match (fields.next(), fields.next().and_then(number)) {
+            (Some("0"), Some(address)) if address.is_multiple_of(4) && address >> 32 == 0 => {
+                address / 4
+            }
+            (Some("0"), _) => return "E01".to_string(),
+            _ => return String::new(),
+        };
+        match packet.starts_with('Z') {
+            true => self.breakpoints.insert(address as u32),
+            false => self.breakpoints.remove(&(address as u32)),
+        };
+        "OK".to_string()
+    }
+
+    fn step(&mut self) -> Stop {
+        match self.vm.step() {
+            Ok(true) => Stop::Trap,
+            Ok(false) => Stop::Halted,
+            Err(error) => Stop::Fault(error),
+        }
+    }
+
+    /// Runs until a breakpoint, a halt, a fault or an interrupt. The
+    /// instruction at the program counter runs even if it has a breakpoint.
+    fn resume(&mut self) -> io::Result<Stop> {
+        let mut steps: u64 = 0;
+        loop {
+            let stop = self.step();
+            if stop != Stop::Trap {
+                return Ok(stop);
+            }
+            if self.breakpoints.contains(&(self.vm.program_counter as u32)) {
+                return Ok(Stop::Breakpoint);
+            }
+            steps += 1;
+            if steps.is_multiple_of(STEPS_PER_POLL) && self.interrupted()? {
+                return Ok(Stop::Interrupted);
+            }
+        }
+    }
+
+    /// Checks, without waiting, whether GDB sent an interrupt.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.reader.get_ref().set_nonblocking(true)?;
+        let buffered = match self.reader.fill_buf() {
+            Ok(buffered) => buffered.first() == Some(&INTERRUPT),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => false,
+            Err(error) => return Err(error),
+        };
+        self.reader.get_ref().set_nonblocking(false)?;
+        if buffered {
+            self.reader.consume(1);
+        }
+        Ok(buffered)
+    }
+
+    /// Reads the next packet, or `None` once GDB disconnects.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Acknowledgements and stray interrupts are skipped.
+            let mut skipped = vec![];
+            if self.reader.read_until(b'$', &mut skipped)? == 0 || skipped.last() != Some(&b'$') {
+                return Ok(None);
+            }
+            let mut packet = vec![];
+            self.reader.read_until(b'#', &mut packet)?;
+            let mut checksum = [0; 2];
+            if packet.pop() != Some(b'#') || self.read_exact(&mut checksum).is_err() {
+                return Ok(None);
+            }
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if !self.ack {
+                return Ok(Some(packet));
+            }
+            if expected == Some(sum(&packet)) {
+                self.writer.write_all(b"+")?;
+                return Ok(Some(packet));
+            }
+            self.writer.write_all(b"-")?;
+        }
+    }
+
+    fn read_exact(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        io::Read::read_exact(&mut self.reader, buffer)
+    }
+
+    /// Sends a packet, again until GDB acknowledges it.
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, sum(data));
+        loop {
+            self.writer.write_all(packet.as_bytes())?;
+            if !self.ack {
+                return Ok(());
+            }
+            let mut answer = [0];
+            loop {
+                self.read_exact(&mut answer)?;
+                if answer[0] == b'+' || answer[0] == b'-' {
+                    break;
+                }
+            }
+            if answer[0] == b'+' {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn sum(data: &str) -> u8 {
+    data.bytes().fold(0, u8::wrapping_add)
+}
+
+fn number(hex: &str) -> Option<u64> {
+    u64::from_str_radix(hex, 16).ok()
+}
+
+fn address_and_length(arguments: &str) -> Option<(u64, u64)> {
+    let (address, length) = arguments.split_once(',')?;
+    Some((number(address)?, number(length)?))
+}
+
+fn hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Big-endian words, as registers are sent.
+fn hex_words(hex: &str) -> Option<Vec<u32>> {
+    let bytes = hex_bytes(hex)?;
+    if !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+    Some(
+        bytes
+            .chunks(4)
+            .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::disasm::{encode, Instruction};
+    use crate::io::Captured;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serves a machine running `program` on a loopback port, returning
+    /// its output once GDB is done.
+    fn start(program: Vec<u32>) -> (TcpStream, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let io = Captured::new(&[]);
+            let output = io.output();
+            let mut vm = VirtualMachine::new();
+            vm.set_io(Box::new(io));
+            vm.initialize_machine(program);
+            serve(&mut vm, stream).unwrap();
+            let output = output.lock().unwrap().clone();
+            output
+        });
+        (TcpStream::connect(address).unwrap(), server)
+    }
+
+    /// Sends a packet as GDB would and returns the reply.
+    fn exchange(stream: &mut TcpStream, packet: &str) -> String {
+        write!(stream, "${}#{:02x}", packet, sum(packet)).unwrap();
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'+');
+        let mut reply = vec![];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                b'$' => {}
+                b'#' => break,
+                b => reply.push(b),
+            }
+        }
+        let mut checksum = [0; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        let reply = String::from_utf8(reply).unwrap();
+        assert_eq!(
+            u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap(),
+            sum(&reply)
+        );
+        stream.write_all(b"+").unwrap();
+        reply
+    }
+
+    #[test]
+    fn debugs_over_loopback() {
+        let mut asm = Assembler::new();
+        asm.loadv(1, 'A' as u32);
+        asm.output(1);
+        asm.loadv(2, 7);
+        asm.add(3, 1, 2);
+        asm.output(3);
+        asm.halt();
+        let (mut gdb, server) = start(asm.finish());
+        assert!(exchange(&mut gdb, "qSupported:swbreak+").contains("qXfer:features:read+"));
+        assert!(exchange(&mut gdb, "qXfer:features:read:target.xml:0,fff").starts_with("l<?xml"));
+        assert_eq!(exchange(&mut gdb, "?"), "S05");
+        assert_eq!(exchange(&mut gdb, "g"), "0".repeat(72));
+        assert_eq!(exchange(&mut gdb, "Z0,c,4"), "OK");
+        assert_eq!(exchange(&mut gdb, "c"), "T05swbreak:;");
+        assert_eq!(exchange(&mut gdb, "p8"), "0000000c");
+        assert_eq!(exchange(&mut gdb, "s"), "S05");
+        assert_eq!(exchange(&mut gdb, "p3"), "00000048");
+        assert_eq!(exchange(&mut gdb, "P3=00000042"), "OK");
+        assert_eq!(exchange(&mut gdb, "m4,6"), "a0000001d400");
+        assert_eq!(exchange(&mut gdb, "M10,4:70000000"), "OK");
+        assert_eq!(exchange(&mut gdb, "m100000000,4"), "E01");
+        assert_eq!(exchange(&mut gdb, "c"), "W00");
+        assert_eq!(exchange(&mut gdb, "g")[24..32], *"00000042");
+        write!(gdb, "$k#{:02x}", sum("k")).unwrap();
+        assert_eq!(server.join().unwrap(), b"A");
+    }
+
+    #[test]
+    fn addresses_program_counter_in_bytes() {
+        let (mut gdb, server) = start(vec![encode(Instruction::Halt); 4]);
+        assert_eq!(exchange(&mut gdb, "P8=00000006"), "E01");
+        assert_eq!(exchange(&mut gdb, "P8=00000008"), "OK");
+        assert_eq!(exchange(&mut gdb, "p8"), "00000008");
+        let mut registers = "0".repeat(64);
+        registers.push_str("0000000d");
+        assert_eq!(exchange(&mut gdb, &format!("G{}", registers)), "E01");
+        registers.replace_range(64.., "0000000c");
+        assert_eq!(exchange(&mut gdb, &format!("G{}", registers)), "OK");
+        assert_eq!(exchange(&mut gdb, "g"), registers);
+        assert_eq!(exchange(&mut gdb, "D"), "OK");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rejects_ranges_that_overflow() {
+        let (mut gdb, server) = start(vec![encode(Instruction::Halt)]);
+        assert_eq!(
+            exchange(
+                &mut gdb,
+                "qXfer:features:read:target.xml:1,ffffffffffffffff"
+            ),
+            format!("l{}", &TARGET_XML[1..])
+        );
+        assert_eq!(
+            exchange(
+                &mut gdb,
+                "qXfer:features:read:target.xml:ffffffffffffffff,10"
+            ),
+            "l"
+        );
+        assert_eq!(exchange(&mut gdb, "mffffffffffffffff,2"), "E01");
+        assert_eq!(exchange(&mut gdb, "Mffffffffffffffff,2:0000"), "E01");
+        assert_eq!(exchange(&mut gdb, "m0,ffffffffffffffff"), "70000000");
+        assert_eq!(exchange(&mut gdb, "D"), "OK");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn reports_faults_as_signals() {
+        let (mut gdb, server) = start(vec![
+            encode(Instruction::Div { a: 1, b: 2, c: 3 }),
+            encode(Instruction::Halt),
+        ]);
+        assert_eq!(exchange(&mut gdb, "c"), "S08");
+        assert_eq!(exchange(&mut gdb, "p8"), "00000000");
+        assert_eq!(exchange(&mut gdb, "D"), "OK");
+        server.join().unwrap();
+    }
+}
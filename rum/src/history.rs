@@ -0,0 +1,146 @@
+use crate::disasm::{decode, Instruction};
+use crate::machine::REGISTERS;
+use crate::segment::Segment;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+///A register or memory word the guest can write
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Location {
+    Register(usize),
+    Word { segment: u32, index: u32 },
+}
+
+///A change to memory made by one instruction, with what it replaced
+/// # Variants:
+/// * `Word`: a store, holding the old word.
+/// * `Mapped`: a new segment, and whether its identifier came from the pool.
+/// * `Unmapped`: an unmapped segment, holding its contents and, under
+///   leak checking, where it was mapped.
+/// * `Program`: a Load Program, holding the abandoned $m[0].
+#[derive(Debug, Clone)]
+pub(crate) enum Change {
+    Word {
+        segment: u32,
+        index: usize,
+        old: u32,
+    },
+    Mapped {
+        id: u32,
+        from_pool: bool,
+    },
+    Unmapped {
+        id: u32,
+        segment: Arc<Segment>,
+        origin: Option<u32>,
+    },
+    Program(Arc<Segment>),
+}
+
+///Everything needed to undo one instruction
+/// Registers are small enough to save whole.
+#[derive(Debug, Clone)]
+pub(crate) struct Step {
+    pub(crate) program_counter: i32,
+    pub(crate) instruction: u32,
+    pub(crate) registers: [u32; REGISTERS],
+    pub(crate) changes: Vec<Change>,
+}
+
+impl Step {
+    ///Whether this step wrote `location`, even if the value stayed the same
+    pub(crate) fn writes(&self, location: Location) -> bool {
+        match location {
+            Location::Register(r) => match decode(self.instruction) {
+                Instruction::CMov { a, c, .. } => a == r && self.registers[c] != 0,
+                Instruction::Load { a, .. }
+                | Instruction::Add { a, .. }
+                | Instruction::Mul { a, .. }
+                | Instruction::Div { a, .. }
+                | Instruction::Nand { a, .. }
+                | Instruction::LoadValue { a, .. } => a == r,
+                Instruction::MapSegment { b, .. } => b == r,
+                Instruction::Input { c } => c == r,
+                _ => false,
+            },
+            Location::Word { segment, index } => self.changes.iter().any(|change| match change {
+                Change::Word {
+                    segment: s,
+                    index: i,
+                    ..
+                } => *s == segment && *i == index as usize,
+                Change::Mapped { id, .. } => *id == segment,
+                Change::Program(_) => segment == 0,
+                Change::Unmapped { .. } => false,
+            }),
+        }
+    }
+}
+
+///Undo log of the most recent steps
+/// Once `capacity` steps are logged the oldest is forgotten.
+#[derive(Debug)]
+pub(crate) struct History {
+    steps: VecDeque<Step>,
+    capacity: usize,
+}
+impl History {
+    pub(crate) fn new(capacity: usize) -> Self {
+        History {
+            steps: VecDeque::with_capacity(capacity.min(1 << 16)),
+            capacity,
+        }
+    }
+    pub(crate) fn push(&mut self, step: Step) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(step);
+    }
+    pub(crate) fn pop(&mut self) -> Option<Step> {
+        self.steps.pop_back()
+    }
+    pub(crate) fn last(&self) -> Option<&Step> {
+        self.steps.back()
+    }
+    pub(crate) fn len(&self) -> usize {
+        self.steps.len()
+    }
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+///Addresses and words of the most recent instructions executed
+/// Once `capacity` are kept the oldest is forgotten.
+#[derive(Debug)]
+pub(crate) struct Trail {
+    entries: VecDeque<(u32, u32)>,
+    capacity: usize,
+}
+impl Trail {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Trail {
+            entries: VecDeque::with_capacity(capacity.min(1 << 16)),
+            capacity,
+        }
+    }
+    pub(crate) fn push(&mut self, pc: u32, instruction: u32) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((pc, instruction));
+    }
+    pub(crate) fn entries(&self) -> Vec<(u32, u32)> {
+        self.entries.iter().copied().collect()
+    }
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+///The I/O device behind Output and Input
+/// `read_byte` returns `None` once the end of input has been signaled.
+/// Each machine owns its device, so devices must be `Send` for machines
+/// to move between threads.
+pub trait IoDevice: Send {
+    fn read_byte(&mut self) -> Option<u8>;
+    fn write_byte(&mut self, byte: u8);
+}
+
+///Standard input and output of the process
+/// Output is flushed before each read, so prompts show up before the
+/// guest waits for an answer.
+#[derive(Debug, Default)]
+pub struct StdIo;
+impl IoDevice for StdIo {
+    fn read_byte(&mut self) -> Option<u8> {
+        io::stdout().flush().unwrap();
+        io::stdin().lock().bytes().next().map(Result::unwrap)
+    }
+    fn write_byte(&mut self, byte: u8) {
+        io::stdout().lock().write_all(&[byte]).unwrap();
+    }
+}
+
+///Fixed input and captured output, for running guests in tests
+/// The output buffer is shared, so it can still be read after the device
+/// is handed to a machine.
+#[derive(Debug, Default)]
+pub struct Captured {
+    input: VecDeque<u8>,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+impl Captured {
+    ///Creates a device that reads `input`, then signals the end of input
+    pub fn new(input: &[u8]) -> Self {
+        Captured {
+            input: input.iter().copied().collect(),
+            output: Arc::default(),
+        }
+    }
+    ///Returns the buffer output is written to
+    pub fn output(&self) -> Arc<Mutex<Vec<u8>>> {
+        Arc::clone(&self.output)
+    }
+}
+impl IoDevice for Captured {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+    fn write_byte(&mut self, byte: u8) {
+        self.output.lock().unwrap().push(byte);
+    }
+}
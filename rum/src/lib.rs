@@ -1,29 +1,44 @@
-use std::collections::HashMap;
+pub mod analysis;
+pub mod asm;
+pub mod batch;
+pub mod cfg;
+pub mod compiler;
+pub mod coverage;
+pub mod disasm;
+pub mod dump;
+pub mod engine;
+#[cfg(feature = "extensions")]
+pub mod extension;
+pub mod gdb;
+pub mod history;
+pub mod io;
+pub mod json;
+pub mod lint;
 pub mod machine;
+pub mod object;
+pub mod optimize;
+pub mod predecoded;
+pub mod reference;
 pub mod rumload;
-// use std::thread::sleep;
-// use std::time::{Duration, Instant};
+pub mod scheduler;
+pub mod script;
+pub mod segment;
+pub mod stdlib;
+pub mod symbols;
+#[cfg(target_os = "linux")]
+pub mod terminal;
+pub mod transcript;
+pub mod translate;
 #[test]
 fn test_singular_value() {
     //Outputs a heart to the terminal
-    let mut vm = machine::VirtualMachine {
-        registers: vec![],
-        memory: HashMap::new(),
-        program_counter: 0,
-        last_key: 0,
-    };
+    let mut vm = machine::VirtualMachine::new();
     vm.initialize_machine(vec![3523215363, 2684354561, 7_u32 << 28]);
-    vm.run_program();
+    vm.run_program().unwrap();
 }
 #[test]
 fn test_hello_world() {
-    let mut vm = machine::VirtualMachine {
-        registers: vec![],
-        memory: HashMap::new(),
-        program_counter: 0,
-        last_key: 0,
-    };
-    // let now = Instant::now();
+    let mut vm = machine::VirtualMachine::new();
     vm.initialize_machine(vec![
         3523215432, 2684354561, 3523215461, 2684354561, 3523215468, 2684354561, 3523215468,
         2684354561, 3523215471, 2684354561, 3523215404, 2684354561, 3523215392, 2684354561,
@@ -31,32 +46,20 @@ fn test_hello_world() {
         2684354561, 3523215460, 2684354561, 3523215406, 2684354561, 3523215370, 2684354561,
         1879048192,
     ]);
-    vm.run_program();
-    // println!("Time! : {}", now.elapsed().as_secs());
+    vm.run_program().unwrap();
 }
 
 #[test]
 fn test_halt() {
-    let mut vm = machine::VirtualMachine {
-        registers: vec![],
-        memory: HashMap::new(),
-        program_counter: 0,
-        last_key: 0,
-    };
+    let mut vm = machine::VirtualMachine::new();
     vm.initialize_machine(vec![1879048192]);
-    vm.run_program();
+    vm.run_program().unwrap();
 }
 
 #[test]
 //Tests midmark
 fn test_midmark() {
-    let mut vm = machine::VirtualMachine {
-        registers: vec![],
-        memory: HashMap::new(),
-        program_counter: 0,
-        last_key: 0,
-    };
-    let program = rumload::load(Some("./rum-binaries/midmark.um"));
+    let mut vm = machine::VirtualMachine::new();
     vm.initialize_machine(vec![
         3523219586, 3221225521, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
         0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
@@ -4000,5 +4003,5 @@ fn test_midmark() {
         268435665, 3623878659, 268435612, 268435929, 3489661058, 268435696, 536871303, 536871339,
         3657433220, 536871338, 3657460493, 3221225525,
     ]);
-    vm.run_program();
+    vm.run_program().unwrap();
 }
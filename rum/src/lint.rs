@@ -0,0 +1,192 @@
+use crate::analysis::{Analysis, Exit, Value};
+use crate::disasm::{decode, Instruction};
+use std::fmt;
+use std::io::{self, Write};
+
+///Something wrong with a program, found without running it
+/// # Variants:
+/// * `InvalidOpcode`: a reachable word with opcode 14 or 15.
+/// * `DivisionByZero`: a Division whose divisor is always zero.
+/// * `OutputTooLarge`: an Output of a constant above 255.
+/// * `JumpPastEnd`: a Load Program to an address past the end of $m[0].
+/// * `Unreachable`: addresses up to `end` that no path from 0 reaches.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Problem {
+    InvalidOpcode(u32),
+    DivisionByZero,
+    OutputTooLarge(u32),
+    JumpPastEnd(u32),
+    Unreachable { end: usize },
+}
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::InvalidOpcode(opcode) => write!(f, "reachable invalid opcode {}", opcode),
+            Problem::DivisionByZero => write!(f, "divisor is always zero"),
+            Problem::OutputTooLarge(value) => write!(f, "outputs {}, which is above 255", value),
+            Problem::JumpPastEnd(target) => write!(f, "jumps to {}, past the end of $m[0]", target),
+            Problem::Unreachable { end } => write!(f, "unreachable through address {}", end),
+        }
+    }
+}
+
+///A problem and the address it was found at
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Finding {
+    pub address: usize,
+    pub problem: Problem,
+}
+
+///Checks a program for code that would fault or misbehave
+/// Uses the constant propagation of `analysis`, so only values it can
+/// prove are flagged. Unreachable regions are not reported when the
+/// program has jumps with unknown targets, since any address may run.
+pub fn lint(program: &[u32]) -> Vec<Finding> {
+    let analysis = Analysis::new(program);
+    let mut findings = vec![];
+    let mut unreachable: Option<usize> = None;
+    for (address, &word) in program.iter().enumerate() {
+        let registers = match analysis.registers[address] {
+            Some(registers) => registers,
+            None => {
+                if !analysis.indirect && unreachable.is_none() {
+                    unreachable = Some(address);
+                }
+                continue;
+            }
+        };
+        if let Some(start) = unreachable.take() {
+            findings.push(Finding {
+                address: start,
+                problem: Problem::Unreachable { end: address - 1 },
+            });
+        }
+        let mut found = |problem| findings.push(Finding { address, problem });
+        match decode(word) {
+            Instruction::Invalid { opcode } => found(Problem::InvalidOpcode(opcode)),
+            Instruction::Div { c, .. } if registers[c] == Value::Const(0) => {
+                found(Problem::DivisionByZero)
+            }
+            Instruction::Output { c } => {
+                if let Some(values) = registers[c].values() {
+                    if let Some(&value) = values.iter().find(|&&v| v > 255) {
+                        found(Problem::OutputTooLarge(value));
+                    }
+                }
+            }
+            _ => {}
+        }
+        if let Some(Exit::Jump(targets)) = &analysis.exits[address] {
+            for &target in targets {
+                if target as usize >= program.len() {
+                    found(Problem::JumpPastEnd(target));
+                }
+            }
+        }
+    }
+    if let Some(start) = unreachable {
+        findings.push(Finding {
+            address: start,
+            problem: Problem::Unreachable {
+                end: program.len() - 1,
+            },
+        });
+    }
+    findings
+}
+
+///Prints each finding with the disassembly of its address
+pub fn report(program: &[u32], findings: &[Finding], out: &mut dyn Write) -> io::Result<()> {
+    for finding in findings {
+        let word = program[finding.address];
+        writeln!(
+            out,
+            "{:>8}  {:08x}  {:<24}  {}",
+            finding.address,
+            word,
+            decode(word).to_string(),
+            finding.problem
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::disasm::Instruction;
+
+    #[test]
+    fn flags_faulty_instructions() {
+        let mut asm = Assembler::new();
+        asm.loadv(1, 300);
+        asm.output(1);
+        asm.div(3, 1, 2);
+        asm.emit(Instruction::Invalid { opcode: 14 });
+        asm.loadv(4, 40);
+        asm.loadp(0, 4);
+        let program = asm.finish();
+        assert_eq!(
+            lint(&program),
+            vec![
+                Finding {
+                    address: 1,
+                    problem: Problem::OutputTooLarge(300)
+                },
+                Finding {
+                    address: 2,
+                    problem: Problem::DivisionByZero
+                },
+                Finding {
+                    address: 3,
+                    problem: Problem::InvalidOpcode(14)
+                },
+                Finding {
+                    address: 5,
+                    problem: Problem::JumpPastEnd(40)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn flags_unreachable_regions() {
+        let mut asm = Assembler::new();
+        asm.loadv(1, 4);
+        asm.loadp(0, 1);
+        // Skipped, so not reported as invalid
+        asm.emit(Instruction::Invalid { opcode: 15 });
+        asm.emit(Instruction::Invalid { opcode: 15 });
+        asm.halt();
+        asm.cmov(0, 0, 0);
+        let program = asm.finish();
+        assert_eq!(
+            lint(&program),
+            vec![
+                Finding {
+                    address: 2,
+                    problem: Problem::Unreachable { end: 3 }
+                },
+                Finding {
+                    address: 5,
+                    problem: Problem::Unreachable { end: 5 }
+                },
+            ]
+        );
+        let mut text = vec![];
+        report(&program, &lint(&program), &mut text).unwrap();
+        assert!(String::from_utf8(text).unwrap().starts_with(
+            "       2  f0000000  invalid opcode 15         unreachable through address 3\n"
+        ));
+    }
+
+    #[test]
+    fn clean_program_has_no_findings() {
+        let mut asm = Assembler::new();
+        asm.loadv(1, 72);
+        asm.output(1);
+        asm.halt();
+        assert!(lint(&asm.finish()).is_empty());
+    }
+}
@@ -1,17 +1,24 @@
+use crate::coverage::Coverage;
+#[cfg(feature = "extensions")]
+use crate::extension::{Context, Extensions};
+use crate::history::{Change, History, Location, Step, Trail};
+use crate::io::{IoDevice, StdIo};
+use crate::optimize::{Superinstruction, Superinstructions};
+use crate::segment::Segment;
 use std::collections::HashMap;
-use std::io::prelude::*;
+use std::sync::{Arc, Weak};
 pub struct Field {
     width: u32,
     lsb: u32,
 }
-static RA: Field = Field { width: 3, lsb: 6 };
-static RB: Field = Field { width: 3, lsb: 3 };
-static RC: Field = Field { width: 3, lsb: 0 };
-static RL: Field = Field { width: 3, lsb: 25 };
-static VL: Field = Field { width: 25, lsb: 0 };
-static OP: Field = Field { width: 4, lsb: 28 };
+pub const RA: Field = Field { width: 3, lsb: 6 };
+pub const RB: Field = Field { width: 3, lsb: 3 };
+pub const RC: Field = Field { width: 3, lsb: 0 };
+pub const RL: Field = Field { width: 3, lsb: 25 };
+pub const VL: Field = Field { width: 25, lsb: 0 };
+pub const OP: Field = Field { width: 4, lsb: 28 };
 #[derive(Debug, PartialEq, Clone, Copy)]
-enum Opcode {
+pub enum Opcode {
     CMov,
     Load,
     Store,
@@ -35,100 +42,483 @@ enum Opcode {
 // ● A segment will only ever be categorized as mapped or unmapped,
 // never both at the same time
 
+/// Number of registers.
+pub const REGISTERS: usize = 8;
+
+/// Default cap on allocated guest memory, in words (1 GiB).
+pub const DEFAULT_MEMORY_LIMIT: usize = 1 << 28;
+
+///Memory usage of the guest program
+/// # Parameters:
+/// * `live_words`: Words currently held by mapped segments, including $m[0].
+/// * `live_segments`: Segments currently mapped, including $m[0].
+/// * `resident_words`: Words actually allocated, which is less than
+///   `live_words` when lazy segments are mostly untouched or segments
+///   share storage after Load Program.
+/// * `peak_words`: Largest value `live_words` has reached.
+/// * `peak_segments`: Largest value `live_segments` has reached.
+/// * `peak_resident_words`: Largest value `resident_words` has reached.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct MemoryStats {
+    pub live_words: usize,
+    pub live_segments: usize,
+    pub resident_words: usize,
+    pub peak_words: usize,
+    pub peak_segments: usize,
+    pub peak_resident_words: usize,
+}
+impl MemoryStats {
+    /// Records a newly mapped segment. Storage shared with
+    /// another segment is only counted once.
+    fn map(&mut self, segment: &Arc<Segment>) {
+        self.live_words += segment.len();
+        self.live_segments += 1;
+        self.peak_words = self.peak_words.max(self.live_words);
+        self.peak_segments = self.peak_segments.max(self.live_segments);
+        if Arc::strong_count(segment) == 1 {
+            self.grow(segment.resident_words());
+        }
+    }
+    /// Records an unmapped segment, freeing its storage if nothing else shares it.
+    fn unmap(&mut self, segment: &Arc<Segment>) {
+        self.live_words -= segment.len();
+        self.live_segments -= 1;
+        if Arc::strong_count(segment) == 1 {
+            self.resident_words -= segment.resident_words();
+        }
+    }
+    /// Records `words` newly allocated words in a mapped segment.
+    fn grow(&mut self, words: usize) {
+        self.resident_words += words;
+        self.peak_resident_words = self.peak_resident_words.max(self.resident_words);
+    }
+}
+
+///Errors that stop the machine
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MachineError {
+    /// The instruction at `pc` needed more words than the memory limit allows.
+    AllocationLimit {
+        pc: u32,
+        requested: usize,
+        resident: usize,
+        limit: usize,
+    },
+    /// The program counter points past the end of $m[0].
+    ProgramCounterOutOfBounds { pc: u32 },
+    /// The instruction at `pc` used a segment that is not mapped.
+    UnmappedSegment { pc: u32, segment: u32 },
+    /// The instruction at `pc` used a word past the end of a segment.
+    OutOfBounds { pc: u32, segment: u32, index: u32 },
+    /// The instruction at `pc` divided by zero.
+    DivisionByZero { pc: u32 },
+    /// The handler of the extension instruction at `pc` failed.
+    #[cfg(feature = "extensions")]
+    Extension { pc: u32, message: String },
+}
+impl MachineError {
+    ///Program counter of the instruction that failed
+    pub fn pc(&self) -> u32 {
+        match *self {
+            MachineError::AllocationLimit { pc, .. }
+            | MachineError::ProgramCounterOutOfBounds { pc }
+            | MachineError::UnmappedSegment { pc, .. }
+            | MachineError::OutOfBounds { pc, .. }
+            | MachineError::DivisionByZero { pc } => pc,
+            #[cfg(feature = "extensions")]
+            MachineError::Extension { pc, .. } => pc,
+        }
+    }
+}
+impl std::fmt::Display for MachineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MachineError::AllocationLimit {
+                pc,
+                requested,
+                resident,
+                limit,
+            } => write!(
+                f,
+                "instruction at pc {} allocated {} words with {} resident, exceeding the limit of {} words",
+                pc, requested, resident, limit
+            ),
+            MachineError::ProgramCounterOutOfBounds { pc } => {
+                write!(f, "program counter {} is past the end of $m[0]", pc)
+            }
+            MachineError::UnmappedSegment { pc, segment } => write!(
+                f,
+                "instruction at pc {} used unmapped segment {}",
+                pc, segment
+            ),
+            MachineError::OutOfBounds { pc, segment, index } => write!(
+                f,
+                "instruction at pc {} used word {} past the end of segment {}",
+                pc, index, segment
+            ),
+            MachineError::DivisionByZero { pc } => {
+                write!(f, "instruction at pc {} divided by zero", pc)
+            }
+            #[cfg(feature = "extensions")]
+            MachineError::Extension { pc, message } => {
+                write!(f, "extension instruction at pc {} failed: {}", pc, message)
+            }
+        }
+    }
+}
+impl std::error::Error for MachineError {}
+
+///Segment still mapped when the program halted
+/// # Parameters:
+/// * `segment`: Identifier of the segment.
+/// * `words`: Size of the segment.
+/// * `pc`: Address of the Map Segment instruction that created it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Leak {
+    pub segment: u32,
+    pub words: usize,
+    pub pc: u32,
+}
+
 ///Virtual Machine
 /// # Parameters:
-/// * `registers`: Vectors of u32, contents represent what is stored within the register.
-/// * `memory`: Hashmap of u32 keys, and values of Vec<u32> that represent memory segments and their identifiers.
+/// * `registers`: The eight registers, indexed by the register numbers `reg` decodes.
+/// * `memory`: Hashmap of u32 keys, and values of Segment that represent memory segments and their identifiers.
+///   Segments are reference counted and copied on write, so Load Program can share storage.
 /// * `program_counter`: Tracks the current instruction.
 /// * `last_key`: Tracks the last used identifier, so the UM can map with new identifiers.
+/// * `pool`: Unmapped segment identifiers, reused by later maps.
+/// * `stats`: Live and peak memory usage.
+/// * `memory_limit`: Most words the guest may have allocated at once, if capped.
+/// * `superinstructions`: Fused sequences of $m[0], when optimization is enabled.
+/// * `fused_from`: Storage the fused sequences were found in, while $m[0] is still it.
+/// * `fused`: Fused sequences of earlier programs by the address of their
+///   storage, for when Load Program brings one back.
+/// * `program_version`: Counts writes to $m[0] and replacements of it.
+/// * `coverage`: Addresses executed in each version of $m[0], when recording.
+/// * `history`: Undo log of recent steps, when enabled.
+/// * `trail`: Addresses and words of the last instructions executed, when kept.
+/// * `changes`: Memory changes made so far by the step being run, for the undo log.
+/// * `origins`: Address of the Map Segment that created each mapped segment, under leak checking.
+/// * `io`: Device read by Input and written by Output.
+/// * `extensions`: Handlers for opcodes 14 and 15.
+/// * `executed`: Instructions executed since the program was loaded.
 pub struct VirtualMachine {
-    pub registers: Vec<u32>,
-    pub memory: HashMap<u32, Vec<u32>>,
+    pub registers: [u32; REGISTERS],
+    pub memory: HashMap<u32, Arc<Segment>>,
     pub program_counter: i32,
     pub last_key: u32,
+    pool: Vec<u32>,
+    stats: MemoryStats,
+    memory_limit: Option<usize>,
+    superinstructions: Option<Superinstructions>,
+    fused_from: Weak<Segment>,
+    fused: HashMap<usize, (Weak<Segment>, Superinstructions)>,
+    program_version: u64,
+    coverage: Option<Coverage>,
+    history: Option<History>,
+    trail: Option<Trail>,
+    changes: Vec<Change>,
+    origins: Option<HashMap<u32, u32>>,
+    io: Box<dyn IoDevice>,
+    #[cfg(feature = "extensions")]
+    extensions: Extensions,
+    #[cfg(feature = "extensions")]
+    executed: u64,
+}
+impl Default for VirtualMachine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl VirtualMachine {
+    ///Creates an empty machine with the default memory limit
+    pub fn new() -> Self {
+        VirtualMachine {
+            registers: [0; REGISTERS],
+            memory: HashMap::new(),
+            program_counter: 0,
+            last_key: 0,
+            pool: vec![],
+            stats: MemoryStats::default(),
+            memory_limit: Some(DEFAULT_MEMORY_LIMIT),
+            superinstructions: None,
+            fused_from: Weak::new(),
+            fused: HashMap::new(),
+            program_version: 0,
+            coverage: None,
+            history: None,
+            trail: None,
+            changes: vec![],
+            origins: None,
+            io: Box::new(StdIo),
+            #[cfg(feature = "extensions")]
+            extensions: Extensions::default(),
+            #[cfg(feature = "extensions")]
+            executed: 0,
+        }
+    }
+    ///Replaces the I/O device, which is standard input and output by default
+    pub fn set_io(&mut self, io: Box<dyn IoDevice>) {
+        self.io = io;
+    }
+    ///Returns the handlers for the extension opcodes 14 and 15, to install more
+    #[cfg(feature = "extensions")]
+    pub fn extensions(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+    ///Fuses common instruction sequences of $m[0] into superinstructions
+    /// A program Load Program brings in is scanned unless its storage was
+    /// scanned before, and a superinstruction is dropped when any word it
+    /// covers is written.
+    pub fn enable_superinstructions(&mut self) {
+        self.fused.clear();
+        self.fused_from = self.memory.get(&0).map_or_else(Weak::new, Arc::downgrade);
+        self.superinstructions = Some(self.fuse_program());
+    }
+    ///Returns the number of superinstructions in $m[0], if enabled
+    pub fn superinstruction_count(&self) -> Option<usize> {
+        self.superinstructions
+            .as_ref()
+            .map(Superinstructions::count)
+    }
+    ///Returns how many times $m[0] has been written or replaced since the program was loaded
+    /// Translated code stays valid only while this is unchanged.
+    pub fn program_version(&self) -> u64 {
+        self.program_version
+    }
+    ///Starts recording which addresses of $m[0] are executed
+    pub fn enable_coverage(&mut self) {
+        let mut coverage = Coverage::default();
+        if let Some(program) = self.memory.get(&0) {
+            coverage.start(program);
+        }
+        self.coverage = Some(coverage);
+    }
+    ///Returns the coverage recorded so far, if enabled
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+    ///Starts remembering where each segment is mapped, for `leaks`
+    pub fn enable_leak_check(&mut self) {
+        self.origins = Some(HashMap::new());
+    }
+    ///Lists the segments other than $m[0] that are still mapped, by identifier
+    /// Empty unless leak checking was enabled before the segments were mapped.
+    pub fn leaks(&self) -> Vec<Leak> {
+        let origins = match &self.origins {
+            Some(origins) => origins,
+            None => return vec![],
+        };
+        let mut leaks: Vec<Leak> = origins
+            .iter()
+            .map(|(&segment, &pc)| Leak {
+                segment,
+                words: self.memory[&segment].len(),
+                pc,
+            })
+            .collect();
+        leaks.sort_by_key(|leak| leak.segment);
+        leaks
+    }
+    fn fuse_program(&self) -> Superinstructions {
+        match self.memory.get(&0) {
+            Some(program) => Superinstructions::new(program),
+            None => Superinstructions::new(&Segment::Dense(vec![])),
+        }
+    }
+    ///Sets the most words the guest may have allocated at once, or `None` for no limit
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+    }
+    ///Returns the live and peak memory usage of the guest
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.stats
+    }
+    /// Checks that `words` more words can be allocated without passing the memory limit.
+    fn reserve(&self, words: usize) -> Result<(), MachineError> {
+        match self.memory_limit {
+            Some(limit) if self.stats.resident_words + words > limit => {
+                Err(MachineError::AllocationLimit {
+                    pc: self.program_counter as u32,
+                    requested: words,
+                    resident: self.stats.resident_words,
+                    limit,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
     ///Initializes machine
     /// # Arguments:
     ///  * `program`: program in binary to be run
     pub fn initialize_machine(&mut self, program: Vec<u32>) {
-        // Initializes the virtual machine by creating empty register vector, empty memory vector.
-        for _i in 0..8 {
-            self.registers.push(0);
-        }
+        // Initializes the virtual machine by clearing the registers and memory.
+        self.registers = [0; REGISTERS];
 
         // Stores program in memory[0].
         self.memory = HashMap::new();
+        self.pool = vec![];
+        self.stats = MemoryStats::default();
+        let program = Arc::new(Segment::from(program));
+        self.stats.map(&program);
         self.memory.insert(0, program);
         self.program_counter = 0;
+        self.program_version = 0;
+        #[cfg(feature = "extensions")]
+        {
+            self.executed = 0;
+        }
+        if self.superinstructions.is_some() {
+            self.enable_superinstructions();
+        }
+        if self.coverage.is_some() {
+            self.enable_coverage();
+        }
+        if let Some(history) = &self.history {
+            self.enable_history(history.capacity());
+        }
+        if let Some(trail) = &self.trail {
+            self.enable_trail(trail.capacity());
+        }
+        if self.origins.is_some() {
+            self.enable_leak_check();
+        }
     }
     /// Conditional Move
     /// if $r[C] != 0 then $r[A] := $r[B]
     fn conditional_move(&mut self, instruction: u32) {
-        let a = get(&RA, instruction);
-        let b = get(&RB, instruction);
-        let c = get(&RC, instruction);
-        if self.registers[c as usize] != 0 {
-            self.registers[a as usize] = self.registers[b as usize];
+        let a = reg(&RA, instruction);
+        let b = reg(&RB, instruction);
+        let c = reg(&RC, instruction);
+        if self.registers[c] != 0 {
+            self.registers[a] = self.registers[b];
         }
     }
+    /// Returns the mapped segment `id`, or the fault for using it.
+    fn segment(&self, id: u32) -> Result<&Arc<Segment>, MachineError> {
+        self.memory.get(&id).ok_or(MachineError::UnmappedSegment {
+            pc: self.program_counter as u32,
+            segment: id,
+        })
+    }
+    /// Returns word `index` of segment `id`, or the fault for reading it.
+    fn word(&self, id: u32, index: u32) -> Result<u32, MachineError> {
+        self.segment(id)?
+            .get(index as usize)
+            .ok_or(MachineError::OutOfBounds {
+                pc: self.program_counter as u32,
+                segment: id,
+                index,
+            })
+    }
     /// Segmented Load
     /// $r[A] := $m[$r[B]][$r[C]]
-    fn load_into(&mut self, instruction: u32) {
-        let a = get(&RA, instruction);
-        let b = get(&RB, instruction);
-        let c = get(&RC, instruction);
+    fn load_into(&mut self, instruction: u32) -> Result<(), MachineError> {
+        let a = reg(&RA, instruction);
+        let b = reg(&RB, instruction);
+        let c = reg(&RC, instruction);
         // if $r[C] != 0 then $r[A] := $r[B]
-        self.registers[a as usize] =
-            self.memory[&self.registers[b as usize]][self.registers[c as usize] as usize];
+        self.registers[a] = self.word(self.registers[b], self.registers[c])?;
+        Ok(())
     }
     /// Segmented Store
     /// $m[$r[A]][$r[B]] := $r[C]
-    fn store(&mut self, instruction: u32) {
-        let a = get(&RA, instruction);
-        let b = get(&RB, instruction);
-        let c = get(&RC, instruction);
-        (*self.memory.get_mut(&self.registers[a as usize]).unwrap())
-            [self.registers[b as usize] as usize] = self.registers[c as usize];
+    /// A segment sharing storage is copied first. Fails if the copy or
+    /// a new page of a lazy segment would pass the memory limit.
+    fn store(&mut self, instruction: u32) -> Result<(), MachineError> {
+        let a = reg(&RA, instruction);
+        let b = reg(&RB, instruction);
+        let c = reg(&RC, instruction);
+        let id = self.registers[a];
+        let index = self.registers[b];
+        let value = self.registers[c];
+        let old = self.word(id, index)?;
+        self.reserve(self.words_to_write(id, index as usize, value))?;
+        if self.history.is_some() {
+            self.changes.push(Change::Word {
+                segment: id,
+                index: index as usize,
+                old,
+            });
+        }
+        self.write_word(id, index as usize, value);
+        Ok(())
+    }
+    /// Words that writing `value` into a mapped segment would allocate.
+    fn words_to_write(&self, id: u32, index: usize, value: u32) -> usize {
+        let segment = &self.memory[&id];
+        let mut words = segment.words_to_store(index, value);
+        if Arc::strong_count(segment) > 1 {
+            words += segment.resident_words();
+        }
+        words
+    }
+    ///Writes word `index` of segment `id` for the host, as a debugger would
+    /// Returns false, changing nothing, if the word is not mapped.
+    pub fn poke(&mut self, id: u32, index: u32, value: u32) -> bool {
+        if self.word(id, index).is_err() {
+            return false;
+        }
+        self.write_word(id, index as usize, value);
+        true
+    }
+    /// Writes an in-bounds word of a mapped segment, copying it first if shared.
+    fn write_word(&mut self, id: u32, index: usize, value: u32) {
+        let words = self.words_to_write(id, index, value);
+        Arc::make_mut(self.memory.get_mut(&id).unwrap()).set(index, value);
+        self.stats.grow(words);
+        if id == 0 {
+            self.program_version += 1;
+            if let Some(superinstructions) = &mut self.superinstructions {
+                superinstructions.invalidate(index);
+                // The sequences no longer match the storage they were found in
+                self.fused_from = Weak::new();
+            }
+        }
     }
     /// Addition
     /// $r[A] := ($r[B] + $r[C]) mod 2^32
     fn add(&mut self, instruction: u32) {
-        let a = get(&RA, instruction);
-        let b = get(&RB, instruction);
-        let c = get(&RC, instruction);
+        let a = reg(&RA, instruction);
+        let b = reg(&RB, instruction);
+        let c = reg(&RC, instruction);
         // $r[A] := ($r[B] + $r[C]) mod 2^32
-        self.registers[a as usize] = ((self.registers[b as usize] as usize
-            + self.registers[c as usize] as usize)
-            % usize::pow(2, 32)) as u32;
+        self.registers[a] =
+            ((self.registers[b] as usize + self.registers[c] as usize) % usize::pow(2, 32)) as u32;
     }
     ///Multiplication
     /// $r[A] := ($r[B] × $r[C]) mod 2^32
     fn multiply(&mut self, instruction: u32) {
-        let a = get(&RA, instruction);
-        let b = get(&RB, instruction);
-        let c = get(&RC, instruction);
-        self.registers[a as usize] = ((self.registers[b as usize] as usize
-            * self.registers[c as usize] as usize)
-            % usize::pow(2, 32)) as u32;
+        let a = reg(&RA, instruction);
+        let b = reg(&RB, instruction);
+        let c = reg(&RC, instruction);
+        self.registers[a] =
+            ((self.registers[b] as usize * self.registers[c] as usize) % usize::pow(2, 32)) as u32;
     }
 
     ///Division
     /// $r[A] := ($r[B] ÷ $r[C]) (integer division)
-    fn divide(&mut self, instruction: u32) {
-        let a = get(&RA, instruction);
-        let b = get(&RB, instruction);
-        let c = get(&RC, instruction);
-        self.registers[a as usize] = self.registers[b as usize] / self.registers[c as usize];
+    fn divide(&mut self, instruction: u32) -> Result<(), MachineError> {
+        let a = reg(&RA, instruction);
+        let b = reg(&RB, instruction);
+        let c = reg(&RC, instruction);
+        if self.registers[c] == 0 {
+            return Err(MachineError::DivisionByZero {
+                pc: self.program_counter as u32,
+            });
+        }
+        self.registers[a] = self.registers[b] / self.registers[c];
+        Ok(())
     }
     ///Bitwise nand
     /// $r[A] :=¬($r[B]∧$r[C])
     fn nand(&mut self, instruction: u32) {
-        let a = get(&RA, instruction);
-        let b = get(&RB, instruction);
-        let c = get(&RC, instruction);
+        let a = reg(&RA, instruction);
+        let b = reg(&RB, instruction);
+        let c = reg(&RC, instruction);
         // $r[A] := ($r[B] ÷ $r[C]) (integer division)
-        self.registers[a as usize] = !(self.registers[b as usize] & self.registers[c as usize]);
+        self.registers[a] = !(self.registers[b] & self.registers[c]);
     }
     ///Map segment
     /// # Task:
@@ -139,29 +529,65 @@ impl VirtualMachine {
     /// identify any currently mapped segment is placed
     /// in $r[B]. The new segment is mapped as
     ///$m[$r[B]].
-    fn map_segment(&mut self, instruction: u32, pool: &mut Vec<u32>) {
-        let b = get(&RB, instruction);
-        let c = get(&RC, instruction);
+    /// Large segments are allocated lazily. Fails instead
+    /// if the new segment would take the allocated words
+    /// past the memory limit.
+    fn map_segment(&mut self, instruction: u32) -> Result<(), MachineError> {
+        let b = reg(&RB, instruction);
+        let c = reg(&RC, instruction);
+        let new_segment = Arc::new(Segment::zeroed(self.registers[c] as usize));
+        self.reserve(new_segment.resident_words())?;
         //check pool
         // ● A segment will only ever be categorized as mapped or unmapped,
         // never both at the same time
-        let new_segment = vec![0; self.registers[c as usize] as usize];
-        if pool.len() > 0 {
-            let key = pool.pop().unwrap();
-            self.registers[b as usize] = key;
-            *self.memory.get_mut(&self.registers[b as usize]).unwrap() = new_segment;
+        let from_pool = !self.pool.is_empty();
+        if let Some(key) = self.pool.pop() {
+            self.registers[b] = key;
         } else {
             self.last_key += 1;
-            self.registers[b as usize] = self.last_key;
-            self.memory.insert(self.registers[b as usize], new_segment);
+            self.registers[b] = self.last_key;
         }
+        if self.history.is_some() {
+            self.changes.push(Change::Mapped {
+                id: self.registers[b],
+                from_pool,
+            });
+        }
+        if let Some(origins) = &mut self.origins {
+            origins.insert(self.registers[b], self.program_counter as u32);
+        }
+        self.stats.map(&new_segment);
+        self.memory.insert(self.registers[b], new_segment);
+        Ok(())
     }
     ///Unmap Segment
     ///The segment $m[$r[C]] is unmapped. Future Map Segment instructions may reuse the identifier $r[C].
-    fn unmap_segment(&mut self, instruction: u32, pool: &mut Vec<u32>) {
-        let c = get(&RC, instruction);
-
-        pool.push(self.registers[c as usize]);
+    /// Fails if $r[C] is 0 or not mapped.
+    fn unmap_segment(&mut self, instruction: u32) -> Result<(), MachineError> {
+        let c = reg(&RC, instruction);
+        let id = self.registers[c];
+        if id == 0 {
+            return Err(MachineError::UnmappedSegment {
+                pc: self.program_counter as u32,
+                segment: id,
+            });
+        }
+        self.segment(id)?;
+        let segment = self.memory.remove(&id).unwrap();
+        self.stats.unmap(&segment);
+        let origin = self
+            .origins
+            .as_mut()
+            .and_then(|origins| origins.remove(&id));
+        if self.history.is_some() {
+            self.changes.push(Change::Unmapped {
+                id,
+                segment,
+                origin,
+            });
+        }
+        self.pool.push(id);
+        Ok(())
     }
 
     ///Output
@@ -171,8 +597,8 @@ impl VirtualMachine {
     /// are allowed.
     fn output(&mut self, instruction: u32) {
         //Instruction will never output a value larger than 255.
-        let c = get(&RC, instruction);
-        print!("{}", self.registers[c as usize] as u8 as char);
+        let c = reg(&RC, instruction);
+        self.io.write_byte(self.registers[c] as u8);
     }
     ///Input
     /// # Task:
@@ -183,15 +609,13 @@ impl VirtualMachine {
     /// loaded with a full 32-bit word in which every bit
     /// is 1.
     fn input(&mut self, instruction: u32) {
-        let c = get(&RC, instruction);
-        //Proper reading in?
-        let x = std::io::stdin().bytes().next();
-        match x {
+        let c = reg(&RC, instruction);
+        match self.io.read_byte() {
             Some(x) => {
-                self.registers[c as usize] = x.unwrap() as u32;
+                self.registers[c] = x as u32;
             }
             None => {
-                self.registers[c as usize] = 4294967295;
+                self.registers[c] = 4294967295;
             }
         }
     }
@@ -203,22 +627,62 @@ impl VirtualMachine {
     /// The program counter is set to point to
     /// $m[0][$r[C]]. If $r[B]=0, the load program
     /// operation should be extremely quick, as this is
-    /// effectively a jump
-    fn load_program(&mut self, instruction: u32) {
-        let b = get(&RB, instruction);
-        let c = get(&RC, instruction);
+    /// effectively a jump. Otherwise the duplicate shares
+    /// storage with $m[$r[B]] until either is written.
+    fn load_program(&mut self, instruction: u32) -> Result<(), MachineError> {
+        let b = reg(&RB, instruction);
+        let c = reg(&RC, instruction);
         // ● M[0] will always be mapped throughout program, otherwise
         // program would crash.
-        if self.registers[b as usize] == 0 {
+        if self.registers[b] == 0 {
             //jump
-            self.program_counter = self.registers[c as usize] as i32 - 1;
+            self.program_counter = self.registers[c] as i32 - 1;
         } else {
-            let dupe = self.memory[&self.registers[b as usize]].clone();
-            *self.memory.get_mut(&0).unwrap() = dupe;
+            let dupe = Arc::clone(self.segment(self.registers[b])?);
+            let abandoned = self.replace_program(dupe);
+            if self.history.is_some() {
+                self.changes.push(Change::Program(abandoned));
+            }
+            if let Some(coverage) = &mut self.coverage {
+                coverage.start(&self.memory[&0]);
+            }
             // self.program_counter =
-            //     self.memory[&0][self.registers[c as usize] as usize];
+            //     self.memory[&0][self.registers[c] as usize];
 
-            self.program_counter = self.registers[c as usize] as i32 - 1;
+            self.program_counter = self.registers[c] as i32 - 1;
+        }
+        Ok(())
+    }
+    /// Makes `program` the new $m[0], returning the old one.
+    fn replace_program(&mut self, program: Arc<Segment>) -> Arc<Segment> {
+        self.stats.map(&program);
+        let abandoned = self.memory.insert(0, program).unwrap();
+        self.stats.unmap(&abandoned);
+        self.program_version += 1;
+        if let Some(superinstructions) = self.superinstructions.take() {
+            self.superinstructions = Some(self.superinstructions_after_load(superinstructions));
+        }
+        abandoned
+    }
+    /// Superinstructions of the new $m[0], given those of the one it replaced.
+    /// Storage that is shared or has a `Weak` to it is copied or moved by
+    /// `Arc::make_mut` before it is written, so storage at a cached address
+    /// still holds the words its sequences were found in.
+    fn superinstructions_after_load(&mut self, replaced: Superinstructions) -> Superinstructions {
+        // Reloading the storage already running, as a loop might
+        if self.fused_from.as_ptr() == Arc::as_ptr(&self.memory[&0]) {
+            return replaced;
+        }
+        let from = std::mem::take(&mut self.fused_from);
+        if from.strong_count() > 0 {
+            self.fused.insert(from.as_ptr() as usize, (from, replaced));
+        }
+        self.fused.retain(|_, (from, _)| from.strong_count() > 0);
+        let program = &self.memory[&0];
+        self.fused_from = Arc::downgrade(program);
+        match self.fused.remove(&(Arc::as_ptr(program) as usize)) {
+            Some((_, superinstructions)) => superinstructions,
+            None => Superinstructions::new(program),
         }
     }
     ///Load value
@@ -227,86 +691,757 @@ impl VirtualMachine {
     /// opcode describe a single register A. The remaining 25 bits indicate a value,
     /// which is loaded into $r[A].
     fn load_value(&mut self, instruction: u32) {
-        let a = get(&RL, instruction);
+        let a = reg(&RL, instruction);
         let v = get(&VL, instruction);
-        self.registers[a as usize] = v;
+        self.registers[a] = v;
     }
 
-    ///Runs the given program
-    pub fn run_program(&mut self) {
-        //Contains unmapped segment identifiers
-        let mut pool: Vec<u32> = vec![];
-        // Loops through execution cycle.
+    ///Runs the given program until it halts or faults
+    pub fn run_program(&mut self) -> Result<(), MachineError> {
+        while self.run_for(u64::MAX)? {}
+        Ok(())
+    }
 
-        loop {
-            let instruction = self.memory[&0][self.program_counter as usize];
-            // Handles instructions similar to lab
-            match get(&OP, instruction) {
-                o if o == Opcode::CMov as u32 => {
-                    self.conditional_move(instruction);
-                }
-                o if o == Opcode::Load as u32 => {
-                    self.load_into(instruction);
-                }
-                o if o == Opcode::Store as u32 => {
-                    self.store(instruction);
-                }
-                o if o == Opcode::Add as u32 => {
-                    self.add(instruction);
-                }
-                o if o == Opcode::Mul as u32 => {
-                    self.multiply(instruction);
-                }
-                o if o == Opcode::Div as u32 => {
-                    self.divide(instruction);
-                }
-                o if o == Opcode::Nand as u32 => {
-                    self.nand(instruction);
+    ///Runs at most `steps` instructions
+    /// Returns false once the program halts and true if it is still
+    /// running, so a scheduler can take turns between machines. A
+    /// superinstruction counts as the instructions it fuses.
+    pub fn run_for(&mut self, steps: u64) -> Result<bool, MachineError> {
+        let mut remaining = steps;
+        while remaining > 0 {
+            // Superinstructions are skipped while logging, so every step can be undone or listed.
+            if let (Some(superinstructions), None, None) =
+                (&self.superinstructions, &self.history, &self.trail)
+            {
+                if let Some(fused) = superinstructions
+                    .at(self.program_counter as usize)
+                    .filter(|fused| fused.len() as u64 <= remaining)
+                {
+                    if let Some(coverage) = &mut self.coverage {
+                        for address in 0..fused.len() {
+                            coverage.hit(self.program_counter as usize + address);
+                        }
+                    }
+                    self.run_superinstruction(fused)?;
+                    #[cfg(feature = "extensions")]
+                    {
+                        self.executed += fused.len() as u64;
+                    }
+                    self.program_counter += 1;
+                    remaining -= fused.len() as u64;
+                    continue;
                 }
-                o if o == Opcode::Halt as u32 => {
-                    return;
-                }
-                o if o == Opcode::MapSegment as u32 => {
-                    self.map_segment(instruction, &mut pool);
-                }
-                o if o == Opcode::UnmapSegment as u32 => {
-                    self.unmap_segment(instruction, &mut pool);
-                }
-                o if o == Opcode::Output as u32 => {
-                    self.output(instruction);
+            }
+            if !self.step()? {
+                return Ok(false);
+            }
+            remaining -= 1;
+        }
+        Ok(true)
+    }
+
+    ///Runs the instruction at the program counter
+    /// Returns false, leaving the program counter on the instruction, if it was Halt.
+    /// On a fault the machine is left as it was before the instruction.
+    pub fn step(&mut self) -> Result<bool, MachineError> {
+        let pc = self.program_counter;
+        let instruction = self.memory[&0]
+            .get(pc as u32 as usize)
+            .ok_or(MachineError::ProgramCounterOutOfBounds { pc: pc as u32 })?;
+        if let Some(trail) = &mut self.trail {
+            trail.push(pc as u32, instruction);
+        }
+        if self.history.is_none() {
+            return self.execute(instruction);
+        }
+        let registers = self.registers;
+        self.changes.clear();
+        let running = self.execute(instruction)?;
+        if running {
+            let changes = std::mem::take(&mut self.changes);
+            self.history.as_mut().unwrap().push(Step {
+                program_counter: pc,
+                instruction,
+                registers,
+                changes,
+            });
+        }
+        Ok(running)
+    }
+
+    /// Runs `instruction`, fetched from the program counter.
+    fn execute(&mut self, instruction: u32) -> Result<bool, MachineError> {
+        if let Some(coverage) = &mut self.coverage {
+            coverage.hit(self.program_counter as usize);
+        }
+        // Handles instructions similar to lab
+        match get(&OP, instruction) {
+            o if o == Opcode::CMov as u32 => {
+                self.conditional_move(instruction);
+            }
+            o if o == Opcode::Load as u32 => {
+                self.load_into(instruction)?;
+            }
+            o if o == Opcode::Store as u32 => {
+                self.store(instruction)?;
+            }
+            o if o == Opcode::Add as u32 => {
+                self.add(instruction);
+            }
+            o if o == Opcode::Mul as u32 => {
+                self.multiply(instruction);
+            }
+            o if o == Opcode::Div as u32 => {
+                self.divide(instruction)?;
+            }
+            o if o == Opcode::Nand as u32 => {
+                self.nand(instruction);
+            }
+            o if o == Opcode::Halt as u32 => {
+                return Ok(false);
+            }
+            o if o == Opcode::MapSegment as u32 => {
+                self.map_segment(instruction)?;
+            }
+            o if o == Opcode::UnmapSegment as u32 => {
+                self.unmap_segment(instruction)?;
+            }
+            o if o == Opcode::Output as u32 => {
+                self.output(instruction);
+            }
+            o if o == Opcode::Input as u32 => {
+                self.input(instruction);
+            }
+            o if o == Opcode::LoadProgram as u32 => {
+                self.load_program(instruction)?;
+            }
+            o if o == Opcode::LoadValue as u32 => {
+                self.load_value(instruction);
+            }
+            #[cfg(feature = "extensions")]
+            o @ (14 | 15) => {
+                self.extension(o, instruction)?;
+            }
+
+            _ => {}
+        }
+        self.program_counter += 1;
+        #[cfg(feature = "extensions")]
+        {
+            self.executed += 1;
+        }
+        Ok(true)
+    }
+
+    /// Runs the handler installed for extension opcode `opcode`, if any.
+    #[cfg(feature = "extensions")]
+    fn extension(&mut self, opcode: u32, instruction: u32) -> Result<(), MachineError> {
+        let pc = self.program_counter as u32;
+        let handler = match self.extensions.get(opcode) {
+            Some(handler) => handler,
+            None => return Ok(()),
+        };
+        let mut context = Context {
+            registers: &mut self.registers,
+            pc,
+            executed: self.executed,
+        };
+        handler
+            .execute(&mut context, instruction)
+            .map_err(|message| MachineError::Extension { pc, message })
+    }
+
+    ///Runs a superinstruction starting at the program counter
+    /// Leaves the program counter on its last instruction.
+    fn run_superinstruction(&mut self, fused: Superinstruction) -> Result<(), MachineError> {
+        match fused {
+            Superinstruction::Constant {
+                x,
+                x_value,
+                y,
+                y_value,
+                a,
+                value,
+            } => {
+                self.registers[x] = x_value;
+                self.registers[y] = y_value;
+                self.registers[a] = value;
+                self.program_counter += 2;
+            }
+            Superinstruction::Jump {
+                t,
+                target,
+                load_program,
+            } => {
+                self.registers[t] = target;
+                self.program_counter += 1;
+                self.load_program(load_program)?;
+            }
+            Superinstruction::Branch {
+                t,
+                t_value,
+                f,
+                f_value,
+                c,
+                load_program,
+            } => {
+                self.registers[t] = t_value;
+                self.registers[f] = f_value;
+                if self.registers[c] != 0 {
+                    self.registers[f] = t_value;
                 }
-                o if o == Opcode::Input as u32 => {
-                    self.input(instruction);
+                self.program_counter += 3;
+                self.load_program(load_program)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Starts logging enough of each step to undo it, keeping the last `capacity` steps
+    /// Output cannot be taken back, and input that is stepped back over is not read again.
+    pub fn enable_history(&mut self, capacity: usize) {
+        self.history = Some(History::new(capacity));
+    }
+    ///Starts keeping the addresses and words of the last `capacity` instructions executed
+    pub fn enable_trail(&mut self, capacity: usize) {
+        self.trail = Some(Trail::new(capacity));
+    }
+    ///Addresses and words of the last instructions executed, oldest first
+    /// Empty unless the trail was enabled. Once the machine stops, the
+    /// last is the instruction that halted or failed.
+    pub fn trail(&self) -> Vec<(u32, u32)> {
+        self.trail.as_ref().map_or(vec![], Trail::entries)
+    }
+    ///Number of steps that can currently be undone
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, History::len)
+    }
+    ///Undoes the most recent step, returning false if there is none
+    pub fn step_back(&mut self) -> bool {
+        let step = match self.history.as_mut().and_then(History::pop) {
+            Some(step) => step,
+            None => return false,
+        };
+        for change in step.changes.into_iter().rev() {
+            match change {
+                Change::Word {
+                    segment,
+                    index,
+                    old,
+                } => self.write_word(segment, index, old),
+                Change::Mapped { id, from_pool } => {
+                    let segment = self.memory.remove(&id).unwrap();
+                    self.stats.unmap(&segment);
+                    if let Some(origins) = &mut self.origins {
+                        origins.remove(&id);
+                    }
+                    if from_pool {
+                        self.pool.push(id);
+                    } else {
+                        self.last_key -= 1;
+                    }
                 }
-                o if o == Opcode::LoadProgram as u32 => {
-                    self.load_program(instruction);
+                Change::Unmapped {
+                    id,
+                    segment,
+                    origin,
+                } => {
+                    if let (Some(origins), Some(pc)) = (&mut self.origins, origin) {
+                        origins.insert(id, pc);
+                    }
+                    self.pool.pop();
+                    self.stats.map(&segment);
+                    self.memory.insert(id, segment);
                 }
-                o if o == Opcode::LoadValue as u32 => {
-                    self.load_value(instruction);
+                Change::Program(program) => {
+                    self.replace_program(program);
                 }
-
-                _ => {}
             }
-            self.program_counter = self.program_counter + 1;
         }
+        self.registers = step.registers;
+        self.program_counter = step.program_counter;
+        true
+    }
+    ///Steps back until the instruction at `pc` is next to run
+    /// Returns false if the history runs out first.
+    pub fn run_back_to(&mut self, pc: u32) -> bool {
+        while self.step_back() {
+            if self.program_counter as u32 == pc {
+                return true;
+            }
+        }
+        false
+    }
+    ///Steps back to just before the last instruction that wrote `location`
+    /// Returns false if the history runs out first.
+    pub fn run_back_to_write(&mut self, location: Location) -> bool {
+        while let Some(step) = self.history.as_ref().and_then(History::last) {
+            let wrote = step.writes(location);
+            self.step_back();
+            if wrote {
+                return true;
+            }
+        }
+        false
     }
 }
 
-fn mask(bits: u32) -> u32 {
+const fn mask(bits: u32) -> u32 {
     (1 << bits) - 1
 }
 
 type Umi = u32;
 
-pub fn get(field: &Field, instruction: Umi) -> u32 {
+pub const fn get(field: &Field, instruction: Umi) -> u32 {
     (instruction >> field.lsb) & mask(field.width)
 }
 
+///Places `value` in `field` of an otherwise zero word, the inverse of `get`
+pub const fn put(field: &Field, value: u32) -> Umi {
+    (value & mask(field.width)) << field.lsb
+}
+
+///Register number in `field` of `instruction`
+/// Always below `REGISTERS`, so indexing the register file with it needs
+/// no bounds check.
+pub const fn reg(field: &Field, instruction: Umi) -> usize {
+    get(field, instruction) as usize % REGISTERS
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::disasm::{encode, Instruction};
+    use crate::segment::PAGE_WORDS;
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn memory_stats_track_map_and_unmap() {
+        let mut asm = Assembler::new();
+        asm.loadv(0, 100);
+        asm.map(1, 0);
+        asm.map(2, 0);
+        asm.unmap(1);
+        asm.halt();
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(asm.finish());
+        vm.run_program().unwrap();
+        let stats = vm.memory_stats();
+        assert_eq!(stats.live_words, 105);
+        assert_eq!(stats.live_segments, 2);
+        assert_eq!(stats.peak_words, 205);
+        assert_eq!(stats.peak_segments, 3);
+    }
+
+    #[test]
+    fn huge_map_is_lazy() {
+        let mut asm = Assembler::new();
+        asm.nand(0, 0, 0); // r0 := 2^32 - 1
+        asm.map(1, 0);
+        asm.loadv(2, 42);
+        asm.store(1, 2, 2);
+        asm.load(3, 1, 2);
+        asm.load(4, 1, 5);
+        asm.halt();
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(asm.finish());
+        vm.run_program().unwrap();
+        assert_eq!(vm.registers[3], 42);
+        assert_eq!(vm.registers[4], 0);
+        let stats = vm.memory_stats();
+        assert_eq!(stats.live_words, u32::MAX as usize + 7);
+        assert_eq!(stats.resident_words, PAGE_WORDS + 7);
+    }
+
+    #[test]
+    fn lazy_pages_respect_memory_limit() {
+        let mut vm = VirtualMachine::new();
+        vm.set_memory_limit(Some(PAGE_WORDS));
+        let mut asm = Assembler::new();
+        asm.nand(0, 0, 0); // r0 := 2^32 - 1
+        asm.map(1, 0);
+        asm.loadv(2, 42);
+        asm.store(1, 2, 2);
+        asm.halt();
+        vm.initialize_machine(asm.finish());
+        match vm.run_program() {
+            Err(MachineError::AllocationLimit { pc, requested, .. }) => {
+                assert_eq!(pc, 3);
+                assert_eq!(requested, PAGE_WORDS);
+            }
+            other => panic!("expected allocation limit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_program_shares_until_written() {
+        let mut asm = Assembler::new();
+        asm.loadv(0, 100);
+        asm.map(1, 0);
+        asm.halt();
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(asm.finish());
+        vm.run_program().unwrap();
+        let halt = encode(Instruction::Halt);
+        Arc::make_mut(vm.memory.get_mut(&1).unwrap()).set(0, halt);
+        vm.registers[2] = 0;
+        vm.load_program(encode(Instruction::LoadProgram { b: 1, c: 2 }))
+            .unwrap();
+        assert!(Arc::ptr_eq(&vm.memory[&0], &vm.memory[&1]));
+        let stats = vm.memory_stats();
+        assert_eq!(stats.live_words, 200);
+        assert_eq!(stats.resident_words, 100);
+
+        vm.registers[3] = 1;
+        vm.registers[4] = 5;
+        vm.store(encode(Instruction::Store { a: 1, b: 3, c: 4 }))
+            .unwrap();
+        assert!(!Arc::ptr_eq(&vm.memory[&0], &vm.memory[&1]));
+        assert_eq!(vm.memory[&0][0], halt);
+        assert_eq!(vm.memory[&0][1], 0);
+        assert_eq!(vm.memory[&1][1], 5);
+        assert_eq!(vm.memory_stats().resident_words, 200);
+    }
+
+    fn run_both(program: Vec<u32>, registers: [u32; 8]) -> ([u32; 8], [u32; 8]) {
+        let mut results = vec![];
+        for optimize in [false, true] {
+            let mut vm = VirtualMachine::new();
+            vm.initialize_machine(program.clone());
+            vm.registers = registers;
+            if optimize {
+                vm.enable_superinstructions();
+                assert!(vm.superinstruction_count().unwrap() > 0);
+            }
+            vm.run_program().unwrap();
+            results.push(vm.registers);
+        }
+        (results.remove(0), results.remove(0))
+    }
+
+    #[test]
+    fn superinstructions_match_plain_execution() {
+        let mut asm = Assembler::new();
+        asm.nand(6, 0, 0); // r6 := -1
+        asm.loadv(1, 5);
+        asm.loadv(7, 1);
+        asm.add(1, 1, 6); // address 3, looped back to while r1 != 0
+        asm.add(5, 5, 7);
+        asm.loadv(3, 3);
+        asm.loadv(4, 9);
+        asm.cmov(4, 3, 1);
+        asm.loadp(0, 4);
+        asm.loadv(2, 12);
+        asm.loadv(3, 10);
+        asm.nand(2, 2, 3);
+        asm.halt();
+        let (plain, optimized) = run_both(asm.finish(), [0; 8]);
+        assert_eq!(plain, optimized);
+        assert_eq!(optimized[5], 5);
+        assert_eq!(optimized[2], !(12 & 10));
+    }
+
+    #[test]
+    fn superinstructions_fall_back_after_writes() {
+        let mut asm = Assembler::new();
+        asm.store(0, 5, 6);
+        asm.loadv(3, 4);
+        asm.loadp(0, 3);
+        asm.loadv(1, 1);
+        asm.halt();
+        // Rewrites the jump target to 3 before it runs.
+        let mut registers = [0; 8];
+        registers[5] = 1;
+        registers[6] = encode(Instruction::LoadValue { a: 3, value: 3 });
+        let (plain, optimized) = run_both(asm.finish(), registers);
+        assert_eq!(plain, optimized);
+        assert_eq!(optimized[1], 1);
+    }
+
+    #[test]
+    fn superinstructions_are_reused_only_for_unchanged_storage() {
+        let mut asm = Assembler::new();
+        asm.loadv(1, 5);
+        asm.loadv(2, 3);
+        asm.nand(3, 1, 2);
+        asm.halt();
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(vec![encode(Instruction::Halt)]);
+        vm.enable_superinstructions();
+        let original = Arc::clone(&vm.memory[&0]);
+        let fused = Arc::new(Segment::from(asm.finish()));
+        vm.stats.map(&fused);
+        vm.memory.insert(1, fused);
+        for _ in 0..2 {
+            vm.replace_program(Arc::clone(&vm.memory[&1]));
+            assert_eq!(vm.superinstruction_count(), Some(1));
+            vm.replace_program(Arc::clone(&original));
+            assert_eq!(vm.superinstruction_count(), Some(0));
+        }
+        assert_eq!(vm.fused.len(), 1);
+        // Written while not $m[0], so its sequences are found again
+        assert!(vm.poke(1, 2, encode(Instruction::Nand { a: 3, b: 1, c: 4 })));
+        vm.replace_program(Arc::clone(&vm.memory[&1]));
+        assert_eq!(vm.superinstruction_count(), Some(0));
+    }
+
+    #[test]
+    fn coverage_counts_each_generation() {
+        let mut asm = Assembler::new();
+        asm.map(1, 0);
+        asm.store(1, 4, 2);
+        asm.loadp(1, 4);
+        asm.halt(); // never runs
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(asm.finish());
+        vm.registers[0] = 2;
+        vm.registers[2] = encode(Instruction::Halt);
+        vm.enable_coverage();
+        vm.run_program().unwrap();
+        let coverage = vm.coverage().unwrap();
+        assert_eq!(coverage.generations.len(), 2);
+        assert_eq!(coverage.generations[0].hits, vec![1; 3]);
+        assert_eq!(coverage.generations[0].never_executed(), vec![(3, 3)]);
+        assert_eq!(coverage.generations[1].hits, vec![1]);
+        assert_eq!(coverage.generations[1].program.len(), 2);
+    }
+
+    #[test]
+    fn coverage_leaves_memory_use_unchanged() {
+        // Copies itself into a new segment, reloads from it and unmaps it, 20 times
+        let mut asm = Assembler::new();
+        let (top, copy, copied, reloaded) = (asm.label(), asm.label(), asm.label(), asm.label());
+        let (done, end) = (asm.label(), asm.label());
+        asm.loadv(1, 20);
+        asm.nand(2, 0, 0);
+        asm.bind(top);
+        asm.loadv_label(5, end);
+        asm.map(6, 5);
+        asm.bind(copy);
+        asm.add(5, 5, 2);
+        asm.load(7, 0, 5);
+        asm.store(6, 5, 7);
+        asm.branch(5, copy, copied, 0, [3, 4]);
+        asm.bind(copied);
+        asm.loadv_label(7, reloaded);
+        asm.loadp(6, 7);
+        asm.bind(reloaded);
+        asm.unmap(6);
+        asm.add(1, 1, 2);
+        asm.branch(1, top, done, 0, [3, 4]);
+        asm.bind(done);
+        asm.halt();
+        asm.bind(end);
+        let program = asm.finish();
+        let len = program.len();
+        let mut vm = VirtualMachine::new();
+        vm.set_memory_limit(Some(3 * len));
+        vm.initialize_machine(program);
+        vm.enable_coverage();
+        assert_eq!(vm.run_program(), Ok(()));
+        assert_eq!(vm.memory_stats().resident_words, len);
+        let coverage = vm.coverage().unwrap();
+        assert_eq!(coverage.generations.len(), 21);
+        // Generations running the same words share one copy
+        assert!(Arc::ptr_eq(
+            &coverage.generations[0].program,
+            &coverage.generations[20].program
+        ));
+    }
+
+    type Snapshot = (
+        [u32; REGISTERS],
+        i32,
+        Vec<(u32, Segment)>,
+        Vec<u32>,
+        u32,
+        usize,
+    );
+    fn snapshot(vm: &VirtualMachine) -> Snapshot {
+        let mut memory: Vec<(u32, Segment)> = vm
+            .memory
+            .iter()
+            .map(|(&id, segment)| (id, (**segment).clone()))
+            .collect();
+        memory.sort_by_key(|&(id, _)| id);
+        (
+            vm.registers,
+            vm.program_counter,
+            memory,
+            vm.pool.clone(),
+            vm.last_key,
+            vm.memory_stats().resident_words,
+        )
+    }
+
+    #[test]
+    fn step_back_undoes_every_kind_of_change() {
+        let mut asm = Assembler::new();
+        asm.loadv(0, 3);
+        asm.map(1, 0);
+        asm.map(2, 0);
+        asm.loadv(3, 7);
+        asm.store(1, 4, 3);
+        asm.unmap(2);
+        asm.map(5, 0); // reuses id 2
+        asm.loadv(6, 28);
+        asm.loadp(1, 4);
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(asm.finish());
+        vm.enable_history(100);
+        let mut snapshots = vec![snapshot(&vm)];
+        for _ in 0..9 {
+            assert!(vm.step().unwrap());
+            snapshots.push(snapshot(&vm));
+        }
+        // $m[0] is now [7, 0, 0], and 7 decodes as a no-op cmov.
+        assert_eq!(vm.memory[&0][0], 7);
+        assert_eq!(vm.history_len(), 9);
+        snapshots.pop();
+        while let Some(expected) = snapshots.pop() {
+            assert!(vm.step_back());
+            assert_eq!(snapshot(&vm), expected);
+        }
+        assert!(!vm.step_back());
+    }
+
+    #[test]
+    fn rewinds_from_a_fault_to_the_last_write() {
+        let mut asm = Assembler::new();
+        asm.loadv(1, 4);
+        asm.loadv(2, 2);
+        asm.nand(1, 2, 2);
+        asm.loadv(3, 5);
+        asm.div(1, 3, 0);
+        asm.halt();
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(asm.finish());
+        vm.enable_history(2);
+        assert_eq!(
+            vm.run_program(),
+            Err(MachineError::DivisionByZero { pc: 4 })
+        );
+        assert_eq!(vm.program_counter, 4);
+        assert!(vm.run_back_to_write(Location::Register(1)));
+        assert_eq!(vm.program_counter, 2);
+        assert_eq!(vm.registers[1], 4);
+        // Only two steps were kept.
+        assert!(!vm.run_back_to(0));
+    }
+
+    #[test]
+    fn faults_leave_the_machine_unchanged() {
+        let cases = [
+            (
+                Instruction::Load { a: 0, b: 1, c: 0 },
+                MachineError::UnmappedSegment { pc: 0, segment: 1 },
+            ),
+            (
+                Instruction::Store { a: 0, b: 1, c: 0 },
+                MachineError::OutOfBounds {
+                    pc: 0,
+                    segment: 0,
+                    index: 1,
+                },
+            ),
+            (
+                Instruction::UnmapSegment { c: 0 },
+                MachineError::UnmappedSegment { pc: 0, segment: 0 },
+            ),
+            (
+                Instruction::LoadProgram { b: 1, c: 0 },
+                MachineError::UnmappedSegment { pc: 0, segment: 1 },
+            ),
+        ];
+        for (instruction, fault) in cases {
+            let mut vm = VirtualMachine::new();
+            vm.initialize_machine(vec![encode(instruction)]);
+            vm.registers[1] = 1;
+            let before = snapshot(&vm);
+            assert_eq!(vm.step(), Err(fault));
+            assert_eq!(snapshot(&vm), before);
+        }
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(vec![0]);
+        assert_eq!(
+            vm.run_program(),
+            Err(MachineError::ProgramCounterOutOfBounds { pc: 1 })
+        );
+    }
+
+    #[test]
+    fn leak_check_lists_segments_left_mapped() {
+        let mut asm = Assembler::new();
+        asm.loadv(0, 10);
+        asm.map(1, 0);
+        asm.map(2, 0);
+        asm.map(3, 1);
+        asm.unmap(2);
+        asm.halt();
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(asm.finish());
+        vm.enable_leak_check();
+        vm.run_program().unwrap();
+        assert_eq!(
+            vm.leaks(),
+            vec![
+                Leak {
+                    segment: 1,
+                    words: 10,
+                    pc: 1
+                },
+                Leak {
+                    segment: 3,
+                    words: 1,
+                    pc: 3
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_limit_can_be_lowered() {
+        let mut vm = VirtualMachine::new();
+        vm.set_memory_limit(Some(64));
+        let mut asm = Assembler::new();
+        asm.loadv(0, 64);
+        asm.map(1, 0);
+        asm.halt();
+        vm.initialize_machine(asm.finish());
+        assert!(vm.run_program().is_err());
+    }
+
+    #[test]
+    fn register_decoders_match_fields() {
+        let mut word: u32 = 0x9e37_79b9;
+        for _ in 0..10_000 {
+            for field in [&RA, &RB, &RC, &RL] {
+                assert!(reg(field, word) < REGISTERS);
+                assert_eq!(reg(field, word), get(field, word) as usize);
+            }
+            word = word.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        }
+        const DECODED: usize = reg(&RA, 0b101 << 6);
+        assert_eq!(DECODED, 5);
+    }
+
+    #[test]
+    fn reinitializing_clears_registers() {
+        let mut vm = VirtualMachine::new();
+        assert_eq!(vm.registers, [0; REGISTERS]);
+        let halt = encode(Instruction::Halt);
+        vm.initialize_machine(vec![
+            encode(Instruction::LoadValue { a: 3, value: 42 }),
+            halt,
+        ]);
+        vm.run_program().unwrap();
+        assert_eq!(vm.registers, [0, 0, 0, 42, 0, 0, 0, 0]);
+        vm.initialize_machine(vec![halt]);
+        assert_eq!(vm.registers, [0; REGISTERS]);
+    }
 }
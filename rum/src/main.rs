@@ -1,19 +1,449 @@
+use rum::batch;
+use rum::disasm::decode;
+use rum::dump::{self, Dump};
+use rum::engine::{self, CrossCheckError, Kind};
+#[cfg(feature = "extensions")]
+use rum::extension;
+use rum::gdb;
+use rum::io::StdIo;
 use rum::machine;
 use rum::rumload;
-use std::collections::HashMap;
+use rum::script::{self, ScriptError};
+use rum::symbols::Symbols;
+#[cfg(target_os = "linux")]
+use rum::terminal::RawMode;
+use rum::transcript::{self, RecordError, ReplayError, Transcript};
 use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let argnum = args.len();
-    assert!(argnum == 2);
-    let filename = args.iter().nth(1).unwrap();
+    if args.get(1).map(String::as_str) == Some("batch") {
+        run_batch(&args[2..]);
+    }
+    let mut filename = None;
+    let mut stats = false;
+    let mut optimize = false;
+    let mut coverage = None;
+    let mut leak_check = false;
+    let mut trace = false;
+    let mut script = None;
+    let mut record = None;
+    let mut dump_on_fault = None;
+    let mut gdb_address = None;
+    let mut dump_contents = false;
+    let mut replay = None;
+    let mut engine = Kind::Interp;
+    let mut cross_check = None;
+    #[cfg(target_os = "linux")]
+    let mut raw = false;
+    #[cfg(feature = "extensions")]
+    let mut host_calls = false;
+    let mut memory_limit = Some(machine::DEFAULT_MEMORY_LIMIT);
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--stats" => stats = true,
+            "--optimize" => optimize = true,
+            "--leak-check" => leak_check = true,
+            "--trace" => trace = true,
+            // Input gets each keypress at once, without echo
+            #[cfg(target_os = "linux")]
+            "--raw" => raw = true,
+            // Opcode 14 calls the host services of `extension::standard`
+            #[cfg(feature = "extensions")]
+            "--host-calls" => host_calls = true,
+            "--coverage" => coverage = Some(rest.next().unwrap_or_else(|| usage())),
+            "--script" => script = Some(rest.next().unwrap_or_else(|| usage())),
+            "--record" => record = Some(rest.next().unwrap_or_else(|| usage())),
+            "--replay" => replay = Some(rest.next().unwrap_or_else(|| usage())),
+            // Writes BASE.json and BASE.umdump if the machine faults
+            "--dump-on-fault" => dump_on_fault = Some(rest.next().unwrap_or_else(|| usage())),
+            "--dump-contents" => dump_contents = true,
+            "--gdb" => gdb_address = Some(rest.next().unwrap_or_else(|| usage())),
+            "--engine" => {
+                engine = rest
+                    .next()
+                    .and_then(|name| Kind::parse(name))
+                    .unwrap_or_else(|| usage())
+            }
+            // Runs the program on this engine as well, comparing them after every instruction
+            "--cross-check" => {
+                cross_check = Some(
+                    rest.next()
+                        .and_then(|name| Kind::parse(name))
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            // Cap on allocated guest memory in words, 0 for no limit
+            "--memory-limit" => {
+                let words: usize = rest
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| usage());
+                memory_limit = if words == 0 { None } else { Some(words) };
+            }
+            _ if filename.is_none() => filename = Some(arg.as_str()),
+            _ => usage(),
+        }
+    }
+    let filename = filename.unwrap_or_else(|| usage());
+    // Each of these decides how the program is run, so only one can be given
+    let modes = [
+        script.is_some(),
+        record.is_some(),
+        replay.is_some(),
+        gdb_address.is_some(),
+        trace,
+    ];
+    if modes.iter().filter(|&&mode| mode).count() > 1 {
+        usage();
+    }
+    // Other engines run the bare program, without the instrumentation of the interpreter
+    let bare = engine != Kind::Interp || cross_check.is_some();
+    let instrumented = stats
+        || optimize
+        || coverage.is_some()
+        || leak_check
+        || dump_on_fault.is_some()
+        || modes.contains(&true);
+    #[cfg(target_os = "linux")]
+    let instrumented = instrumented || raw;
+    #[cfg(feature = "extensions")]
+    let instrumented = instrumented || host_calls;
+    if bare && instrumented {
+        usage();
+    }
+    let replay = replay.map(|path| {
+        File::open(path)
+            .and_then(|file| Transcript::read(&mut io::BufReader::new(file)))
+            .unwrap_or_else(|error| {
+                eprintln!("rum: {}: {}", path, error);
+                process::exit(1);
+            })
+    });
+    // Opened before the run so a bad path is reported before any input is consumed
+    let record = record.map(|path| {
+        let file = File::create(path).unwrap_or_else(|error| {
+            eprintln!("rum: {}: {}", path, error);
+            process::exit(1);
+        });
+        (path, file)
+    });
+    let script = script.map(|path| {
+        fs::read_to_string(path)
+            .map_err(|error| format!("{}: {}", path, error))
+            .and_then(|text| script::parse(&text))
+            .unwrap_or_else(|error| {
+                eprintln!("rum: {}", error);
+                process::exit(1);
+            })
+    });
     let program = rumload::load(Some(filename));
-    let mut vm = machine::VirtualMachine {
-        registers: vec![],
-        memory: HashMap::new(),
-        program_counter: 0,
-        last_key: 0,
-    };
+    let symbols = rumload::load_symbols(filename)
+        .unwrap_or_else(|error| {
+            eprintln!("rum: ignoring symbols of {}: {}", filename, error);
+            None
+        })
+        .unwrap_or_default();
+    if bare {
+        run_engines(engine, cross_check, program, memory_limit, &symbols);
+        return;
+    }
+    let mut vm = machine::VirtualMachine::new();
+    vm.set_memory_limit(memory_limit);
     vm.initialize_machine(program);
-    vm.run_program();
+    if optimize {
+        vm.enable_superinstructions();
+    }
+    if coverage.is_some() {
+        vm.enable_coverage();
+    }
+    if leak_check {
+        vm.enable_leak_check();
+    }
+    if dump_on_fault.is_some() {
+        vm.enable_trail(dump::DEFAULT_TRAIL);
+    }
+    #[cfg(feature = "extensions")]
+    if host_calls {
+        vm.extensions()
+            .install(extension::HOST_CALL, Box::new(extension::standard()));
+    }
+    #[cfg(target_os = "linux")]
+    let raw = raw.then(|| {
+        RawMode::enable().unwrap_or_else(|error| {
+            eprintln!("rum: --raw: {}", error);
+            process::exit(1);
+        })
+    });
+    let result = if let Some(commands) = script {
+        match script::run(&mut vm, commands, Box::new(StdIo)) {
+            Err(ScriptError::Machine(error)) => Err(error),
+            Err(error) => {
+                #[cfg(target_os = "linux")]
+                drop(raw);
+                eprintln!("rum: {}", error);
+                process::exit(1);
+            }
+            Ok(()) => Ok(()),
+        }
+    } else if let Some((path, file)) = record {
+        let out = Box::new(BufWriter::new(file));
+        match transcript::record(&mut vm, Box::new(StdIo), out) {
+            Err(RecordError::Machine(error)) => Err(error),
+            Err(RecordError::Io(error)) => {
+                #[cfg(target_os = "linux")]
+                drop(raw);
+                eprintln!("rum: {}: {}", path, error);
+                process::exit(1);
+            }
+            Ok(()) => Ok(()),
+        }
+    } else if let Some(recorded) = replay {
+        match transcript::replay(&mut vm, &recorded, Box::new(StdIo)) {
+            Err(ReplayError::Machine(error)) => Err(error),
+            Err(error) => {
+                #[cfg(target_os = "linux")]
+                drop(raw);
+                eprintln!("rum: {}", error);
+                process::exit(1);
+            }
+            Ok(()) => Ok(()),
+        }
+    } else if let Some(address) = gdb_address {
+        debug(&mut vm, address);
+        Ok(())
+    } else if trace {
+        run_traced(&mut vm, &symbols)
+    } else {
+        vm.run_program()
+    };
+    #[cfg(target_os = "linux")]
+    drop(raw);
+    if stats {
+        let usage = vm.memory_stats();
+        eprintln!(
+            "peak memory: {} words ({} bytes) in {} segments, {} words ({} bytes) allocated",
+            usage.peak_words,
+            usage.peak_words * 4,
+            usage.peak_segments,
+            usage.peak_resident_words,
+            usage.peak_resident_words * 4
+        );
+    }
+    if leak_check && result.is_ok() {
+        report_leaks(&vm.leaks(), &symbols, vm.program_version());
+    }
+    if let (Some(path), Some(recorded)) = (coverage, vm.coverage()) {
+        let written = File::create(path).and_then(|file| {
+            let mut out = BufWriter::new(file);
+            recorded.write(&mut out)?;
+            out.flush()
+        });
+        if let Err(error) = written {
+            eprintln!("rum: {}: {}", path, error);
+            // A fault is still reported below, and exits the same way
+            if result.is_ok() {
+                process::exit(1);
+            }
+        }
+    }
+    if let Err(error) = result {
+        eprintln!("rum: {}", error);
+        if !symbols.is_empty() {
+            let at = describe(&symbols, error.pc(), vm.program_version());
+            eprintln!("    at {}", at);
+        }
+        if let Some(base) = dump_on_fault {
+            let dump = Dump::capture(&vm, &error, dump_contents);
+            match write_dump(&dump, base) {
+                Ok(()) => eprintln!("    machine dumped to {0}.json and {0}.umdump", base),
+                Err(error) => eprintln!("rum: {}", error),
+            }
+        }
+        process::exit(1);
+    }
+}
+
+/// Runs every case of a manifest and reports how they went, exiting 1 if any did not pass.
+fn run_batch(args: &[String]) -> ! {
+    let mut manifest = None;
+    let mut jobs = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut limits = batch::Limits::default();
+    let mut junit = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--jobs" => jobs = value().parse().unwrap_or_else(|_| usage()),
+            "--max-steps" => limits.max_steps = Some(value().parse().unwrap_or_else(|_| usage())),
+            "--timeout" => {
+                let seconds = value().parse().unwrap_or_else(|_| usage());
+                limits.timeout =
+                    Some(Duration::try_from_secs_f64(seconds).unwrap_or_else(|_| usage()));
+            }
+            "--junit" => junit = Some(value()),
+            _ if manifest.is_none() => manifest = Some(arg),
+            _ => usage(),
+        }
+    }
+    let manifest = manifest.unwrap_or_else(|| usage());
+    let text = fs::read_to_string(manifest).unwrap_or_else(|error| {
+        eprintln!("rum: {}: {}", manifest, error);
+        process::exit(1);
+    });
+    let base = Path::new(manifest).parent().unwrap_or(Path::new("."));
+    let cases = batch::read_manifest(&text, base, limits).unwrap_or_else(|error| {
+        eprintln!("rum: {}: {}", manifest, error);
+        process::exit(1);
+    });
+    let outcomes = batch::run_all(cases, jobs);
+    let _ = batch::summary(&outcomes, &mut io::stdout().lock());
+    if let Some(path) = junit {
+        let written = File::create(path).and_then(|file| {
+            let mut out = BufWriter::new(file);
+            batch::junit(&outcomes, manifest, &mut out)?;
+            out.flush()
+        });
+        if let Err(error) = written {
+            eprintln!("rum: {}: {}", path, error);
+            process::exit(1);
+        }
+    }
+    let passed = outcomes.iter().all(|o| o.status == batch::Status::Passed);
+    process::exit(if passed { 0 } else { 1 });
+}
+
+/// Runs the program on `kind`, or on it and `other` in lockstep, exiting 1 if it fails.
+fn run_engines(
+    kind: Kind,
+    other: Option<Kind>,
+    program: Vec<u32>,
+    memory_limit: Option<usize>,
+    symbols: &Symbols,
+) {
+    let mut first = kind.create(program.clone(), memory_limit);
+    let result = match other {
+        None => first.run(),
+        Some(other) => {
+            let mut second = other.create(program, memory_limit);
+            match engine::cross_check(first.as_mut(), second.as_mut(), Box::new(StdIo)) {
+                Err(CrossCheckError::Machine(error)) => Err(error),
+                Err(error) => {
+                    eprintln!("rum: {} against {}: {}", kind, other, error);
+                    if let (CrossCheckError::Mismatch { pc, .. }, false) =
+                        (&error, symbols.is_empty())
+                    {
+                        let at = describe(symbols, *pc, first.program_version());
+                        eprintln!("    at {}", at);
+                    }
+                    process::exit(1);
+                }
+                Ok(()) => Ok(()),
+            }
+        }
+    };
+    if let Err(error) = result {
+        eprintln!("rum: {}", error);
+        if !symbols.is_empty() {
+            let at = describe(symbols, error.pc(), first.program_version());
+            eprintln!("    at {}", at);
+        }
+        process::exit(1);
+    }
+}
+
+/// Waits for one GDB to connect at `address` and lets it drive the machine.
+fn debug(vm: &mut machine::VirtualMachine, address: &str) {
+    let served = TcpListener::bind(address).and_then(|listener| {
+        eprintln!("rum: waiting for gdb on {}", listener.local_addr()?);
+        let (stream, peer) = listener.accept()?;
+        eprintln!("rum: gdb connected from {}", peer);
+        gdb::serve(vm, stream)
+    });
+    if let Err(error) = served {
+        eprintln!("rum: --gdb {}: {}", address, error);
+        process::exit(1);
+    }
+}
+
+/// Runs the program one step at a time, printing each instruction to stderr.
+fn run_traced(
+    vm: &mut machine::VirtualMachine,
+    symbols: &Symbols,
+) -> Result<(), machine::MachineError> {
+    loop {
+        let pc = vm.program_counter as u32;
+        if let Some(word) = vm.memory[&0].get(pc as usize) {
+            let at = describe(symbols, pc, vm.program_version());
+            eprintln!("{:>8}  {:<32}  {}", pc, at, decode(word));
+        }
+        if !vm.step()? {
+            return Ok(());
+        }
+    }
+}
+
+/// Describes `pc` with `symbols` while `version` says $m[0] is still the
+/// program they were written for, and as a bare address once it is not.
+fn describe(symbols: &Symbols, pc: u32, version: u64) -> String {
+    match version {
+        0 => symbols.describe(pc),
+        _ => pc.to_string(),
+    }
+}
+
+/// Lists the segments left mapped at halt, with symbols only if $m[0] never changed.
+fn report_leaks(leaks: &[machine::Leak], symbols: &Symbols, version: u64) {
+    let words: usize = leaks.iter().map(|leak| leak.words).sum();
+    eprintln!(
+        "leak summary: {} segments still mapped at halt, {} words ({} bytes)",
+        leaks.len(),
+        words,
+        words * 4
+    );
+    for leak in leaks {
+        eprintln!(
+            "    segment {}: {} words mapped at pc {}",
+            leak.segment,
+            leak.words,
+            describe(symbols, leak.pc, version)
+        );
+    }
+}
+
+/// Writes `dump` to BASE.json and BASE.umdump, or returns the path that failed and why.
+fn write_dump(dump: &Dump, base: &str) -> Result<(), String> {
+    let create = |path: String| {
+        File::create(&path)
+            .map(BufWriter::new)
+            .map_err(|error| format!("{}: {}", path, error))
+            .map(|out| (path, out))
+    };
+    let (path, mut out) = create(format!("{}.json", base))?;
+    dump.write_json(&mut out)
+        .and_then(|()| out.flush())
+        .map_err(|error| format!("{}: {}", path, error))?;
+    let (path, mut out) = create(format!("{}.umdump", base))?;
+    dump.write_binary(&mut out)
+        .and_then(|()| out.flush())
+        .map_err(|error| format!("{}: {}", path, error))
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: rum [--stats] [--optimize] [--coverage FILE] [--leak-check] [--trace] [--memory-limit WORDS]
+           [--script FILE | --record FILE | --replay FILE | --gdb ADDRESS] [--raw]
+           [--dump-on-fault BASE [--dump-contents]] [--host-calls]
+           [--engine interp|predecoded|reference] [--cross-check ENGINE] program.um
+       rum batch [--jobs N] [--max-steps N] [--timeout SECONDS] [--junit FILE] manifest
+--host-calls needs rum built with the `extensions` feature."
+    );
+    process::exit(2);
 }
@@ -0,0 +1,321 @@
+use crate::asm::MAX_LOAD_VALUE;
+use crate::disasm::{decode, encode, Instruction};
+use crate::symbols::{self, Symbols};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+///A name for an offset in an object's code
+/// Global symbols can be used by every object linked together, local
+/// ones only by their own.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub offset: u32,
+    pub global: bool,
+}
+
+///A Load Value word that must hold the address of a symbol
+/// The linked value is the symbol's address plus `addend`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Relocation {
+    pub offset: u32,
+    pub symbol: String,
+    pub addend: u32,
+}
+
+///Relocatable UM code
+/// Addresses in `code` are as if it started at address 0. Symbol names
+/// must not contain whitespace.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Object {
+    pub code: Vec<u32>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+impl Object {
+    ///Writes the object in the `.umo` text format
+    /// A header line and a `code <words>` line followed by one word per
+    /// line in hexadecimal, then `symbol <name> <offset> global|local`
+    /// and `reloc <offset> <symbol> <addend>` lines.
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "rum object 1")?;
+        writeln!(out, "code {}", self.code.len())?;
+        for word in &self.code {
+            writeln!(out, "{:08x}", word)?;
+        }
+        for symbol in &self.symbols {
+            let scope = if symbol.global { "global" } else { "local" };
+            writeln!(out, "symbol {} {} {}", symbol.name, symbol.offset, scope)?;
+        }
+        for relocation in &self.relocations {
+            writeln!(
+                out,
+                "reloc {} {} {}",
+                relocation.offset, relocation.symbol, relocation.addend
+            )?;
+        }
+        Ok(())
+    }
+
+    ///Reads an object written by `write`
+    pub fn read(input: &mut dyn BufRead) -> io::Result<Object> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad object line: {}", line),
+            )
+        };
+        let mut lines = input.lines();
+        match lines.next() {
+            Some(Ok(header)) if header == "rum object 1" => {}
+            _ => return Err(invalid("missing header")),
+        }
+        let mut object = Object::default();
+        let mut words = 0;
+        for line in lines {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if object.code.len() < words {
+                let word = u32::from_str_radix(&line, 16).map_err(|_| invalid(&line))?;
+                object.code.push(word);
+                continue;
+            }
+            match fields[..] {
+                ["code", n] => words = n.parse().map_err(|_| invalid(&line))?,
+                ["symbol", name, offset, scope @ ("global" | "local")] => {
+                    object.symbols.push(Symbol {
+                        name: name.to_string(),
+                        offset: offset.parse().map_err(|_| invalid(&line))?,
+                        global: scope == "global",
+                    })
+                }
+                ["reloc", offset, symbol, addend] => object.relocations.push(Relocation {
+                    offset: offset.parse().map_err(|_| invalid(&line))?,
+                    symbol: symbol.to_string(),
+                    addend: addend.parse().map_err(|_| invalid(&line))?,
+                }),
+                _ => return Err(invalid(&line)),
+            }
+        }
+        if object.code.len() < words {
+            return Err(invalid("missing code words"));
+        }
+        Ok(object)
+    }
+}
+
+///Reasons objects cannot be linked
+/// # Variants:
+/// * `Duplicate`: a symbol defined twice in the same scope.
+/// * `Undefined`: a relocation to a symbol no object defines.
+/// * `DoesNotFit`: a linked address too large for the 25 bits of Load Value.
+/// * `NotLoadValue`: a relocation of a word that is not Load Value, or past the code.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum LinkError {
+    Duplicate(String),
+    Undefined(String),
+    DoesNotFit { symbol: String, value: u64 },
+    NotLoadValue { object: usize, offset: u32 },
+}
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::Duplicate(name) => write!(f, "symbol `{}` is defined twice", name),
+            LinkError::Undefined(name) => write!(f, "symbol `{}` is not defined", name),
+            LinkError::DoesNotFit { symbol, value } => write!(
+                f,
+                "address {} of `{}` does not fit in the 25 bits of Load Value",
+                value, symbol
+            ),
+            LinkError::NotLoadValue { object, offset } => write!(
+                f,
+                "object {} relocates word {}, which is not a Load Value",
+                object, offset
+            ),
+        }
+    }
+}
+impl Error for LinkError {}
+
+///Links objects into a program for $m[0]
+/// The objects are placed one after another in the order given, so the
+/// first one runs first. Each relocation uses the object's own symbol of
+/// that name if there is one, else a global symbol.
+pub fn link(objects: &[Object]) -> Result<Vec<u32>, LinkError> {
+    let mut bases = vec![];
+    let mut program = vec![];
+    for object in objects {
+        bases.push(program.len() as u64);
+        program.extend_from_slice(&object.code);
+    }
+    let mut globals = HashMap::new();
+    let mut locals = vec![HashMap::new(); objects.len()];
+    for (n, object) in objects.iter().enumerate() {
+        for symbol in &object.symbols {
+            let address = bases[n] + symbol.offset as u64;
+            if locals[n].insert(symbol.name.as_str(), address).is_some()
+                || symbol.global && globals.insert(symbol.name.as_str(), address).is_some()
+            {
+                return Err(LinkError::Duplicate(symbol.name.clone()));
+            }
+        }
+    }
+    for (n, object) in objects.iter().enumerate() {
+        for relocation in &object.relocations {
+            let name = relocation.symbol.as_str();
+            let address = locals[n]
+                .get(name)
+                .or_else(|| globals.get(name))
+                .ok_or_else(|| LinkError::Undefined(name.to_string()))?;
+            let value = address + relocation.addend as u64;
+            if value > MAX_LOAD_VALUE as u64 {
+                return Err(LinkError::DoesNotFit {
+                    symbol: name.to_string(),
+                    value,
+                });
+            }
+            let at = bases[n] as usize + relocation.offset as usize;
+            match object
+                .code
+                .get(relocation.offset as usize)
+                .map(|&w| decode(w))
+            {
+                Some(Instruction::LoadValue { a, .. }) => {
+                    program[at] = encode(Instruction::LoadValue {
+                        a,
+                        value: value as u32,
+                    })
+                }
+                _ => {
+                    return Err(LinkError::NotLoadValue {
+                        object: n,
+                        offset: relocation.offset,
+                    })
+                }
+            }
+        }
+    }
+    Ok(program)
+}
+
+///Debug symbols for the program `link` makes of `objects`
+/// Each global symbol labels the code from it to the next global symbol
+/// of its object, or the end of the object.
+pub fn symbols(objects: &[Object]) -> Symbols {
+    let mut result = Symbols::default();
+    let mut base = 0;
+    for object in objects {
+        let mut starts: Vec<(u32, &str)> = object
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.global)
+            .map(|symbol| (symbol.offset, symbol.name.as_str()))
+            .collect();
+        starts.sort();
+        for (i, &(offset, name)) in starts.iter().enumerate() {
+            let end = starts
+                .get(i + 1)
+                .map_or(object.code.len() as u32, |next| next.0);
+            result.labels.push(symbols::Label {
+                start: base + offset,
+                end: base + end,
+                name: name.to_string(),
+            });
+        }
+        base += object.code.len() as u32;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::io::Captured;
+    use crate::machine::VirtualMachine;
+
+    /// Prints `text` by jumping to a `print` routine in another object.
+    fn caller() -> Object {
+        let mut asm = Assembler::new();
+        let back = asm.label();
+        asm.loadv_label(3, back);
+        asm.loadv_symbol(2, "print");
+        asm.loadp(0, 2);
+        asm.bind(back);
+        asm.halt();
+        asm.object()
+    }
+    fn callee() -> Object {
+        let mut asm = Assembler::new();
+        asm.word(0);
+        asm.define("print");
+        asm.loadv(1, 'o' as u32);
+        asm.output(1);
+        asm.loadv(1, 'k' as u32);
+        asm.output(1);
+        asm.loadp(0, 3);
+        asm.object()
+    }
+
+    #[test]
+    fn links_across_objects() {
+        let program = link(&[caller(), callee()]).unwrap();
+        let io = Captured::new(&[]);
+        let output = io.output();
+        let mut vm = VirtualMachine::new();
+        vm.set_io(Box::new(io));
+        vm.initialize_machine(program);
+        vm.run_program().unwrap();
+        assert_eq!(&output.lock().unwrap()[..], b"ok");
+    }
+
+    #[test]
+    fn labels_global_symbols() {
+        let symbols = symbols(&[caller(), callee()]);
+        assert_eq!(symbols.describe(6), "print+1");
+        assert_eq!(symbols.describe(4), "4");
+    }
+
+    #[test]
+    fn round_trips_through_text() {
+        let object = caller();
+        let mut text = vec![];
+        object.write(&mut text).unwrap();
+        assert!(String::from_utf8(text.clone())
+            .unwrap()
+            .contains("symbol .L0 3 local\nreloc 0 .L0 0\nreloc 1 print 0\n"));
+        assert_eq!(Object::read(&mut &text[..]).unwrap(), object);
+    }
+
+    #[test]
+    fn reports_link_errors() {
+        assert_eq!(
+            link(&[caller()]),
+            Err(LinkError::Undefined("print".to_string()))
+        );
+        assert_eq!(
+            link(&[caller(), callee(), callee()]),
+            Err(LinkError::Duplicate("print".to_string()))
+        );
+        let mut far = caller();
+        far.relocations[1].addend = MAX_LOAD_VALUE;
+        assert_eq!(
+            link(&[far, callee()]),
+            Err(LinkError::DoesNotFit {
+                symbol: "print".to_string(),
+                value: MAX_LOAD_VALUE as u64 + 5
+            })
+        );
+        let mut bad = caller();
+        bad.relocations[0].offset = 2;
+        assert_eq!(
+            link(&[bad, callee()]),
+            Err(LinkError::NotLoadValue {
+                object: 0,
+                offset: 2
+            })
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use crate::disasm::{decode, Instruction};
+use crate::segment::Segment;
+
+///Fused instruction sequence
+/// # Variants:
+/// * `Constant`: `loadv rX; loadv rY; nand rA, rB, rC` where B and C are
+///   both X or Y, so the result is known ahead of time.
+/// * `Jump`: `loadv rT; loadp rB, rT`, a jump when $r[B] = 0.
+/// * `Branch`: `loadv rT; loadv rF; cmov rF, rT, rC; loadp rB, rF`, which
+///   jumps to the first value if $r[C] != 0 and to the second otherwise.
+///
+/// Jumps and branches keep the Load Program word so the machine can run it
+/// as usual once the registers are set up.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Superinstruction {
+    Constant {
+        x: usize,
+        x_value: u32,
+        y: usize,
+        y_value: u32,
+        a: usize,
+        value: u32,
+    },
+    Jump {
+        t: usize,
+        target: u32,
+        load_program: u32,
+    },
+    Branch {
+        t: usize,
+        t_value: u32,
+        f: usize,
+        f_value: u32,
+        c: usize,
+        load_program: u32,
+    },
+}
+impl Superinstruction {
+    ///Number of instructions the superinstruction replaces
+    pub fn len(&self) -> usize {
+        match self {
+            Superinstruction::Constant { .. } => 3,
+            Superinstruction::Jump { .. } => 2,
+            Superinstruction::Branch { .. } => 4,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+/// Longest sequence replaced by a superinstruction.
+const MAX_LEN: usize = 4;
+/// Stands in for words past the end of the program, as no sequence has a halt.
+const PAST_END: Instruction = Instruction::Halt;
+
+///Superinstructions found in $m[0]
+/// `fused[i]` holds the superinstruction starting at address `i`, if any.
+/// Sequences are only fused when entered at their first instruction, so
+/// jumps into the middle of one run the plain instructions.
+pub struct Superinstructions {
+    fused: Vec<Option<Superinstruction>>,
+}
+impl Superinstructions {
+    ///Scans a program for fusable sequences
+    /// Each word is decoded once, into a window sliding over the program.
+    /// Lazily mapped programs are left unoptimized.
+    pub fn new(program: &Segment) -> Self {
+        let fused = match program {
+            Segment::Dense(words) => {
+                let mut window = [PAST_END; MAX_LEN];
+                for (slot, &word) in window.iter_mut().zip(words) {
+                    *slot = decode(word);
+                }
+                (0..words.len())
+                    .map(|i| {
+                        let op = fuse(&window, &words[i..]);
+                        window.rotate_left(1);
+                        window[MAX_LEN - 1] =
+                            words.get(i + MAX_LEN).map_or(PAST_END, |&w| decode(w));
+                        op
+                    })
+                    .collect()
+            }
+            Segment::Lazy { .. } => vec![],
+        };
+        Superinstructions { fused }
+    }
+    ///Returns the superinstruction starting at `address`
+    #[inline]
+    pub fn at(&self, address: usize) -> Option<Superinstruction> {
+        self.fused.get(address).copied().flatten()
+    }
+    ///Drops every superinstruction covering `address`, after it is written
+    pub fn invalidate(&mut self, address: usize) {
+        for start in address.saturating_sub(MAX_LEN - 1)..=address {
+            if let Some(Some(op)) = self.fused.get(start) {
+                if start + op.len() > address {
+                    self.fused[start] = None;
+                }
+            }
+        }
+    }
+    ///Number of superinstructions found
+    pub fn count(&self) -> usize {
+        self.fused.iter().filter(|op| op.is_some()).count()
+    }
+}
+
+/// Returns the superinstruction for the sequence at the start of `words`.
+/// `code` holds the first words decoded, padded with `PAST_END`.
+fn fuse(code: &[Instruction; MAX_LEN], words: &[u32]) -> Option<Superinstruction> {
+    match *code {
+        [Instruction::LoadValue {
+            a: t,
+            value: t_value,
+        }, Instruction::LoadValue {
+            a: f,
+            value: f_value,
+        }, Instruction::CMov { a, b, c }, Instruction::LoadProgram { c: target, .. }, ..]
+            if t != f && a == f && b == t && target == f =>
+        {
+            Some(Superinstruction::Branch {
+                t,
+                t_value,
+                f,
+                f_value,
+                c,
+                load_program: words[3],
+            })
+        }
+        [Instruction::LoadValue {
+            a: x,
+            value: x_value,
+        }, Instruction::LoadValue {
+            a: y,
+            value: y_value,
+        }, Instruction::Nand { a, b, c }, ..] => {
+            let known = |r: usize| {
+                if r == y {
+                    Some(y_value)
+                } else if r == x {
+                    Some(x_value)
+                } else {
+                    None
+                }
+            };
+            let value = !(known(b)? & known(c)?);
+            Some(Superinstruction::Constant {
+                x,
+                x_value,
+                y,
+                y_value,
+                a,
+                value,
+            })
+        }
+        [Instruction::LoadValue {
+            a: t,
+            value: target,
+        }, Instruction::LoadProgram { b, c }, ..]
+            if c == t && b != t =>
+        {
+            Some(Superinstruction::Jump {
+                t,
+                target,
+                load_program: words[1],
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::disasm::encode;
+
+    ///`loadv r1, 10; loadv r2, 20; cmov r2, r1, r3; loadp r0, r2`
+    fn branch(asm: &mut Assembler) {
+        asm.loadv(1, 10);
+        asm.loadv(2, 20);
+        asm.cmov(2, 1, 3);
+        asm.loadp(0, 2);
+    }
+
+    #[test]
+    fn fuses_constants() {
+        let mut asm = Assembler::new();
+        asm.loadv(1, 5);
+        asm.loadv(2, 3);
+        asm.nand(3, 1, 2);
+        let program = Segment::from(asm.finish());
+        let fused = Superinstructions::new(&program);
+        assert_eq!(
+            fused.at(0),
+            Some(Superinstruction::Constant {
+                x: 1,
+                x_value: 5,
+                y: 2,
+                y_value: 3,
+                a: 3,
+                value: !(5 & 3),
+            })
+        );
+        assert_eq!(fused.count(), 1);
+    }
+
+    #[test]
+    fn nand_of_unknown_register_is_not_fused() {
+        let mut asm = Assembler::new();
+        asm.loadv(1, 5);
+        asm.loadv(2, 3);
+        asm.nand(3, 1, 4);
+        let program = Segment::from(asm.finish());
+        assert_eq!(Superinstructions::new(&program).count(), 0);
+    }
+
+    #[test]
+    fn fuses_jumps_and_branches() {
+        let mut asm = Assembler::new();
+        branch(&mut asm);
+        asm.loadv(4, 0);
+        asm.loadp(0, 4);
+        let program = Segment::from(asm.finish());
+        let fused = Superinstructions::new(&program);
+        assert!(matches!(fused.at(0), Some(Superinstruction::Branch { .. })));
+        assert_eq!(
+            fused.at(4),
+            Some(Superinstruction::Jump {
+                t: 4,
+                target: 0,
+                load_program: encode(Instruction::LoadProgram { b: 0, c: 4 }),
+            })
+        );
+    }
+
+    #[test]
+    fn writes_drop_covering_superinstructions() {
+        let mut asm = Assembler::new();
+        branch(&mut asm);
+        let program = Segment::from(asm.finish());
+        let mut fused = Superinstructions::new(&program);
+        assert_eq!(fused.count(), 1);
+        fused.invalidate(3);
+        assert_eq!(fused.count(), 0);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::disasm::{decode, Instruction};
+use crate::engine::Engine;
+use crate::io::{IoDevice, StdIo};
+use crate::machine::{MachineError, REGISTERS};
+use crate::reference::Memory;
+
+///Universal Machine that decodes $m[0] once instead of at every step
+/// Stores into $m[0] decode the word again, and Load Program decodes the
+/// whole new program, so self-modifying code still works.
+/// # Parameters:
+/// * `registers`: The eight registers.
+/// * `memory`: The segments, $m[0] included as words.
+/// * `program`: $m[0] decoded, word for word.
+/// * `pc`: Address of the next instruction in $m[0].
+/// * `io`: Device behind Output and Input.
+pub struct Predecoded {
+    registers: [u32; REGISTERS],
+    memory: Memory,
+    program: Vec<Instruction>,
+    pc: u32,
+    io: Box<dyn IoDevice>,
+}
+impl Predecoded {
+    ///Creates a machine about to run `program`, on standard input and output
+    pub fn new(program: Vec<u32>) -> Predecoded {
+        Predecoded {
+            registers: [0; REGISTERS],
+            program: program.iter().map(|&word| decode(word)).collect(),
+            memory: Memory::new(program),
+            pc: 0,
+            io: Box::new(StdIo),
+        }
+    }
+
+    ///Caps the words the guest may have mapped at once, or lifts the cap with `None`
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory.set_limit(limit);
+    }
+}
+impl Engine for Predecoded {
+    fn step(&mut self) -> Result<bool, MachineError> {
+        let pc = self.pc;
+        let instruction = *self
+            .program
+            .get(pc as usize)
+            .ok_or(MachineError::ProgramCounterOutOfBounds { pc })?;
+        let r = &mut self.registers;
+        match instruction {
+            Instruction::CMov { a, b, c } => {
+                if r[c] != 0 {
+                    r[a] = r[b];
+                }
+            }
+            Instruction::Load { a, b, c } => r[a] = self.memory.load(pc, r[b], r[c])?,
+            Instruction::Store { a, b, c } => {
+                self.memory.store(pc, r[a], r[b], r[c])?;
+                if r[a] == 0 {
+                    self.program[r[b] as usize] = decode(r[c]);
+                }
+            }
+            Instruction::Add { a, b, c } => r[a] = r[b].wrapping_add(r[c]),
+            Instruction::Mul { a, b, c } => r[a] = r[b].wrapping_mul(r[c]),
+            Instruction::Div { a, b, c } => {
+                if r[c] == 0 {
+                    return Err(MachineError::DivisionByZero { pc });
+                }
+                r[a] = r[b] / r[c];
+            }
+            Instruction::Nand { a, b, c } => r[a] = !(r[b] & r[c]),
+            Instruction::Halt => return Ok(false),
+            Instruction::MapSegment { b, c } => r[b] = self.memory.map(pc, r[c])?,
+            Instruction::UnmapSegment { c } => self.memory.unmap(pc, r[c])?,
+            Instruction::Output { c } => self.io.write_byte(r[c] as u8),
+            Instruction::Input { c } => r[c] = self.io.read_byte().map_or(u32::MAX, u32::from),
+            Instruction::LoadProgram { b, c } => {
+                if r[b] != 0 {
+                    let program = self.memory.load_program(pc, r[b])?;
+                    self.program = program.iter().map(|&word| decode(word)).collect();
+                }
+                self.pc = r[c];
+                return Ok(true);
+            }
+            Instruction::LoadValue { a, value } => r[a] = value,
+            Instruction::Invalid { .. } => {}
+        }
+        self.pc = pc.wrapping_add(1);
+        Ok(true)
+    }
+
+    fn registers(&self) -> [u32; REGISTERS] {
+        self.registers
+    }
+
+    fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    fn program_version(&self) -> u64 {
+        self.memory.version()
+    }
+
+    fn set_io(&mut self, io: Box<dyn IoDevice>) {
+        self.io = io;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::disasm::encode;
+
+    #[test]
+    fn sees_stores_into_program() {
+        // Overwrites the first halt with a Load Value, then runs it
+        let mut asm = Assembler::new();
+        let patched = asm.label();
+        asm.constant(2, encode(Instruction::LoadValue { a: 4, value: 9 }), 3);
+        asm.loadv_label(1, patched);
+        asm.store(0, 1, 2);
+        asm.bind(patched);
+        asm.halt();
+        asm.halt();
+        let mut engine = Predecoded::new(asm.finish());
+        while engine.step().unwrap() {}
+        assert_eq!(engine.registers()[4], 9);
+    }
+
+    #[test]
+    fn decodes_loaded_program() {
+        // Writes a two word program into segment 1 and jumps into it
+        let mut asm = Assembler::new();
+        asm.loadv(1, 2);
+        asm.map(5, 1);
+        let words = [Instruction::LoadValue { a: 4, value: 7 }, Instruction::Halt];
+        for (index, instruction) in words.into_iter().enumerate() {
+            asm.constant(2, encode(instruction), 3);
+            asm.loadv(1, index as u32);
+            asm.store(5, 1, 2);
+        }
+        asm.loadv(1, 0);
+        asm.loadp(5, 1);
+        let mut engine = Predecoded::new(asm.finish());
+        while engine.step().unwrap() {}
+        assert_eq!(engine.registers()[4], 7);
+        assert_eq!(engine.pc(), 1);
+    }
+}
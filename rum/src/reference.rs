@@ -0,0 +1,306 @@
+use crate::engine::Engine;
+use crate::io::{IoDevice, StdIo};
+use crate::machine::{get, MachineError, DEFAULT_MEMORY_LIMIT, OP, RA, RB, RC, REGISTERS, RL, VL};
+
+///Segments of a machine that keeps every word in a plain vector
+/// Identifiers are chosen as `VirtualMachine` chooses them: the one most
+/// recently unmapped, else one past the highest yet, so the registers of
+/// engines sharing it can be compared.
+/// # Parameters:
+/// * `segments`: Words of each segment by identifier, `None` if unmapped.
+/// * `free`: Unmapped identifiers, reused last in, first out.
+/// * `words`: Words of all mapped segments, $m[0] included.
+/// * `limit`: Most words that may be mapped at once, if capped.
+/// * `version`: Counts writes to $m[0] and replacements of it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Memory {
+    segments: Vec<Option<Vec<u32>>>,
+    free: Vec<u32>,
+    words: usize,
+    limit: Option<usize>,
+    version: u64,
+}
+impl Memory {
+    ///Memory holding only `program`, as $m[0], with the default limit
+    pub(crate) fn new(program: Vec<u32>) -> Memory {
+        Memory {
+            words: program.len(),
+            segments: vec![Some(program)],
+            free: vec![],
+            limit: Some(DEFAULT_MEMORY_LIMIT),
+            version: 0,
+        }
+    }
+
+    ///Caps the words mapped at once, or lifts the cap with `None`
+    pub(crate) fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    /// Fails the instruction at `pc` if mapping `requested` more words
+    /// after `released` are unmapped would pass the limit.
+    fn reserve(&self, pc: u32, requested: usize, released: usize) -> Result<(), MachineError> {
+        let resident = self.words - released;
+        match self.limit {
+            Some(limit) if resident.saturating_add(requested) > limit => {
+                Err(MachineError::AllocationLimit {
+                    pc,
+                    requested,
+                    resident,
+                    limit,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    ///Mapped segment `id`, or the fault of the instruction at `pc` for using it
+    pub(crate) fn segment(&self, pc: u32, id: u32) -> Result<&Vec<u32>, MachineError> {
+        match self.segments.get(id as usize) {
+            Some(Some(segment)) => Ok(segment),
+            _ => Err(MachineError::UnmappedSegment { pc, segment: id }),
+        }
+    }
+
+    ///Word `index` of segment `id`, or the fault of the instruction at `pc` for reading it
+    pub(crate) fn load(&self, pc: u32, id: u32, index: u32) -> Result<u32, MachineError> {
+        self.segment(pc, id)?
+            .get(index as usize)
+            .copied()
+            .ok_or(MachineError::OutOfBounds {
+                pc,
+                segment: id,
+                index,
+            })
+    }
+
+    ///Writes word `index` of segment `id`, or returns the fault of the instruction at `pc`
+    pub(crate) fn store(
+        &mut self,
+        pc: u32,
+        id: u32,
+        index: u32,
+        value: u32,
+    ) -> Result<(), MachineError> {
+        self.load(pc, id, index)?;
+        self.segments[id as usize].as_mut().unwrap()[index as usize] = value;
+        if id == 0 {
+            self.version += 1;
+        }
+        Ok(())
+    }
+
+    ///Maps a segment of `words` zeros, returning its identifier
+    /// Or the fault of the instruction at `pc` if that passes the limit.
+    pub(crate) fn map(&mut self, pc: u32, words: u32) -> Result<u32, MachineError> {
+        self.reserve(pc, words as usize, 0)?;
+        self.words += words as usize;
+        let segment = Some(vec![0; words as usize]);
+        Ok(match self.free.pop() {
+            Some(id) => {
+                self.segments[id as usize] = segment;
+                id
+            }
+            None => {
+                self.segments.push(segment);
+                (self.segments.len() - 1) as u32
+            }
+        })
+    }
+
+    ///Unmaps segment `id`, or returns the fault of the instruction at `pc`
+    /// $m[0] cannot be unmapped.
+    pub(crate) fn unmap(&mut self, pc: u32, id: u32) -> Result<(), MachineError> {
+        if id == 0 {
+            return Err(MachineError::UnmappedSegment { pc, segment: id });
+        }
+        self.words -= self.segment(pc, id)?.len();
+        self.segments[id as usize] = None;
+        self.free.push(id);
+        Ok(())
+    }
+
+    ///Replaces $m[0] with a copy of segment `id`, returning the copy
+    /// Or the fault of the instruction at `pc`.
+    pub(crate) fn load_program(&mut self, pc: u32, id: u32) -> Result<&Vec<u32>, MachineError> {
+        let (requested, released) = (self.segment(pc, id)?.len(), self.program().len());
+        self.reserve(pc, requested, released)?;
+        self.words = self.words - released + requested;
+        let program = self.segment(pc, id)?.clone();
+        self.segments[0] = Some(program);
+        self.version += 1;
+        Ok(self.program())
+    }
+
+    ///How many times $m[0] has been written or replaced
+    pub(crate) fn version(&self) -> u64 {
+        self.version
+    }
+
+    ///$m[0]
+    pub(crate) fn program(&self) -> &Vec<u32> {
+        self.segments[0].as_ref().unwrap()
+    }
+}
+
+///Universal Machine written to follow the specification as plainly as possible
+/// Each word is decoded as it is executed, and every word of a segment is
+/// allocated when it is mapped, counting against the memory limit. Meant
+/// as the yardstick other engines are cross-checked against, not for speed.
+/// # Parameters:
+/// * `registers`: The eight registers.
+/// * `memory`: The segments.
+/// * `pc`: Address of the next instruction in $m[0].
+/// * `io`: Device behind Output and Input.
+pub struct Reference {
+    registers: [u32; REGISTERS],
+    memory: Memory,
+    pc: u32,
+    io: Box<dyn IoDevice>,
+}
+impl Reference {
+    ///Creates a machine about to run `program`, on standard input and output
+    pub fn new(program: Vec<u32>) -> Reference {
+        Reference {
+            registers: [0; REGISTERS],
+            memory: Memory::new(program),
+            pc: 0,
+            io: Box::new(StdIo),
+        }
+    }
+
+    ///Caps the words the guest may have mapped at once, or lifts the cap with `None`
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory.set_limit(limit);
+    }
+}
+impl Engine for Reference {
+    fn step(&mut self) -> Result<bool, MachineError> {
+        let pc = self.pc;
+        let word = *self
+            .memory
+            .program()
+            .get(pc as usize)
+            .ok_or(MachineError::ProgramCounterOutOfBounds { pc })?;
+        let a = get(&RA, word) as usize;
+        let b = get(&RB, word) as usize;
+        let c = get(&RC, word) as usize;
+        let r = self.registers;
+        let mut next = pc.wrapping_add(1);
+        match get(&OP, word) {
+            0 if r[c] != 0 => self.registers[a] = r[b],
+            1 => self.registers[a] = self.memory.load(pc, r[b], r[c])?,
+            2 => self.memory.store(pc, r[a], r[b], r[c])?,
+            3 => self.registers[a] = r[b].wrapping_add(r[c]),
+            4 => self.registers[a] = r[b].wrapping_mul(r[c]),
+            5 => {
+                if r[c] == 0 {
+                    return Err(MachineError::DivisionByZero { pc });
+                }
+                self.registers[a] = r[b] / r[c];
+            }
+            6 => self.registers[a] = !(r[b] & r[c]),
+            7 => return Ok(false),
+            8 => self.registers[b] = self.memory.map(pc, r[c])?,
+            9 => self.memory.unmap(pc, r[c])?,
+            10 => self.io.write_byte(r[c] as u8),
+            11 => self.registers[c] = self.io.read_byte().map_or(u32::MAX, u32::from),
+            12 => {
+                if r[b] != 0 {
+                    self.memory.load_program(pc, r[b])?;
+                }
+                next = r[c];
+            }
+            13 => self.registers[get(&RL, word) as usize] = get(&VL, word),
+            // Conditional moves with $r[C] = 0, and opcodes 14 and 15
+            _ => {}
+        }
+        self.pc = next;
+        Ok(true)
+    }
+
+    fn registers(&self) -> [u32; REGISTERS] {
+        self.registers
+    }
+
+    fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    fn program_version(&self) -> u64 {
+        self.memory.version()
+    }
+
+    fn set_io(&mut self, io: Box<dyn IoDevice>) {
+        self.io = io;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::disasm::{encode, Instruction};
+    use crate::io::Captured;
+
+    #[test]
+    fn runs_compiled_program() {
+        let program =
+            compile("fn main() { var i = 0; while (i < 3) { putc('0' + i); i = i + 1; } }")
+                .unwrap();
+        let mut engine = Reference::new(program);
+        let io = Captured::new(&[]);
+        let output = io.output();
+        engine.set_io(Box::new(io));
+        while engine.step().unwrap() {}
+        assert_eq!(&output.lock().unwrap()[..], b"012");
+    }
+
+    #[test]
+    fn reuses_identifiers_last_in_first_out() {
+        let mut memory = Memory::new(vec![]);
+        let mut map = |words| memory.map(0, words).unwrap();
+        assert_eq!((map(1), map(2), map(3)), (1, 2, 3));
+        memory.unmap(0, 1).unwrap();
+        memory.unmap(0, 3).unwrap();
+        let mut map = |words| memory.map(0, words).unwrap();
+        assert_eq!((map(4), map(5), map(6)), (3, 1, 4));
+        assert_eq!(
+            memory.unmap(7, 0),
+            Err(MachineError::UnmappedSegment { pc: 7, segment: 0 })
+        );
+    }
+
+    #[test]
+    fn enforces_memory_limit() {
+        let mut memory = Memory::new(vec![0; 4]);
+        memory.set_limit(Some(10));
+        assert_eq!(memory.map(0, 6), Ok(1));
+        assert_eq!(
+            memory.map(3, u32::MAX),
+            Err(MachineError::AllocationLimit {
+                pc: 3,
+                requested: u32::MAX as usize,
+                resident: 10,
+                limit: 10,
+            })
+        );
+        memory.unmap(0, 1).unwrap();
+        assert_eq!(memory.map(0, 2), Ok(1));
+        // Replacing $m[0] releases the old program
+        assert!(memory.load_program(0, 1).is_ok());
+        assert_eq!(memory.words, 4);
+    }
+
+    #[test]
+    fn faults_leave_machine_unchanged() {
+        let mut engine = Reference::new(vec![
+            encode(Instruction::LoadValue { a: 1, value: 4 }),
+            encode(Instruction::Div { a: 2, b: 1, c: 0 }),
+        ]);
+        assert_eq!(engine.step(), Ok(true));
+        assert_eq!(engine.step(), Err(MachineError::DivisionByZero { pc: 1 }));
+        assert_eq!(engine.pc(), 1);
+        assert_eq!(engine.registers(), [0, 4, 0, 0, 0, 0, 0, 0]);
+    }
+}
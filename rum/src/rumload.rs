@@ -1,3 +1,4 @@
+use crate::symbols::Symbols;
 use std::convert::TryInto;
 pub fn load(input: Option<&str>) -> Vec<u32> {
     let mut raw_reader: Box<dyn std::io::BufRead> = match input {
@@ -8,9 +9,33 @@ pub fn load(input: Option<&str>) -> Vec<u32> {
     };
     let mut buf = Vec::<u8>::new();
     raw_reader.read_to_end(&mut buf).unwrap();
-    let instructions: Vec<u32> = buf
-        .chunks_exact(4)
+    from_bytes(&buf)
+}
+
+///Decodes a `.um` image already in memory
+pub fn from_bytes(buf: &[u8]) -> Vec<u32> {
+    buf.chunks_exact(4)
         .map(|x| u32::from_be_bytes(x.try_into().unwrap()))
-        .collect();
-    instructions
+        .collect()
+}
+
+///Encodes a program as a `.um` image, the inverse of `load`
+pub fn to_bytes(program: &[u32]) -> Vec<u8> {
+    program.iter().flat_map(|word| word.to_be_bytes()).collect()
+}
+
+///Loads the `.umsym` debug symbols beside the program at `program`, if there are any
+pub fn load_symbols(program: &str) -> std::io::Result<Option<Symbols>> {
+    match std::fs::File::open(Symbols::sidecar(std::path::Path::new(program))) {
+        Ok(file) => Symbols::read(&mut std::io::BufReader::new(file)).map(Some),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+///Writes debug symbols to the `.umsym` file beside the program at `program`
+pub fn save_symbols(program: &std::path::Path, symbols: &Symbols) -> std::io::Result<()> {
+    let mut out = std::io::BufWriter::new(std::fs::File::create(Symbols::sidecar(program))?);
+    symbols.write(&mut out)?;
+    std::io::Write::flush(&mut out)
 }
@@ -0,0 +1,156 @@
+use crate::machine::{MachineError, VirtualMachine};
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+/// Steps a machine runs per turn when the caller has no preference.
+pub const DEFAULT_SLICE: u64 = 1 << 16;
+
+/// Machines waiting for a turn, how many are taking one, and how the
+/// others ended, by their position in the input.
+struct Queue {
+    waiting: VecDeque<(usize, VirtualMachine)>,
+    running: usize,
+    finished: Vec<Option<(VirtualMachine, Result<(), MachineError>)>>,
+}
+
+///Runs machines on `threads` threads, round-robin in turns of `slice` steps
+/// A machine that has not halted after its turn goes to the back of the
+/// queue, so a long-running guest cannot starve the others. Machines share
+/// nothing but the threads. Returns each machine in the order given, with
+/// how it ended.
+pub fn run_all(
+    machines: Vec<VirtualMachine>,
+    threads: usize,
+    slice: u64,
+) -> Vec<(VirtualMachine, Result<(), MachineError>)> {
+    let queue = Mutex::new(Queue {
+        finished: machines.iter().map(|_| None).collect(),
+        waiting: machines.into_iter().enumerate().collect(),
+        running: 0,
+    });
+    let turned = Condvar::new();
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            scope.spawn(|| loop {
+                let (n, mut vm) = {
+                    let mut queue = queue.lock().unwrap();
+                    loop {
+                        if let Some(next) = queue.waiting.pop_front() {
+                            queue.running += 1;
+                            break next;
+                        }
+                        if queue.running == 0 {
+                            return;
+                        }
+                        queue = turned.wait(queue).unwrap();
+                    }
+                };
+                let turn = vm.run_for(slice);
+                let mut queue = queue.lock().unwrap();
+                queue.running -= 1;
+                match turn {
+                    Ok(true) => queue.waiting.push_back((n, vm)),
+                    Ok(false) => queue.finished[n] = Some((vm, Ok(()))),
+                    Err(error) => queue.finished[n] = Some((vm, Err(error))),
+                }
+                turned.notify_all();
+            });
+        }
+    });
+    let queue = queue.into_inner().unwrap();
+    queue.finished.into_iter().map(Option::unwrap).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::disasm::{encode, Instruction};
+    use crate::io::{Captured, IoDevice};
+    use std::sync::Arc;
+
+    /// Outputs `count` copies of `letter`.
+    fn repeat(letter: char, count: u32) -> VirtualMachine {
+        let source = format!(
+            "fn main() {{ var i = 0; while (i < {}) {{ putc('{}'); i = i + 1; }} }}",
+            count, letter
+        );
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(compile(&source).unwrap());
+        vm
+    }
+
+    /// Output of several machines into one buffer, to see the turns they took.
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl IoDevice for Shared {
+        fn read_byte(&mut self) -> Option<u8> {
+            None
+        }
+        fn write_byte(&mut self, byte: u8) {
+            self.0.lock().unwrap().push(byte);
+        }
+    }
+
+    #[test]
+    fn machines_can_move_between_threads() {
+        fn send<T: Send>() {}
+        send::<VirtualMachine>();
+    }
+
+    #[test]
+    fn runs_for_a_number_of_steps() {
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(vec![0, 0, 0, 0, encode(Instruction::Halt)]);
+        assert_eq!(vm.run_for(3), Ok(true));
+        assert_eq!(vm.program_counter, 3);
+        assert_eq!(vm.run_for(10), Ok(false));
+        assert_eq!(vm.program_counter, 4);
+    }
+
+    #[test]
+    fn takes_turns_fairly() {
+        let output = Arc::new(Mutex::new(vec![]));
+        let machines = ['a', 'b', 'c']
+            .into_iter()
+            .map(|letter| {
+                let mut vm = repeat(letter, 20);
+                vm.set_io(Box::new(Shared(Arc::clone(&output))));
+                vm
+            })
+            .collect();
+        for (_, result) in run_all(machines, 1, 50) {
+            assert_eq!(result, Ok(()));
+        }
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert_eq!(output.len(), 60);
+        assert!(output.find('c').unwrap() < output.rfind('a').unwrap());
+    }
+
+    #[test]
+    fn isolates_machines_on_many_threads() {
+        let mut outputs = vec![];
+        let mut machines: Vec<VirtualMachine> = (0..8)
+            .map(|n| {
+                let io = Captured::new(&[]);
+                outputs.push(io.output());
+                let mut vm = repeat((b'a' + n) as char, 100 + n as u32);
+                vm.set_io(Box::new(io));
+                vm
+            })
+            .collect();
+        let mut faulty = VirtualMachine::new();
+        faulty.initialize_machine(compile("fn main() { var a = array(1); putc(a[5]); }").unwrap());
+        machines.push(faulty);
+        let finished = run_all(machines, 3, 64);
+        for (n, output) in outputs.iter().enumerate() {
+            assert_eq!(finished[n].1, Ok(()));
+            let expected = vec![b'a' + n as u8; 100 + n];
+            assert_eq!(*output.lock().unwrap(), expected);
+        }
+        assert!(matches!(
+            finished[8].1,
+            Err(MachineError::OutOfBounds { index: 5, .. })
+        ));
+    }
+}
@@ -0,0 +1,365 @@
+use crate::io::IoDevice;
+use crate::machine::{MachineError, VirtualMachine};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long an `expect` waits when the script does not say.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Steps run between checks of the deadline.
+const STEPS_PER_CHECK: u64 = 1 << 12;
+
+///One line of a script
+/// # Variants:
+/// * `Expect`: wait until the guest outputs this text.
+/// * `Send`: give the guest this text as input.
+/// * `Timeout`: how long the following `expect`s may wait.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Command {
+    Expect(String),
+    Send(String),
+    Timeout(Duration),
+}
+
+///Reads a script, one command per line
+/// `expect TEXT` waits for TEXT in the output, `send TEXT` inputs TEXT and
+/// a newline, and `timeout SECONDS` limits how long later `expect`s wait.
+/// TEXT runs to the end of the line and may use `\n`, `\t` and `\\`.
+/// Blank lines and lines starting with `#` are skipped.
+pub fn parse(text: &str) -> Result<Vec<(usize, Command)>, String> {
+    let mut commands = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (word, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let bad = |what: &str| format!("script line {}: {}", number, what);
+        let command = match word {
+            "expect" => Command::Expect(unescape(rest).map_err(|e| bad(&e))?),
+            "send" => Command::Send(unescape(rest).map_err(|e| bad(&e))? + "\n"),
+            "timeout" => {
+                let seconds = rest.trim().parse().map_err(|_| bad("bad timeout"))?;
+                Command::Timeout(
+                    Duration::try_from_secs_f64(seconds).map_err(|_| bad("bad timeout"))?,
+                )
+            }
+            _ => return Err(bad(&format!("unknown command `{}`", word))),
+        };
+        commands.push((number, command));
+    }
+    Ok(commands)
+}
+
+fn unescape(text: &str) -> Result<String, String> {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('\\') => result.push('\\'),
+            other => {
+                return Err(format!(
+                    "bad escape `\\{}`",
+                    other.map_or(String::new(), String::from)
+                ))
+            }
+        }
+    }
+    Ok(result)
+}
+
+///Why a scripted session failed
+/// # Variants:
+/// * `Timeout`: the expected text did not show up in time.
+/// * `Stuck`: the guest waited for input before showing the expected text.
+/// * `Halted`: the guest halted before showing the expected text.
+/// * `Machine`: the machine failed.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ScriptError {
+    Timeout { line: usize, expected: String },
+    Stuck { line: usize, expected: String },
+    Halted { line: usize, expected: String },
+    Machine(MachineError),
+}
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::Timeout { line, expected } => write!(
+                f,
+                "script line {}: timed out waiting for {:?}",
+                line, expected
+            ),
+            ScriptError::Stuck { line, expected } => write!(
+                f,
+                "script line {}: guest wants input but {:?} was not output",
+                line, expected
+            ),
+            ScriptError::Halted { line, expected } => write!(
+                f,
+                "script line {}: guest halted before outputting {:?}",
+                line, expected
+            ),
+            ScriptError::Machine(error) => error.fmt(f),
+        }
+    }
+}
+impl Error for ScriptError {}
+
+/// Progress through the script, shared by the device and the driver.
+/// `unmatched` is the output bytes since the last match that could still
+/// be part of one, `deadline` is when
+/// the current `expect` gives up, and `stuck` is set when the guest
+/// asked for input the script cannot give yet.
+#[derive(Debug)]
+struct Session {
+    commands: VecDeque<(usize, Command)>,
+    unmatched: Vec<u8>,
+    input: VecDeque<u8>,
+    timeout: Duration,
+    deadline: Option<Instant>,
+    stuck: bool,
+}
+impl Session {
+    /// Runs commands until one must wait for more output.
+    fn advance(&mut self) {
+        while let Some((_, command)) = self.commands.front() {
+            match command {
+                Command::Expect(text) => match find(&self.unmatched, text.as_bytes()) {
+                    Some(at) => {
+                        self.unmatched.drain(..at + text.len());
+                        self.deadline = None;
+                    }
+                    None => {
+                        // Only the last bytes can start a match once more output arrives
+                        let stale = self.unmatched.len().saturating_sub(text.len() - 1);
+                        self.unmatched.drain(..stale);
+                        self.deadline
+                            .get_or_insert_with(|| Instant::now() + self.timeout);
+                        return;
+                    }
+                },
+                Command::Send(text) => self.input.extend(text.bytes()),
+                Command::Timeout(timeout) => self.timeout = *timeout,
+            }
+            self.commands.pop_front();
+        }
+        // No `expect` is left to match the output against
+        self.unmatched.clear();
+    }
+    /// The `expect` being waited on, as its line and text.
+    fn waiting(&self) -> Option<(usize, String)> {
+        match self.commands.front() {
+            Some((line, Command::Expect(text))) => Some((*line, text.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// Where `pattern` first occurs in `bytes`, if it does.
+fn find(bytes: &[u8], pattern: &[u8]) -> Option<usize> {
+    if pattern.is_empty() {
+        return Some(0);
+    }
+    bytes
+        .windows(pattern.len())
+        .position(|window| window == pattern)
+}
+
+/// Device that feeds the script's input and watches the guest's output,
+/// passing the output on to `echo`.
+struct ScriptDevice {
+    session: Arc<Mutex<Session>>,
+    echo: Box<dyn IoDevice>,
+}
+impl IoDevice for ScriptDevice {
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut session = self.session.lock().unwrap();
+        session.advance();
+        let byte = session.input.pop_front();
+        if byte.is_none() && session.waiting().is_some() {
+            session.stuck = true;
+        }
+        byte
+    }
+    fn write_byte(&mut self, byte: u8) {
+        self.echo.write_byte(byte);
+        let mut session = self.session.lock().unwrap();
+        session.unmatched.push(byte);
+        session.advance();
+    }
+}
+
+///Runs the machine, answering its input from `script`
+/// Output goes to `echo` as well. Once the script is used up the guest
+/// reads the end of input, and the run goes on until it halts.
+pub fn run(
+    vm: &mut VirtualMachine,
+    script: Vec<(usize, Command)>,
+    echo: Box<dyn IoDevice>,
+) -> Result<(), ScriptError> {
+    let session = Arc::new(Mutex::new(Session {
+        commands: script.into(),
+        unmatched: vec![],
+        input: VecDeque::new(),
+        timeout: DEFAULT_TIMEOUT,
+        deadline: None,
+        stuck: false,
+    }));
+    session.lock().unwrap().advance();
+    vm.set_io(Box::new(ScriptDevice {
+        session: Arc::clone(&session),
+        echo,
+    }));
+    let mut steps: u64 = 0;
+    loop {
+        let running = vm.step().map_err(ScriptError::Machine)?;
+        steps += 1;
+        let session = session.lock().unwrap();
+        let timed_out = steps.is_multiple_of(STEPS_PER_CHECK)
+            && session.deadline.is_some_and(|d| Instant::now() >= d);
+        if running && !session.stuck && !timed_out {
+            continue;
+        }
+        let (line, expected) = match session.waiting() {
+            Some(waiting) => waiting,
+            None if running => continue,
+            None => return Ok(()),
+        };
+        return Err(if !running {
+            ScriptError::Halted { line, expected }
+        } else if session.stuck {
+            ScriptError::Stuck { line, expected }
+        } else {
+            ScriptError::Timeout { line, expected }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::io::Captured;
+
+    /// Asks for a name, greets it, and loops forever on `spin`.
+    const GREETER: &str = "
+        fn main() {
+            putc('?'); putc(' ');
+            var c = getc();
+            var n = 0;
+            putc('h'); putc('i'); putc(' ');
+            while (c != '\\n' && c != -1) { putc(c); c = getc(); n = n + 1; }
+            putc('\\n');
+            if (n == 4) { while (1) {} }
+        }
+    ";
+
+    fn session(script: &str) -> (Result<(), ScriptError>, String) {
+        let io = Captured::new(&[]);
+        let output = io.output();
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(compile(GREETER).unwrap());
+        let result = run(&mut vm, parse(script).unwrap(), Box::new(io));
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        (result, output)
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse("# login\nexpect name:\\t\nsend bob\ntimeout 0.5\n").unwrap(),
+            vec![
+                (2, Command::Expect("name:\t".to_string())),
+                (3, Command::Send("bob\n".to_string())),
+                (4, Command::Timeout(Duration::from_millis(500))),
+            ]
+        );
+        assert_eq!(
+            parse("wait 1").unwrap_err(),
+            "script line 1: unknown command `wait`"
+        );
+        for timeout in ["-1", "nan", "1e300"] {
+            assert_eq!(
+                parse(&format!("send x\ntimeout {}", timeout)).unwrap_err(),
+                "script line 2: bad timeout"
+            );
+        }
+    }
+
+    #[test]
+    fn answers_after_prompt() {
+        let (result, output) = session("expect ? \nsend sam\nexpect hi sam\\n");
+        assert_eq!(result, Ok(()));
+        assert_eq!(output, "? hi sam\n");
+    }
+
+    #[test]
+    fn matches_multibyte_output() {
+        let (result, output) = session("expect ? \nsend renée\nexpect hi renée\\n");
+        assert_eq!(result, Ok(()));
+        assert_eq!(output, "? hi renée\n");
+    }
+
+    #[test]
+    fn keeps_only_output_that_could_still_match() {
+        let mut session = Session {
+            commands: parse("expect done\nexpect !").unwrap().into(),
+            unmatched: vec![],
+            input: VecDeque::new(),
+            timeout: DEFAULT_TIMEOUT,
+            deadline: None,
+            stuck: false,
+        };
+        for &byte in b"chatter ".repeat(1000).iter().chain(b"do") {
+            session.unmatched.push(byte);
+            session.advance();
+            assert!(session.unmatched.len() < "done".len());
+        }
+        for &byte in b"ne and more!" {
+            session.unmatched.push(byte);
+            session.advance();
+        }
+        assert!(session.commands.is_empty() && session.unmatched.is_empty());
+    }
+
+    #[test]
+    fn reports_text_never_output() {
+        let (result, _) = session("expect login:\nsend sam");
+        assert_eq!(
+            result,
+            Err(ScriptError::Stuck {
+                line: 1,
+                expected: "login:".to_string()
+            })
+        );
+        let (result, _) = session("send sam\nexpect bye");
+        assert_eq!(
+            result,
+            Err(ScriptError::Halted {
+                line: 2,
+                expected: "bye".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn times_out_while_guest_spins() {
+        let (result, _) = session("send samy\ntimeout 0.05\nexpect bye");
+        assert_eq!(
+            result,
+            Err(ScriptError::Timeout {
+                line: 3,
+                expected: "bye".to_string()
+            })
+        );
+    }
+}
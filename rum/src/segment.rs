@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::ops::Index;
+
+/// Segments of at least this many words are mapped lazily.
+pub const LAZY_THRESHOLD: usize = 1 << 16;
+/// Words in each page of a lazy segment.
+pub const PAGE_WORDS: usize = 1 << 10;
+
+static ZERO: u32 = 0;
+
+///Memory segment
+/// # Variants:
+/// * `Dense`: every word is stored, used for programs and small segments.
+/// * `Lazy`: words are stored in pages that are allocated on the first
+///   non-zero write; words in missing pages read as zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Dense(Vec<u32>),
+    Lazy {
+        len: usize,
+        pages: HashMap<usize, Box<[u32]>>,
+    },
+}
+impl Segment {
+    ///Creates a zero-filled segment of `len` words, lazily if it is large
+    pub fn zeroed(len: usize) -> Self {
+        if len >= LAZY_THRESHOLD {
+            Segment::Lazy {
+                len,
+                pages: HashMap::new(),
+            }
+        } else {
+            Segment::Dense(vec![0; len])
+        }
+    }
+    ///Number of words the guest can address in the segment
+    pub fn len(&self) -> usize {
+        match self {
+            Segment::Dense(words) => words.len(),
+            Segment::Lazy { len, .. } => *len,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    ///Number of words actually allocated for the segment
+    pub fn resident_words(&self) -> usize {
+        match self {
+            Segment::Dense(words) => words.len(),
+            Segment::Lazy { pages, .. } => pages.len() * PAGE_WORDS,
+        }
+    }
+    ///Stored words as runs of offset and words, in address order
+    /// Words outside every run read as zero.
+    pub fn runs(&self) -> Vec<(usize, &[u32])> {
+        match self {
+            Segment::Dense(words) => vec![(0, &words[..])],
+            Segment::Lazy { len, pages } => {
+                let mut runs: Vec<(usize, &[u32])> = pages
+                    .iter()
+                    .map(|(&page, words)| {
+                        let offset = page * PAGE_WORDS;
+                        (offset, &words[..PAGE_WORDS.min(len - offset)])
+                    })
+                    .collect();
+                runs.sort_unstable_by_key(|&(offset, _)| offset);
+                runs
+            }
+        }
+    }
+    ///Returns the word at `index`, or `None` if it is out of bounds
+    pub fn get(&self, index: usize) -> Option<u32> {
+        match self {
+            Segment::Dense(words) => words.get(index).copied(),
+            Segment::Lazy { len, pages } => {
+                if index >= *len {
+                    return None;
+                }
+                Some(
+                    pages
+                        .get(&(index / PAGE_WORDS))
+                        .map_or(0, |page| page[index % PAGE_WORDS]),
+                )
+            }
+        }
+    }
+    ///Number of words that storing `value` at `index` would allocate
+    pub fn words_to_store(&self, index: usize, value: u32) -> usize {
+        match self {
+            Segment::Lazy { len, pages }
+                if index < *len && value != 0 && !pages.contains_key(&(index / PAGE_WORDS)) =>
+            {
+                PAGE_WORDS
+            }
+            _ => 0,
+        }
+    }
+    ///Stores `value` at `index`, returning false if it is out of bounds
+    pub fn set(&mut self, index: usize, value: u32) -> bool {
+        match self {
+            Segment::Dense(words) => match words.get_mut(index) {
+                Some(word) => {
+                    *word = value;
+                    true
+                }
+                None => false,
+            },
+            Segment::Lazy { len, pages } => {
+                if index >= *len {
+                    return false;
+                }
+                let page = index / PAGE_WORDS;
+                match pages.get_mut(&page) {
+                    Some(words) => words[index % PAGE_WORDS] = value,
+                    // Missing pages already read as zero.
+                    None if value == 0 => {}
+                    None => {
+                        let mut words = vec![0; PAGE_WORDS].into_boxed_slice();
+                        words[index % PAGE_WORDS] = value;
+                        pages.insert(page, words);
+                    }
+                }
+                true
+            }
+        }
+    }
+}
+impl From<Vec<u32>> for Segment {
+    fn from(words: Vec<u32>) -> Self {
+        Segment::Dense(words)
+    }
+}
+impl Index<usize> for Segment {
+    type Output = u32;
+    fn index(&self, index: usize) -> &u32 {
+        match self {
+            Segment::Dense(words) => &words[index],
+            Segment::Lazy { len, pages } => {
+                assert!(
+                    index < *len,
+                    "index out of bounds: the len is {} but the index is {}",
+                    len,
+                    index
+                );
+                pages
+                    .get(&(index / PAGE_WORDS))
+                    .map_or(&ZERO, |page| &page[index % PAGE_WORDS])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn small_segments_are_dense() {
+        assert!(matches!(Segment::zeroed(10), Segment::Dense(_)));
+        assert!(matches!(
+            Segment::zeroed(LAZY_THRESHOLD),
+            Segment::Lazy { .. }
+        ));
+    }
+
+    #[test]
+    fn lazy_segment_allocates_on_first_write() {
+        let mut segment = Segment::zeroed(u32::MAX as usize);
+        assert_eq!(segment.resident_words(), 0);
+        assert_eq!(segment.get(4_000_000_000), Some(0));
+        assert_eq!(segment[123], 0);
+        assert!(segment.set(123, 0));
+        assert_eq!(segment.resident_words(), 0);
+        assert_eq!(segment.words_to_store(4_000_000_000, 7), PAGE_WORDS);
+        assert!(segment.set(4_000_000_000, 7));
+        assert_eq!(segment.get(4_000_000_000), Some(7));
+        assert_eq!(segment[4_000_000_000], 7);
+        assert_eq!(segment.get(4_000_000_001), Some(0));
+        assert_eq!(segment.resident_words(), PAGE_WORDS);
+        assert_eq!(segment.words_to_store(4_000_000_001, 7), 0);
+        assert!(segment.set(5, 1));
+        let runs = segment.runs();
+        assert_eq!(runs.len(), 2);
+        assert_eq!((runs[0].0, runs[0].1[5]), (0, 1));
+        // The last page stops at the end of the segment
+        let offset = 4_000_000_000 / PAGE_WORDS * PAGE_WORDS;
+        assert_eq!(runs[1].0, offset);
+        assert_eq!(runs[1].1.len(), PAGE_WORDS.min(u32::MAX as usize - offset));
+    }
+
+    #[test]
+    fn out_of_bounds_is_rejected() {
+        let mut segment = Segment::zeroed(LAZY_THRESHOLD);
+        assert_eq!(segment.get(LAZY_THRESHOLD), None);
+        assert!(!segment.set(LAZY_THRESHOLD, 1));
+        let mut segment = Segment::zeroed(3);
+        assert_eq!(segment.get(3), None);
+        assert!(!segment.set(3, 1));
+    }
+}
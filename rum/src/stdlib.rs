@@ -0,0 +1,413 @@
+use crate::asm::{Assembler, Label};
+
+/// Always 0. Library code never writes it.
+pub const ZERO: usize = 0;
+/// Arguments of a routine, in order. The first also holds its result.
+/// Routines may overwrite all of them.
+pub const ARGUMENTS: [usize; 4] = [1, 2, 3, 4];
+/// Overwritten by every call, return, push and pop.
+pub const LINK: usize = 5;
+/// Identifier of the stack segment.
+pub const STACK: usize = 6;
+/// Index of the word on top of the stack, 0 when it is empty.
+pub const TOP: usize = 7;
+
+/// Stack size in words when the caller has no preference.
+pub const DEFAULT_STACK: u32 = 1 << 12;
+
+///Sets up the stack, before the program calls anything
+/// Maps a stack of `words` words, which bounds how deep calls can nest
+/// and how much can be pushed. `ZERO` must still hold 0.
+pub fn start(asm: &mut Assembler, words: u32) {
+    asm.loadv(LINK, words);
+    asm.map(STACK, LINK);
+    asm.loadv(TOP, 0);
+}
+
+///Calls the routine at `label`
+/// The return address goes on the stack, for `ret` to take off again.
+pub fn call(asm: &mut Assembler, label: Label) {
+    let back = asm.label();
+    increment(asm, TOP);
+    asm.loadv_label(LINK, back);
+    asm.store(STACK, TOP, LINK);
+    asm.jump(label, ZERO, LINK);
+    asm.bind(back);
+}
+
+///Returns from a routine to its caller
+/// Whatever the routine pushed must have been popped.
+pub fn ret(asm: &mut Assembler) {
+    decrement(asm, TOP);
+    increment_into(asm, LINK, TOP);
+    asm.load(LINK, STACK, LINK);
+    asm.loadp(ZERO, LINK);
+}
+
+///Pushes register `r` onto the stack
+pub fn push(asm: &mut Assembler, r: usize) {
+    assert_ne!(r, LINK, "cannot push the link register");
+    increment(asm, TOP);
+    asm.store(STACK, TOP, r);
+}
+
+///Pops the word on top of the stack into register `r`
+pub fn pop(asm: &mut Assembler, r: usize) {
+    assert_ne!(r, LINK, "cannot pop into the link register");
+    asm.load(r, STACK, TOP);
+    decrement(asm, TOP);
+}
+
+/// r := r + 1
+fn increment(asm: &mut Assembler, r: usize) {
+    increment_into(asm, r, r);
+}
+
+/// a := b + 1
+fn increment_into(asm: &mut Assembler, a: usize, b: usize) {
+    asm.loadv(LINK, 1);
+    asm.add(a, b, LINK);
+}
+
+/// r := r - 1
+fn decrement(asm: &mut Assembler, r: usize) {
+    asm.nand(LINK, ZERO, ZERO);
+    asm.add(r, r, LINK);
+}
+
+/// a := b
+fn copy(asm: &mut Assembler, a: usize, b: usize) {
+    asm.add(a, b, ZERO);
+}
+
+///Routines of the library
+/// Arguments and results are in `ARGUMENTS`, in order. Heap blocks are
+/// segments whose word 0 holds their size, followed by that many words.
+/// # Variants:
+/// * `PrintDecimal`: outputs the first argument as an unsigned decimal number.
+/// * `ReadLine`: reads a line into the block in the first argument, of at
+///   most as many bytes as the second argument, with the newline left out.
+///   Sets the size to the number of bytes read and returns it, with the
+///   second argument all ones if the input ended and 0 otherwise.
+/// * `Memcpy`: copies as many words as the third argument from the start
+///   of the segment in the second argument to the one in the first.
+/// * `Alloc`: returns a new block of as many zeros as the first argument.
+/// * `Free`: frees the block in the first argument.
+/// * `Grow`: returns a copy of the block in the first argument with its
+///   size raised to the second argument, padded with zeros, and frees the
+///   block. The new size must not be smaller.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Routine {
+    PrintDecimal,
+    ReadLine,
+    Memcpy,
+    Alloc,
+    Free,
+    Grow,
+}
+
+///Routines a program calls, to be emitted after its code
+/// # Parameters:
+/// * `entries`: Entry of each routine called so far, in the order first called.
+/// * `emitted`: How many of `entries` have been emitted.
+#[derive(Debug, Default)]
+pub struct Library {
+    entries: Vec<(Routine, Label)>,
+    emitted: usize,
+}
+impl Library {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Calls `routine`, which `finish` will emit
+    pub fn call(&mut self, asm: &mut Assembler, routine: Routine) {
+        let entry = self.entry(asm, routine);
+        call(asm, entry);
+    }
+
+    /// Entry of `routine`, a new label the first time it is asked for.
+    fn entry(&mut self, asm: &mut Assembler, routine: Routine) -> Label {
+        match self.entries.iter().find(|(r, _)| *r == routine) {
+            Some(&(_, label)) => label,
+            None => {
+                let label = asm.label();
+                self.entries.push((routine, label));
+                label
+            }
+        }
+    }
+
+    ///Emits every routine called, and those they call in turn
+    /// The program must not run into them, so this goes after its halt.
+    pub fn finish(mut self, asm: &mut Assembler) {
+        while let Some(&(routine, entry)) = self.entries.get(self.emitted) {
+            self.emitted += 1;
+            asm.bind(entry);
+            match routine {
+                Routine::PrintDecimal => print_decimal(asm),
+                Routine::ReadLine => read_line(asm),
+                Routine::Memcpy => memcpy(asm),
+                Routine::Alloc => alloc(asm),
+                Routine::Free => {
+                    asm.unmap(ARGUMENTS[0]);
+                    ret(asm);
+                }
+                Routine::Grow => self.grow(asm),
+            }
+        }
+    }
+
+    fn grow(&mut self, asm: &mut Assembler) {
+        let [block, words, count, new] = ARGUMENTS;
+        push(asm, block);
+        push(asm, words);
+        increment_into(asm, count, words);
+        asm.map(new, count);
+        push(asm, new);
+        // Copies the old size along with the words, then sets the new one
+        asm.load(count, block, ZERO);
+        increment(asm, count);
+        copy(asm, words, block);
+        copy(asm, block, new);
+        self.call(asm, Routine::Memcpy);
+        pop(asm, block);
+        pop(asm, words);
+        asm.store(block, ZERO, words);
+        pop(asm, count);
+        asm.unmap(count);
+        ret(asm);
+    }
+}
+
+/// Divides by 10 until nothing is left, pushing each digit, then pops
+/// and outputs them.
+fn print_decimal(asm: &mut Assembler) {
+    let [value, count, ten, digit] = ARGUMENTS;
+    let (divide, print, done) = (asm.label(), asm.label(), asm.label());
+    asm.loadv(ten, 10);
+    asm.loadv(count, 0);
+    asm.bind(divide);
+    // value mod 10 is value + !(value / 10 * 10) + 1
+    asm.div(digit, value, ten);
+    asm.mul(LINK, digit, ten);
+    asm.nand(LINK, LINK, LINK);
+    asm.add(LINK, value, LINK);
+    asm.cmov(value, digit, ten);
+    asm.loadv(digit, '0' as u32 + 1);
+    asm.add(digit, digit, LINK);
+    push(asm, digit);
+    increment(asm, count);
+    asm.branch(value, divide, print, ZERO, [digit, LINK]);
+    asm.bind(print);
+    pop(asm, digit);
+    asm.output(digit);
+    decrement(asm, count);
+    asm.branch(count, print, done, ZERO, [digit, LINK]);
+    asm.bind(done);
+    ret(asm);
+}
+
+/// Stores each byte after the bytes so far before looking at it, since
+/// there are too few registers to keep it while branching.
+fn read_line(asm: &mut Assembler) {
+    let [buffer, remaining, byte, slot] = ARGUMENTS;
+    let [top, read, check, keep, end_of_input, ended, finish] = [(); 7].map(|_| asm.label());
+    asm.store(buffer, ZERO, ZERO);
+    asm.bind(top);
+    asm.branch(remaining, read, ended, ZERO, [slot, LINK]);
+    asm.bind(read);
+    asm.input(byte);
+    asm.load(slot, buffer, ZERO);
+    increment(asm, slot);
+    asm.store(buffer, slot, byte);
+    // Only the end of input is all ones
+    asm.nand(slot, byte, byte);
+    asm.branch(slot, check, end_of_input, ZERO, [byte, LINK]);
+    asm.bind(check);
+    asm.load(slot, buffer, ZERO);
+    increment(asm, slot);
+    asm.load(byte, buffer, slot);
+    // !9 is -10
+    asm.loadv(LINK, 9);
+    asm.nand(LINK, LINK, LINK);
+    asm.add(byte, byte, LINK);
+    asm.branch(byte, keep, ended, ZERO, [slot, LINK]);
+    asm.bind(keep);
+    asm.load(slot, buffer, ZERO);
+    increment(asm, slot);
+    asm.store(buffer, ZERO, slot);
+    decrement(asm, remaining);
+    asm.jump(top, ZERO, LINK);
+    asm.bind(end_of_input);
+    asm.nand(byte, ZERO, ZERO);
+    asm.jump(finish, ZERO, LINK);
+    asm.bind(ended);
+    asm.loadv(byte, 0);
+    asm.bind(finish);
+    asm.load(buffer, buffer, ZERO);
+    copy(asm, remaining, byte);
+    ret(asm);
+}
+
+/// Copies from the last word down, counting down to 0.
+fn memcpy(asm: &mut Assembler) {
+    let [destination, source, count, word] = ARGUMENTS;
+    let (top, next, done) = (asm.label(), asm.label(), asm.label());
+    asm.bind(top);
+    asm.branch(count, next, done, ZERO, [word, LINK]);
+    asm.bind(next);
+    decrement(asm, count);
+    asm.load(word, source, count);
+    asm.store(destination, count, word);
+    asm.jump(top, ZERO, LINK);
+    asm.bind(done);
+    ret(asm);
+}
+
+fn alloc(asm: &mut Assembler) {
+    let [words, size, block, _] = ARGUMENTS;
+    increment_into(asm, size, words);
+    asm.map(block, size);
+    asm.store(block, ZERO, words);
+    copy(asm, words, block);
+    ret(asm);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::Captured;
+    use crate::machine::VirtualMachine;
+
+    /// Runs what `body` emits between `start` and the library, on `input`.
+    fn run(input: &[u8], body: impl Fn(&mut Assembler, &mut Library)) -> (VirtualMachine, Vec<u8>) {
+        let mut asm = Assembler::new();
+        let mut library = Library::new();
+        start(&mut asm, DEFAULT_STACK);
+        body(&mut asm, &mut library);
+        asm.halt();
+        library.finish(&mut asm);
+        let io = Captured::new(input);
+        let output = io.output();
+        let mut vm = VirtualMachine::new();
+        vm.set_io(Box::new(io));
+        vm.initialize_machine(asm.finish());
+        vm.run_program().unwrap();
+        assert_eq!(vm.registers[TOP], 0, "stack left unbalanced");
+        let output = output.lock().unwrap().clone();
+        (vm, output)
+    }
+
+    /// Words of block `id`, after its size.
+    fn block(vm: &VirtualMachine, id: u32) -> Vec<u32> {
+        let segment = &vm.memory[&id];
+        let size = segment.get(0).unwrap() as usize;
+        (1..=size).map(|i| segment.get(i).unwrap()).collect()
+    }
+
+    #[test]
+    fn prints_decimal_numbers() {
+        let (_, output) = run(&[], |asm, library| {
+            for value in [0, 7, 10, 1_234_567_890, u32::MAX] {
+                asm.constant(ARGUMENTS[0], value, LINK);
+                library.call(asm, Routine::PrintDecimal);
+                asm.loadv(ARGUMENTS[0], ' ' as u32);
+                asm.output(ARGUMENTS[0]);
+            }
+        });
+        assert_eq!(output, b"0 7 10 1234567890 4294967295 ");
+    }
+
+    #[test]
+    fn reads_lines() {
+        // Reads four times into blocks of 8 bytes, the second line being too long for one
+        let (vm, _) = run(b"hello\nthis is long\nend", |asm, library| {
+            for _ in 0..4 {
+                asm.loadv(ARGUMENTS[0], 8);
+                library.call(asm, Routine::Alloc);
+                asm.loadv(ARGUMENTS[1], 8);
+                library.call(asm, Routine::ReadLine);
+            }
+        });
+        assert_eq!(vm.registers[1..3], [3, u32::MAX]);
+        let bytes = |id: u32| -> Vec<u8> { block(&vm, id).iter().map(|&b| b as u8).collect() };
+        assert_eq!(bytes(2), b"hello");
+        assert_eq!(bytes(3), b"this is ");
+        assert_eq!(bytes(4), b"long");
+        assert_eq!(bytes(5), b"end");
+    }
+
+    #[test]
+    fn copies_between_segments() {
+        let (vm, _) = run(&[], |asm, library| {
+            let [destination, source, count, word] = ARGUMENTS;
+            asm.loadv(count, 4);
+            asm.map(source, count);
+            for index in 0..4 {
+                asm.loadv(word, 10 + index);
+                asm.loadv(count, index);
+                asm.store(source, count, word);
+            }
+            asm.loadv(count, 5);
+            asm.map(destination, count);
+            asm.loadv(count, 3);
+            library.call(asm, Routine::Memcpy);
+        });
+        let destination = &vm.memory[&3];
+        let words: Vec<u32> = (0..5).map(|i| destination.get(i).unwrap()).collect();
+        assert_eq!(words, vec![10, 11, 12, 0, 0]);
+    }
+
+    #[test]
+    fn grows_and_frees_blocks() {
+        let (vm, _) = run(&[], |asm, library| {
+            let [block, words, _, value] = ARGUMENTS;
+            asm.loadv(block, 2);
+            library.call(asm, Routine::Alloc);
+            asm.loadv(words, 2);
+            asm.loadv(value, 42);
+            asm.store(block, words, value);
+            asm.loadv(words, 4);
+            library.call(asm, Routine::Grow);
+            push(asm, block);
+            asm.loadv(block, 1);
+            library.call(asm, Routine::Alloc);
+            library.call(asm, Routine::Free);
+            pop(asm, block);
+        });
+        // Segment 1 is the stack, the first block 2, and the grown one 3
+        assert_eq!(vm.registers[1], 3);
+        assert_eq!(block(&vm, 3), vec![0, 42, 0, 0]);
+        let mut mapped: Vec<u32> = vm.memory.keys().copied().collect();
+        mapped.sort_unstable();
+        assert_eq!(mapped, vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn nests_calls() {
+        // Prints n, n - 1, ..., 1 by recursion
+        let (_, output) = run(&[], |asm, library| {
+            let countdown = asm.label();
+            let (more, done) = (asm.label(), asm.label());
+            let skip = asm.label();
+            asm.loadv(ARGUMENTS[0], 12);
+            call(asm, countdown);
+            asm.jump(skip, ZERO, LINK);
+            asm.bind(countdown);
+            asm.branch(ARGUMENTS[0], more, done, ZERO, [ARGUMENTS[1], LINK]);
+            asm.bind(more);
+            push(asm, ARGUMENTS[0]);
+            library.call(asm, Routine::PrintDecimal);
+            asm.loadv(ARGUMENTS[0], ',' as u32);
+            asm.output(ARGUMENTS[0]);
+            pop(asm, ARGUMENTS[0]);
+            decrement(asm, ARGUMENTS[0]);
+            call(asm, countdown);
+            asm.bind(done);
+            ret(asm);
+            asm.bind(skip);
+        });
+        assert_eq!(output, b"12,11,10,9,8,7,6,5,4,3,2,1,");
+    }
+}
@@ -0,0 +1,192 @@
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+///Addresses `start..end` of $m[0] belonging to a label
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Label {
+    pub start: u32,
+    pub end: u32,
+    pub name: String,
+}
+
+///Addresses `start..end` of $m[0] generated from one source line
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Line {
+    pub start: u32,
+    pub end: u32,
+    pub file: String,
+    pub line: u32,
+}
+
+///Debug symbols of a program, kept in a `.umsym` file beside it
+/// Ranges are sorted by start and do not overlap.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Symbols {
+    pub labels: Vec<Label>,
+    pub lines: Vec<Line>,
+}
+impl Symbols {
+    ///Path of the sidecar file for the program at `program`
+    pub fn sidecar(program: &Path) -> PathBuf {
+        program.with_extension("umsym")
+    }
+
+    ///Whether there are no symbols at all
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.lines.is_empty()
+    }
+    ///Label containing `address` and the offset into it
+    pub fn label_at(&self, address: u32) -> Option<(&str, u32)> {
+        find(&self.labels, address, |l| (l.start, l.end))
+            .map(|label| (label.name.as_str(), address - label.start))
+    }
+    ///Source file and line `address` was generated from
+    pub fn line_at(&self, address: u32) -> Option<(&str, u32)> {
+        find(&self.lines, address, |l| (l.start, l.end)).map(|line| (line.file.as_str(), line.line))
+    }
+    ///Describes `address` as `label+offset (file:line)`, as far as known
+    pub fn describe(&self, address: u32) -> String {
+        self.lookup(address).unwrap_or_else(|| address.to_string())
+    }
+    ///Describes `address` like `describe`, or `None` if no symbol covers it
+    pub fn lookup(&self, address: u32) -> Option<String> {
+        let label = self.label_at(address).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            _ => format!("{}+{}", name, offset),
+        });
+        let line = self
+            .line_at(address)
+            .map(|(file, line)| format!("({}:{})", file, line));
+        match (label, line) {
+            (Some(label), Some(line)) => Some(format!("{} {}", label, line)),
+            (label, line) => label.or(line),
+        }
+    }
+
+    ///Writes the symbols in the `.umsym` text format
+    /// A header line, then `label <start> <end> <name>` and
+    /// `line <start> <end> <file> <line>` lines with tabs between the
+    /// fields, so files may contain spaces. Names and files must not
+    /// contain tabs or line breaks.
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "rum symbols 2")?;
+        for label in &self.labels {
+            writeln!(out, "label\t{}\t{}\t{}", label.start, label.end, label.name)?;
+        }
+        for line in &self.lines {
+            writeln!(
+                out,
+                "line\t{}\t{}\t{}\t{}",
+                line.start, line.end, line.file, line.line
+            )?;
+        }
+        Ok(())
+    }
+
+    ///Reads symbols written by `write`
+    /// Files of version 1, whose fields are separated by any whitespace,
+    /// are still read.
+    pub fn read(input: &mut dyn BufRead) -> io::Result<Symbols> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad symbols line: {}", line),
+            )
+        };
+        let mut lines = input.lines();
+        let tabbed = match lines.next() {
+            Some(Ok(header)) if header == "rum symbols 1" => false,
+            Some(Ok(header)) if header == "rum symbols 2" => true,
+            _ => return Err(invalid("missing header")),
+        };
+        let mut symbols = Symbols::default();
+        for text in lines {
+            let text = text?;
+            let fields: Vec<&str> = match tabbed {
+                true => text.split('\t').collect(),
+                false => text.split_whitespace().collect(),
+            };
+            let number = |field: &str| field.parse().map_err(|_| invalid(&text));
+            match fields[..] {
+                ["label", start, end, name] => symbols.labels.push(Label {
+                    start: number(start)?,
+                    end: number(end)?,
+                    name: name.to_string(),
+                }),
+                ["line", start, end, file, line] => symbols.lines.push(Line {
+                    start: number(start)?,
+                    end: number(end)?,
+                    file: file.to_string(),
+                    line: number(line)?,
+                }),
+                _ => return Err(invalid(&text)),
+            }
+        }
+        symbols.labels.sort_by_key(|l| l.start);
+        symbols.lines.sort_by_key(|l| l.start);
+        Ok(symbols)
+    }
+}
+
+/// Finds the range containing `address` in ranges sorted by start.
+fn find<T>(ranges: &[T], address: u32, range: impl Fn(&T) -> (u32, u32)) -> Option<&T> {
+    let after = ranges.partition_point(|r| range(r).0 <= address);
+    let candidate = ranges.get(after.checked_sub(1)?)?;
+    (address < range(candidate).1).then_some(candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn sample() -> Symbols {
+        Symbols {
+            labels: vec![
+                Label {
+                    start: 0,
+                    end: 4,
+                    name: "_start".to_string(),
+                },
+                Label {
+                    start: 10,
+                    end: 30,
+                    name: "main".to_string(),
+                },
+            ],
+            lines: vec![Line {
+                start: 20,
+                end: 25,
+                file: "prog.uma".to_string(),
+                line: 34,
+            }],
+        }
+    }
+
+    #[test]
+    fn describes_addresses() {
+        let symbols = sample();
+        assert_eq!(symbols.describe(0), "_start");
+        assert_eq!(symbols.describe(22), "main+12 (prog.uma:34)");
+        assert_eq!(symbols.describe(12), "main+2");
+        assert_eq!(symbols.describe(7), "7");
+        assert_eq!(symbols.describe(30), "30");
+        assert_eq!(symbols.lookup(30), None);
+    }
+
+    #[test]
+    fn round_trips_through_text() {
+        let mut text = vec![];
+        sample().write(&mut text).unwrap();
+        assert_eq!(Symbols::read(&mut &text[..]).unwrap(), sample());
+        let mut spaced = sample();
+        spaced.lines[0].file = "my programs/prog.uma".to_string();
+        let mut text = vec![];
+        spaced.write(&mut text).unwrap();
+        assert_eq!(Symbols::read(&mut &text[..]).unwrap(), spaced);
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let text = "rum symbols 1\nlabel 0 4 _start\nlabel 10 30 main\nline 20 25 prog.uma 34\n";
+        assert_eq!(Symbols::read(&mut text.as_bytes()).unwrap(), sample());
+    }
+}
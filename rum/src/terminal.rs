@@ -0,0 +1,65 @@
+use std::io;
+use std::mem::MaybeUninit;
+use std::sync::OnceLock;
+
+/// Settings of standard input before raw mode, for the Ctrl-C handler.
+static SAVED: OnceLock<libc::termios> = OnceLock::new();
+
+///Standard input in raw mode while this is alive
+/// Bytes are delivered as soon as they are typed and are not echoed.
+/// Ctrl-C still interrupts the process. The terminal is restored when this
+/// is dropped, or by the signal handler before the process exits on Ctrl-C.
+/// Note that `process::exit` does not run destructors, so drop this first.
+pub struct RawMode {
+    saved: libc::termios,
+}
+impl RawMode {
+    ///Puts standard input in raw mode
+    /// Fails if standard input is not a terminal.
+    pub fn enable() -> io::Result<RawMode> {
+        let mut termios = MaybeUninit::uninit();
+        // SAFETY: tcgetattr fills `termios` when it succeeds.
+        let saved = unsafe {
+            if libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            termios.assume_init()
+        };
+        let _ = SAVED.set(saved);
+        // SAFETY: the handler only calls async-signal-safe functions.
+        unsafe {
+            let handler: extern "C" fn(libc::c_int) = interrupted;
+            libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        }
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        set(&raw)?;
+        Ok(RawMode { saved })
+    }
+}
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = set(&self.saved);
+    }
+}
+
+fn set(termios: &libc::termios) -> io::Result<()> {
+    // SAFETY: `termios` is a valid, initialized termios.
+    match unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, termios) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Restores the terminal and exits as a shell expects after Ctrl-C.
+extern "C" fn interrupted(_signal: libc::c_int) {
+    // SAFETY: tcsetattr and _exit are async-signal-safe.
+    unsafe {
+        if let Some(saved) = SAVED.get() {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved);
+        }
+        libc::_exit(128 + libc::SIGINT)
+    }
+}
@@ -0,0 +1,500 @@
+use crate::io::IoDevice;
+use crate::machine::{MachineError, VirtualMachine};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+/// First line of a transcript.
+const HEADER: &str = "rum transcript 1";
+
+///What crossed the I/O device
+/// # Variants:
+/// * `Input`: the guest read a byte, or `None` at the end of input.
+/// * `Output`: the guest output a byte.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Kind {
+    Input(Option<u8>),
+    Output(u8),
+}
+
+///One byte read or written by the guest
+/// `step` is the number of instructions executed before the one that read
+/// or wrote it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Event {
+    pub step: u64,
+    pub kind: Kind,
+}
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            Kind::Input(Some(byte)) => write!(f, "input of byte {}", byte)?,
+            Kind::Input(None) => write!(f, "end of input")?,
+            Kind::Output(byte) => write!(f, "output of byte {}", byte)?,
+        }
+        write!(f, " at step {}", self.step)
+    }
+}
+
+///Every byte a guest read and wrote, in order
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Transcript {
+    pub events: Vec<Event>,
+}
+impl Transcript {
+    ///Writes the transcript as text
+    /// A header line, then one `<step> in <byte>|eof` or
+    /// `<step> out <byte>` line per event, bytes in decimal.
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{}", HEADER)?;
+        for event in &self.events {
+            write_event(out, event)?;
+        }
+        Ok(())
+    }
+
+    ///Reads a transcript written by `write`
+    pub fn read(input: &mut dyn BufRead) -> io::Result<Transcript> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad transcript line: {}", line),
+            )
+        };
+        let mut lines = input.lines();
+        match lines.next() {
+            Some(Ok(header)) if header == HEADER => {}
+            _ => return Err(invalid("missing header")),
+        }
+        let mut transcript = Transcript::default();
+        for line in lines {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (step, kind) = match fields[..] {
+                [step, "in", "eof"] => (step, Kind::Input(None)),
+                [step, "in", byte] => (
+                    step,
+                    Kind::Input(Some(byte.parse().map_err(|_| invalid(&line))?)),
+                ),
+                [step, "out", byte] => (
+                    step,
+                    Kind::Output(byte.parse().map_err(|_| invalid(&line))?),
+                ),
+                _ => return Err(invalid(&line)),
+            };
+            let step = step.parse().map_err(|_| invalid(&line))?;
+            transcript.events.push(Event { step, kind });
+        }
+        Ok(transcript)
+    }
+}
+
+/// Writes the line of one event, as `Transcript::write` does.
+fn write_event(out: &mut dyn Write, event: &Event) -> io::Result<()> {
+    match event.kind {
+        Kind::Input(Some(byte)) => writeln!(out, "{} in {}", event.step, byte),
+        Kind::Input(None) => writeln!(out, "{} in eof", event.step),
+        Kind::Output(byte) => writeln!(out, "{} out {}", event.step, byte),
+    }
+}
+
+///Why a recording failed
+/// # Variants:
+/// * `Io`: the transcript could not be written.
+/// * `Machine`: the machine failed, after the transcript up to then was written.
+#[derive(Debug)]
+pub enum RecordError {
+    Io(io::Error),
+    Machine(MachineError),
+}
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordError::Io(error) => error.fmt(f),
+            RecordError::Machine(error) => error.fmt(f),
+        }
+    }
+}
+impl Error for RecordError {}
+
+///What the guest did where a replay diverged
+/// # Variants:
+/// * `Input`: it read input.
+/// * `Output`: it output this byte.
+/// * `Halt`: it halted.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Found {
+    Input,
+    Output(u8),
+    Halt,
+}
+
+///Why a replay failed
+/// # Variants:
+/// * `Diverged`: at `step` the guest did not do what the transcript
+///   expected next, which is `None` past its end.
+/// * `Machine`: the machine failed.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ReplayError {
+    Diverged {
+        step: u64,
+        expected: Option<Event>,
+        found: Found,
+    },
+    Machine(MachineError),
+}
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Diverged {
+                step,
+                expected,
+                found,
+            } => {
+                write!(f, "replay diverged at step {}: the guest ", step)?;
+                match found {
+                    Found::Input => write!(f, "read input")?,
+                    Found::Output(byte) => write!(f, "output byte {}", byte)?,
+                    Found::Halt => write!(f, "halted")?,
+                }
+                match expected {
+                    Some(event) => write!(f, ", but the transcript has {}", event),
+                    None => write!(f, ", but the transcript has ended"),
+                }
+            }
+            ReplayError::Machine(error) => error.fmt(f),
+        }
+    }
+}
+impl Error for ReplayError {}
+
+/// State shared by the devices and the step loops that drive them.
+/// `step` counts the instructions executed so far, `expected` holds the
+/// events a replay has yet to see and `divergence` the first one it missed.
+#[derive(Debug, Default)]
+struct Tape {
+    step: u64,
+    expected: VecDeque<Event>,
+    divergence: Option<ReplayError>,
+}
+impl Tape {
+    fn diverge(&mut self, expected: Option<Event>, found: Found) {
+        self.divergence.get_or_insert(ReplayError::Diverged {
+            step: self.step,
+            expected,
+            found,
+        });
+    }
+}
+
+/// Where a recording is written, shared by the recorder and `record`.
+/// `failed` keeps the first write error, after which nothing more is written.
+struct Log {
+    out: Box<dyn Write + Send>,
+    failed: Option<io::Error>,
+}
+impl Log {
+    fn note(&mut self, event: Event) {
+        if self.failed.is_none() {
+            self.failed = write_event(&mut self.out, &event).err();
+        }
+    }
+    fn flush(&mut self) {
+        if self.failed.is_none() {
+            self.failed = self.out.flush().err();
+        }
+    }
+}
+
+/// Device passing everything through to `inner` while writing it down.
+/// The log is flushed before each read, which is where an interactive
+/// session usually waits and gets interrupted.
+struct Recorder {
+    tape: Arc<Mutex<Tape>>,
+    log: Arc<Mutex<Log>>,
+    inner: Box<dyn IoDevice>,
+}
+impl IoDevice for Recorder {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.log.lock().unwrap().flush();
+        let byte = self.inner.read_byte();
+        let step = self.tape.lock().unwrap().step;
+        self.log.lock().unwrap().note(Event {
+            step,
+            kind: Kind::Input(byte),
+        });
+        byte
+    }
+    fn write_byte(&mut self, byte: u8) {
+        self.inner.write_byte(byte);
+        let step = self.tape.lock().unwrap().step;
+        self.log.lock().unwrap().note(Event {
+            step,
+            kind: Kind::Output(byte),
+        });
+    }
+}
+
+/// Device giving the recorded input and checking output against the
+/// transcript, passing the output on to `echo`.
+struct Replayer {
+    tape: Arc<Mutex<Tape>>,
+    echo: Box<dyn IoDevice>,
+}
+impl IoDevice for Replayer {
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut tape = self.tape.lock().unwrap();
+        let expected = tape.expected.pop_front();
+        match expected {
+            Some(Event {
+                step,
+                kind: Kind::Input(byte),
+            }) if step == tape.step => byte,
+            _ => {
+                tape.diverge(expected, Found::Input);
+                None
+            }
+        }
+    }
+    fn write_byte(&mut self, byte: u8) {
+        self.echo.write_byte(byte);
+        let mut tape = self.tape.lock().unwrap();
+        let expected = tape.expected.pop_front();
+        let found = Event {
+            step: tape.step,
+            kind: Kind::Output(byte),
+        };
+        if expected != Some(found) {
+            tape.diverge(expected, Found::Output(byte));
+        }
+    }
+}
+
+///Runs the machine with `inner` as its device, writing what crosses it to `out`
+/// Each event is written as it happens, in the format of
+/// `Transcript::write`, so the transcript survives a run that fails or is
+/// interrupted up to the guest's last read.
+pub fn record(
+    vm: &mut VirtualMachine,
+    inner: Box<dyn IoDevice>,
+    out: Box<dyn Write + Send>,
+) -> Result<(), RecordError> {
+    let tape = Arc::new(Mutex::new(Tape::default()));
+    let mut log = Log { out, failed: None };
+    log.failed = writeln!(log.out, "{}", HEADER).err();
+    let log = Arc::new(Mutex::new(log));
+    vm.set_io(Box::new(Recorder {
+        tape: Arc::clone(&tape),
+        log: Arc::clone(&log),
+        inner,
+    }));
+    let result = loop {
+        match vm.step() {
+            Ok(true) => tape.lock().unwrap().step += 1,
+            Ok(false) => break Ok(()),
+            Err(error) => break Err(error),
+        }
+    };
+    let mut log = log.lock().unwrap();
+    log.flush();
+    match log.failed.take() {
+        Some(error) => Err(RecordError::Io(error)),
+        None => result.map_err(RecordError::Machine),
+    }
+}
+
+///Runs the machine on the input of `transcript`, checking that it gives the same output
+/// Output goes to `echo` as well. The replay stops at the first event that
+/// differs from the transcript, including its step.
+pub fn replay(
+    vm: &mut VirtualMachine,
+    transcript: &Transcript,
+    echo: Box<dyn IoDevice>,
+) -> Result<(), ReplayError> {
+    let tape = Arc::new(Mutex::new(Tape {
+        expected: transcript.events.iter().copied().collect(),
+        ..Tape::default()
+    }));
+    vm.set_io(Box::new(Replayer {
+        tape: Arc::clone(&tape),
+        echo,
+    }));
+    loop {
+        let running = vm.step().map_err(ReplayError::Machine)?;
+        let mut tape = tape.lock().unwrap();
+        if let Some(divergence) = tape.divergence.take() {
+            return Err(divergence);
+        }
+        if !running {
+            return match tape.expected.pop_front() {
+                Some(expected) => Err(ReplayError::Diverged {
+                    step: tape.step,
+                    expected: Some(expected),
+                    found: Found::Halt,
+                }),
+                None => Ok(()),
+            };
+        }
+        tape.step += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::io::Captured;
+
+    /// Echoes its input up to a newline, then prints `!`.
+    const ECHO: &str = "
+        fn main() {
+            var c = getc();
+            while (c != '\\n' && c != -1) { putc(c); c = getc(); }
+            putc('!');
+        }
+    ";
+
+    fn machine(source: &str) -> VirtualMachine {
+        let mut vm = VirtualMachine::new();
+        vm.initialize_machine(compile(source).unwrap());
+        vm
+    }
+
+    /// Buffer that stays readable after being handed to `record`.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn recorded(input: &[u8]) -> Transcript {
+        let written = Shared::default();
+        let io = Box::new(Captured::new(input));
+        record(&mut machine(ECHO), io, Box::new(written.clone())).unwrap();
+        let text = written.0.lock().unwrap();
+        Transcript::read(&mut &text[..]).unwrap()
+    }
+
+    #[test]
+    fn records_interleaved_events() {
+        let transcript = recorded(b"ab");
+        let kinds: Vec<Kind> = transcript.events.iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                Kind::Input(Some(b'a')),
+                Kind::Output(b'a'),
+                Kind::Input(Some(b'b')),
+                Kind::Output(b'b'),
+                Kind::Input(None),
+                Kind::Output(b'!'),
+            ]
+        );
+        assert!(transcript.events.windows(2).all(|w| w[0].step < w[1].step));
+        let mut text = vec![];
+        transcript.write(&mut text).unwrap();
+        assert_eq!(Transcript::read(&mut &text[..]).unwrap(), transcript);
+    }
+
+    /// Input that notes how many lines of the transcript were written by each read.
+    struct Peeking {
+        written: Shared,
+        lines: Arc<Mutex<Vec<usize>>>,
+    }
+    impl IoDevice for Peeking {
+        fn read_byte(&mut self) -> Option<u8> {
+            let written = self.written.0.lock().unwrap();
+            let lines = written.iter().filter(|&&b| b == b'\n').count();
+            self.lines.lock().unwrap().push(lines);
+            Some(b'\n')
+        }
+        fn write_byte(&mut self, _byte: u8) {}
+    }
+
+    #[test]
+    fn writes_events_as_they_happen() {
+        let written = Shared::default();
+        let lines = Arc::new(Mutex::new(vec![]));
+        let io = Box::new(Peeking {
+            written: written.clone(),
+            lines: Arc::clone(&lines),
+        });
+        let greeter = "fn main() { putc('>'); putc(' '); var c = getc(); putc(c); }";
+        let vm = &mut machine(greeter);
+        record(vm, io, Box::new(written.clone())).unwrap();
+        // The header and both outputs were written when the guest read
+        assert_eq!(*lines.lock().unwrap(), vec![3]);
+        let text = String::from_utf8(written.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(text.lines().count(), 5);
+    }
+
+    #[test]
+    fn reports_write_errors() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _bytes: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk full"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let io = Box::new(Captured::new(b"hi\n"));
+        let result = record(&mut machine(ECHO), io, Box::new(Broken));
+        assert_eq!(result.unwrap_err().to_string(), "disk full");
+    }
+
+    #[test]
+    fn replays_recorded_session() {
+        let transcript = recorded(b"hi\n");
+        let io = Captured::new(&[]);
+        let output = io.output();
+        assert_eq!(
+            replay(&mut machine(ECHO), &transcript, Box::new(io)),
+            Ok(())
+        );
+        assert_eq!(&output.lock().unwrap()[..], b"hi!");
+    }
+
+    #[test]
+    fn flags_first_divergence() {
+        let transcript = recorded(b"hi\n");
+        let changed = ECHO.replace("putc('!')", "putc('?')");
+        let result = replay(
+            &mut machine(&changed),
+            &transcript,
+            Box::new(Captured::new(&[])),
+        );
+        let last = *transcript.events.last().unwrap();
+        assert_eq!(
+            result,
+            Err(ReplayError::Diverged {
+                step: last.step,
+                expected: Some(last),
+                found: Found::Output(b'?'),
+            })
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            format!(
+                "replay diverged at step {0}: the guest output byte 63, \
+                 but the transcript has output of byte 33 at step {0}",
+                last.step
+            )
+        );
+        let mut shorter = transcript.clone();
+        shorter.events.pop();
+        let result = replay(&mut machine(ECHO), &shorter, Box::new(Captured::new(&[])));
+        assert!(matches!(
+            result,
+            Err(ReplayError::Diverged { expected: None, .. })
+        ));
+    }
+}
@@ -0,0 +1,168 @@
+use crate::analysis::Analysis;
+use crate::disasm::{decode, Instruction};
+use std::fmt::Write;
+
+///Translates a UM program into Rust source
+/// The output is a standalone `main` that depends on this crate. It holds
+/// one `match` arm per reachable address of $m[0]. Register instructions
+/// run natively, while memory, I/O and Load Program go through
+/// `VirtualMachine::step`. Once $m[0] is written or replaced the
+/// translated code no longer matches it, so the rest of the run is left to
+/// the interpreter, as are addresses the analysis did not reach.
+/// # Arguments:
+///  * `program`: program in binary to be translated
+///  * `name`: name of the image, for the header comment
+pub fn translate(program: &[u32], name: &str) -> String {
+    let analysis = Analysis::new(program);
+    let mut out = String::new();
+    writeln!(out, "// Translated from {} by rum2rs.", name).unwrap();
+    writeln!(out, "// Build it in a crate that depends on `rum`.").unwrap();
+    out.push_str(
+        "use rum::machine::{MachineError, VirtualMachine};
+
+fn main() {
+    let mut vm = VirtualMachine::new();
+    vm.initialize_machine(PROGRAM.to_vec());
+    if let Err(error) = run(&mut vm) {
+        eprintln!(\"rum: {}\", error);
+        std::process::exit(1);
+    }
+}
+
+",
+    );
+    writeln!(out, "static PROGRAM: [u32; {}] = [", program.len()).unwrap();
+    for line in program.chunks(8) {
+        let words: Vec<String> = line.iter().map(|w| w.to_string()).collect();
+        writeln!(out, "    {},", words.join(", ")).unwrap();
+    }
+    out.push_str(
+        "];
+
+fn run(vm: &mut VirtualMachine) -> Result<(), MachineError> {
+    loop {
+        if vm.program_version() != 0 {
+            return vm.run_program();
+        }
+        match vm.program_counter {
+",
+    );
+    for (address, &word) in program.iter().enumerate() {
+        if !analysis.is_reachable(address) {
+            continue;
+        }
+        let instruction = decode(word);
+        writeln!(out, "            // {}", instruction).unwrap();
+        writeln!(out, "            {} => {{", address).unwrap();
+        for line in arm(instruction, address) {
+            writeln!(out, "                {}", line).unwrap();
+        }
+        writeln!(out, "            }}").unwrap();
+    }
+    out.push_str(
+        "            _ => {
+                if !vm.step()? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+",
+    );
+    out
+}
+
+/// Returns the body of the match arm for `instruction` at `address`.
+fn arm(instruction: Instruction, address: usize) -> Vec<String> {
+    let next = format!("vm.program_counter = {};", address + 1);
+    let binary = |a: usize, expression: String| {
+        vec![
+            format!("vm.registers[{}] = {};", a, expression),
+            next.clone(),
+        ]
+    };
+    let r = |i: usize| format!("vm.registers[{}]", i);
+    match instruction {
+        Instruction::CMov { a, b, c } => vec![
+            format!("if {} != 0 {{", r(c)),
+            format!("    {} = {};", r(a), r(b)),
+            "}".to_string(),
+            next.clone(),
+        ],
+        Instruction::Add { a, b, c } => binary(a, format!("{}.wrapping_add({})", r(b), r(c))),
+        Instruction::Mul { a, b, c } => binary(a, format!("{}.wrapping_mul({})", r(b), r(c))),
+        // Division by zero is left to the interpreter to report.
+        Instruction::Div { a, b, c } => vec![
+            format!("if {} == 0 {{", r(c)),
+            "    vm.step()?;".to_string(),
+            "} else {".to_string(),
+            format!("    {} = {} / {};", r(a), r(b), r(c)),
+            format!("    {}", next),
+            "}".to_string(),
+        ],
+        Instruction::Nand { a, b, c } => binary(a, format!("!({} & {})", r(b), r(c))),
+        Instruction::LoadValue { a, value } => binary(a, value.to_string()),
+        Instruction::Halt => vec!["return Ok(());".to_string()],
+        Instruction::Invalid { .. } => vec![next],
+        Instruction::Load { .. }
+        | Instruction::Store { .. }
+        | Instruction::MapSegment { .. }
+        | Instruction::UnmapSegment { .. }
+        | Instruction::Output { .. }
+        | Instruction::Input { .. }
+        | Instruction::LoadProgram { .. } => vec!["vm.step()?;".to_string()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+
+    #[test]
+    fn translates_reachable_addresses() {
+        let mut asm = Assembler::new();
+        asm.loadv(1, 3);
+        asm.loadp(0, 1);
+        asm.output(1); // skipped
+        asm.halt();
+        let program = asm.finish();
+        let source = translate(&program, "jump.um");
+        assert!(source.contains("            0 => {\n                vm.registers[1] = 3;"));
+        assert!(source.contains(
+            "            // loadp r0, r1\n            1 => {\n                vm.step()?;"
+        ));
+        assert!(!source.contains("            2 => {"));
+        assert!(source.contains("            3 => {\n                return Ok(());"));
+        assert!(source.contains("static PROGRAM: [u32; 4]"));
+    }
+
+    /// Uses every kind of instruction once.
+    fn every_instruction() -> Vec<u32> {
+        let mut asm = Assembler::new();
+        asm.loadv(1, 6);
+        asm.loadv(2, 7);
+        asm.cmov(3, 1, 2);
+        asm.add(3, 1, 2);
+        asm.mul(3, 3, 2);
+        asm.div(3, 3, 1);
+        asm.nand(4, 3, 3);
+        asm.map(5, 2);
+        asm.store(5, 1, 3);
+        asm.load(6, 5, 1);
+        asm.unmap(5);
+        asm.input(7);
+        asm.output(6);
+        asm.halt();
+        asm.finish()
+    }
+
+    /// `tests/translate.rs` builds and runs a translation, but slowly and
+    /// only when asked for with `--ignored`.
+    #[test]
+    fn matches_golden_translation() {
+        let source = translate(&every_instruction(), "every.um");
+        assert_eq!(source, include_str!("../tests/data/every.rs.txt"));
+    }
+}
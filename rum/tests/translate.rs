@@ -0,0 +1,65 @@
+use rum::compiler::compile;
+use rum::io::Captured;
+use rum::machine::VirtualMachine;
+use rum::translate::translate;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+#[test]
+#[ignore = "builds the translation with cargo, which takes a while"]
+fn translation_runs_like_interpreter() {
+    let program = compile(
+        "fn main() {
+            var line = array(64);
+            var n = 0;
+            var c = getc();
+            while (c != '\\n' && c != -1) { line[n] = c; n = n + 1; c = getc(); }
+            putc('0' + n);
+            while (n > 0) { n = n - 1; putc(line[n] / 2 * 2); }
+        }",
+    )
+    .unwrap();
+    let input = b"hello\n";
+    let io = Captured::new(input);
+    let expected = io.output();
+    let mut vm = VirtualMachine::new();
+    vm.set_io(Box::new(io));
+    vm.initialize_machine(program.clone());
+    vm.run_program().unwrap();
+
+    // Builds the translation as a crate of its own that depends on this one
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let dir = env::temp_dir().join(format!("rum-translate-{}", std::process::id()));
+    fs::create_dir_all(dir.join("src")).unwrap();
+    let manifest = format!(
+        "[package]\nname = \"translated\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+         [dependencies]\nrum = {{ path = {:?} }}\n",
+        root
+    );
+    fs::write(dir.join("Cargo.toml"), manifest).unwrap();
+    fs::write(dir.join("src/main.rs"), translate(&program, "reverse.um")).unwrap();
+    let target = root.join("target/translate");
+    let built = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+        .args(["build", "--quiet", "--offline", "--manifest-path"])
+        .arg(dir.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target)
+        .status()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(built.success());
+
+    let mut child = Command::new(target.join("debug/translated"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, *expected.lock().unwrap());
+    assert_eq!(output.stdout, b"5nlldh");
+}